@@ -115,11 +115,14 @@ export const history: ChatText[] = [];
 
 let audio: Audio | undefined;
 
+// neotool加载应用时传入的token，调用需要权限的命令时使用
+let token = '';
+
 async function loadKey(key: Writable<XunFeiKey | undefined>, service: string) {
   let xkey: XunFeiKey | undefined = { appId: '', apiSecret: '', apiKey: '' };
   let user: keyof XunFeiKey;
   for (user in xkey) {
-    const entry = new SecretKeyEntry(service, user);
+    const entry = new SecretKeyEntry(token, service, user);
     const data = await entry.get_data();
     if (data) {
       if (xkey) {
@@ -137,7 +140,7 @@ async function saveKey(key: XunFeiKey | undefined, service: string): Promise<voi
   if (key) {
     let user: keyof XunFeiKey;
     for (user in key) {
-      const entry = new SecretKeyEntry(service, user);
+      const entry = new SecretKeyEntry(token, service, user);
       await entry.set_data(key[user]);
     }
   }
@@ -195,7 +198,9 @@ async function saveConfig(config: ChatConfig): Promise<void> {
   await writeTextFile(path, JSON.stringify(config, null, 2), fsOption);
 }
 
-export async function initConfig(): Promise<Unsubscriber> {
+export async function initConfig(appToken: string): Promise<Unsubscriber> {
+  token = appToken;
+
   await loadConfig();
   let isSavingConfig = false;
   const configUnsubscriber = chatConfig.subscribe((config) => {
@@ -218,7 +223,7 @@ export async function initConfig(): Promise<Unsubscriber> {
   const sparkUnsubscriber = sparkKey.subscribe((key) => saveKey(key, sparkKeyService));
   const ttsUnsubscriber = ttsKey.subscribe((key) => saveKey(key, ttsKeyService));
 
-  audio = await Audio.newAudio();
+  audio = await Audio.newAudio(token);
 
   return async () => {
     configUnsubscriber();
@@ -282,6 +287,7 @@ export async function chat(
 
         chatState.set(ChatState.Chatting);
         await sparkChat(
+          token,
           {
             ...key,
             chatId,
@@ -343,6 +349,7 @@ export async function textToSpeech(
   ) {
     for (const content of contents) {
       await tts(
+        token,
         {
           ...key,
           aue: 'lame',
@@ -37,7 +37,7 @@ export class KeyData {
   }
 }
 
-export async function simulate(keyData: KeyData): Promise<void> {
+export async function simulate(token: string, keyData: KeyData): Promise<void> {
   let isKeyDown = true;
   let index = 0;
   for (const key of keyData.keys) {
@@ -56,7 +56,7 @@ export async function simulate(keyData: KeyData): Promise<void> {
       isKeyDown = false;
     }
 
-    await simulate_input(key);
+    await simulate_input(token, key);
   }
 }
 
@@ -86,14 +86,14 @@ export async function saveImage(path: string): Promise<string> {
   return destination;
 }
 
-export async function symlinkDataDir(path: string): Promise<void> {
+export async function symlinkDataDir(token: string, path: string): Promise<void> {
   if (!(await exists(dirName, dataFsOption))) {
     await createDir(dirName, { dir: dataFsOption.dir, recursive: true });
   }
 
   const source = await join(await appDataDir(), dirName);
   const destination = await join(path, dirName);
-  await symlinkDir(source, destination);
+  await symlinkDir(token, source, destination);
 }
 
 export async function getPathInWeb(path: string): Promise<string> {
@@ -1,39 +1,471 @@
-use std::collections::HashMap;
+mod source;
+mod stream;
 
-use once_cell::sync::Lazy;
+pub use source::*;
+pub use stream::*;
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::OnceCell;
+use serde::{Serialize, Serializer};
 use tokio::sync::Mutex;
 
-pub type AudioSourceId = u32;
+pub type AudioSourceId = u64;
+
+/// 音频源默认的过期时间
+pub const DEFAULT_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// 所有音频源默认的总字节数上限
+pub const DEFAULT_MAX_BYTES: usize = 64 * 1024 * 1024;
+
+// 后台删除过期音频源的间隔范围，实际间隔跟过期时间一样
+const MIN_PURGE_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+static GLOBAL: OnceCell<AudioSourceManager> = OnceCell::new();
+
+/// 音频源的保存限制
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AudioSourceLimits {
+    /// 音频源添加后超过这个时间还没被取出就会被删除
+    pub ttl: Duration,
+    /// 所有音频源的总字节数上限，超出时从最旧的音频源开始删除
+    pub max_bytes: usize,
+}
 
-pub type AudioSource = Vec<u8>;
+impl Default for AudioSourceLimits {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            ttl: DEFAULT_TTL,
+            max_bytes: DEFAULT_MAX_BYTES,
+        }
+    }
+}
 
-static ID: Lazy<Mutex<AudioSourceId>> = Lazy::new(|| Mutex::new(0));
+#[inline]
+fn serialize_secs<S>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_f64(duration.as_secs_f64())
+}
 
 #[inline]
-async fn new_id() -> AudioSourceId {
-    let mut id = ID.lock().await;
-    *id += 1;
+fn serialize_option_secs<S>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match duration {
+        Some(duration) => serializer.serialize_some(&duration.as_secs_f64()),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// 保存中的音频源的信息，时间以秒为单位序列化
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AudioSourceInfo {
+    pub id: AudioSourceId,
+    pub bytes: usize,
+    pub format: AudioFormat,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u16>,
+    #[serde(serialize_with = "serialize_option_secs")]
+    pub duration: Option<Duration>,
+    pub origin: AudioOrigin,
+    /// 是否还在写入中的音频流
+    pub stream: bool,
+    /// 音频源已经保存的时间
+    #[serde(serialize_with = "serialize_secs")]
+    pub age: Duration,
+}
+
+impl AudioSourceInfo {
+    #[inline]
+    fn new(id: AudioSourceId, source: &AudioSource, age: Duration) -> Self {
+        Self {
+            id,
+            bytes: source.len(),
+            format: source.format,
+            sample_rate: source.sample_rate,
+            channels: source.channels,
+            duration: source.duration,
+            origin: source.origin,
+            stream: source.is_stream(),
+            age,
+        }
+    }
+}
 
-    *id
+/// 音频源的统计数据
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AudioSourceStatistics {
+    /// 当前保存的音频源数量
+    pub count: usize,
+    /// 当前保存的音频源总字节数
+    pub bytes: usize,
+    /// 添加过的音频源数量
+    pub added: u64,
+    /// 被取出或删除的音频源数量，包括过期和超出上限被删除的
+    pub removed: u64,
+    /// 因过期被删除的音频源数量
+    pub expired: u64,
+    /// 因超出字节数上限被删除的音频源数量
+    pub evicted: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    source: AudioSource,
+    added_at: Instant,
 }
 
 #[derive(Debug, Default)]
-pub struct AudioSourceManager(Mutex<HashMap<AudioSourceId, AudioSource>>);
+struct Sources {
+    // ID是递增的，所以按ID排序就是按添加时间排序
+    map: BTreeMap<AudioSourceId, Entry>,
+    statistics: AudioSourceStatistics,
+}
+
+impl Sources {
+    // 音频流在保存期间还会增长，所以字节数每次都重新计算
+    #[inline]
+    fn bytes(&self) -> usize {
+        self.map.values().map(|entry| entry.source.len()).sum()
+    }
+
+    fn purge_expired(&mut self, ttl: Duration) {
+        let now = Instant::now();
+        while let Some((&id, entry)) = self.map.iter().next() {
+            if now.duration_since(entry.added_at) < ttl {
+                break;
+            }
+
+            self.map.remove(&id);
+            self.statistics.removed += 1;
+            self.statistics.expired += 1;
+        }
+    }
+
+    fn evict(&mut self, max_bytes: usize) {
+        let mut bytes = self.bytes();
+        // 最新的音频源总是保留，即使它本身就超出了上限
+        while bytes > max_bytes && self.map.len() > 1 {
+            if let Some(&id) = self.map.keys().next() {
+                if let Some(entry) = self.map.remove(&id) {
+                    bytes -= entry.source.len();
+                }
+                self.statistics.removed += 1;
+                self.statistics.evicted += 1;
+            }
+        }
+    }
+
+    #[inline]
+    fn statistics(&self) -> AudioSourceStatistics {
+        AudioSourceStatistics {
+            count: self.map.len(),
+            bytes: self.bytes(),
+            ..self.statistics
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Registry {
+    sources: Mutex<Sources>,
+    limits: AudioSourceLimits,
+    last_id: AtomicU64,
+}
+
+/// 音频源的注册表，clone得到的是同一个注册表的句柄
+#[derive(Clone, Debug)]
+pub struct AudioSourceManager(Arc<Registry>);
+
+impl Default for AudioSourceManager {
+    #[inline]
+    fn default() -> Self {
+        Self::new(AudioSourceLimits::default())
+    }
+}
+
+// 没有访问注册表时也要删除过期的音频源，注册表被释放后线程退出
+fn spawn_purger(registry: Weak<Registry>, ttl: Duration) {
+    let interval = ttl.clamp(MIN_PURGE_INTERVAL, MAX_PURGE_INTERVAL);
+    let _ = std::thread::Builder::new()
+        .name("audio-source-purger".to_string())
+        .spawn(move || loop {
+            std::thread::sleep(interval);
+            match registry.upgrade() {
+                Some(registry) => registry
+                    .sources
+                    .blocking_lock()
+                    .purge_expired(registry.limits.ttl),
+                None => break,
+            }
+        });
+}
 
 impl AudioSourceManager {
+    /// 会启动一个后台线程定期删除过期的音频源
+    #[inline]
+    pub fn new(limits: AudioSourceLimits) -> Self {
+        let registry = Arc::new(Registry {
+            sources: Mutex::default(),
+            limits,
+            last_id: AtomicU64::new(0),
+        });
+        spawn_purger(Arc::downgrade(&registry), limits.ttl);
+
+        Self(registry)
+    }
+
+    /// 进程内共享的注册表，所有插件都应该通过它添加和取出音频源
+    #[inline]
+    pub fn global() -> Self {
+        GLOBAL.get_or_init(Self::default).clone()
+    }
+
+    /// 用指定的限制初始化共享的注册表，已经初始化过时返回`false`
+    #[inline]
+    pub fn init_global(limits: AudioSourceLimits) -> bool {
+        GLOBAL.set(Self::new(limits)).is_ok()
+    }
+
+    /// 两个句柄是否指向同一个注册表
     #[inline]
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
+    #[inline]
+    pub fn limits(&self) -> AudioSourceLimits {
+        self.0.limits
+    }
+
+    #[inline]
+    fn new_id(&self) -> AudioSourceId {
+        self.0.last_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
     pub async fn add(&self, source: AudioSource) -> AudioSourceId {
-        let id = new_id().await;
-        let mut map = self.0.lock().await;
-        map.insert(id, source);
+        let id = self.new_id();
+        let mut sources = self.0.sources.lock().await;
+        sources.purge_expired(self.0.limits.ttl);
+
+        sources.statistics.added += 1;
+        sources.map.insert(
+            id,
+            Entry {
+                source,
+                added_at: Instant::now(),
+            },
+        );
+        sources.evict(self.0.limits.max_bytes);
 
         id
     }
 
     #[inline]
     pub async fn get_and_remove(&self, id: AudioSourceId) -> Option<AudioSource> {
-        let mut map = self.0.lock().await;
+        self.remove(id).await
+    }
+
+    /// 删除并返回音频源
+    pub async fn remove(&self, id: AudioSourceId) -> Option<AudioSource> {
+        let mut sources = self.0.sources.lock().await;
+        sources.purge_expired(self.0.limits.ttl);
+        let source = sources.map.remove(&id)?.source;
+        sources.statistics.removed += 1;
+
+        Some(source)
+    }
+
+    /// 返回音频源的复制，不删除音频源，完整的音频数据不会被复制
+    pub async fn peek(&self, id: AudioSourceId) -> Option<AudioSource> {
+        let mut sources = self.0.sources.lock().await;
+        sources.purge_expired(self.0.limits.ttl);
+
+        sources.map.get(&id).map(|entry| entry.source.clone())
+    }
+
+    /// 返回音频源的信息
+    pub async fn info(&self, id: AudioSourceId) -> Option<AudioSourceInfo> {
+        let mut sources = self.0.sources.lock().await;
+        sources.purge_expired(self.0.limits.ttl);
+
+        sources
+            .map
+            .get(&id)
+            .map(|entry| AudioSourceInfo::new(id, &entry.source, entry.added_at.elapsed()))
+    }
+
+    /// 按添加顺序列出保存中的音频源
+    pub async fn list(&self) -> Vec<AudioSourceInfo> {
+        let mut sources = self.0.sources.lock().await;
+        sources.purge_expired(self.0.limits.ttl);
+        let now = Instant::now();
+
+        sources
+            .map
+            .iter()
+            .map(|(&id, entry)| {
+                AudioSourceInfo::new(id, &entry.source, now.duration_since(entry.added_at))
+            })
+            .collect()
+    }
+
+    /// 删除所有过期的音频源
+    #[inline]
+    pub async fn purge_expired(&self) {
+        self.0.sources.lock().await.purge_expired(self.0.limits.ttl);
+    }
+
+    #[inline]
+    pub async fn statistics(&self) -> AudioSourceStatistics {
+        let mut sources = self.0.sources.lock().await;
+        sources.purge_expired(self.0.limits.ttl);
+
+        sources.statistics()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline]
+    fn source(bytes: usize) -> AudioSource {
+        AudioSource::pcm(vec![0; bytes], 16000, 1, AudioOrigin::Generated)
+    }
+
+    #[tokio::test]
+    async fn test_audio_source_manager_limits() {
+        let manager = AudioSourceManager::new(AudioSourceLimits {
+            ttl: DEFAULT_TTL,
+            max_bytes: 10,
+        });
+        let first = manager.add(source(4)).await;
+        let second = manager.add(source(4)).await;
+        assert_eq!(manager.clone().peek(first).await, Some(source(4)));
+
+        let third = manager.add(source(4)).await;
+        assert_eq!(manager.peek(first).await, None);
+        let ids: Vec<_> = manager.list().await.into_iter().map(|i| i.id).collect();
+        assert_eq!(ids, vec![second, third]);
+        let info = manager.info(third).await.unwrap();
+        assert_eq!(info.format, AudioFormat::Pcm);
+        assert_eq!(info.duration, Some(Duration::from_micros(125)));
+        // 复制音频源时共享完整的音频数据
+        let (a, b) = (
+            manager.peek(third).await.unwrap(),
+            manager.peek(third).await.unwrap(),
+        );
+        assert!(matches!(
+            (&a.data, &b.data),
+            (AudioData::Complete(a), AudioData::Complete(b)) if Arc::ptr_eq(a, b)
+        ));
+
+        assert_eq!(manager.remove(second).await, Some(source(4)));
+        let statistics = manager.statistics().await;
+        assert_eq!(statistics.count, 1);
+        assert_eq!(statistics.bytes, 4);
+        assert_eq!(statistics.added, 3);
+        assert_eq!(statistics.removed, 2);
+        assert_eq!(statistics.evicted, 1);
+
+        let manager = AudioSourceManager::new(AudioSourceLimits {
+            ttl: Duration::ZERO,
+            max_bytes: DEFAULT_MAX_BYTES,
+        });
+        let id = manager.add(source(4)).await;
+        assert_eq!(manager.get_and_remove(id).await, None);
+        let statistics = manager.statistics().await;
+        assert_eq!(statistics.removed, 1);
+        assert_eq!(statistics.expired, 1);
+    }
+
+    #[tokio::test]
+    async fn test_audio_source_manager_purger() {
+        let manager = AudioSourceManager::new(AudioSourceLimits {
+            ttl: Duration::from_millis(50),
+            max_bytes: DEFAULT_MAX_BYTES,
+        });
+        manager.add(source(4)).await;
+        assert_eq!(manager.0.sources.lock().await.map.len(), 1);
+
+        // 不访问注册表，等后台线程删除
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let sources = manager.0.sources.lock().await;
+        assert!(sources.map.is_empty());
+        assert_eq!(sources.statistics.expired, 1);
+    }
+
+    #[test]
+    fn test_audio_stream() {
+        use std::io::Read;
+
+        let (source, writer) = AudioSource::stream(AudioFormat::Mp3, AudioOrigin::Tts);
+        let mut reader = match source.data {
+            AudioData::Stream(reader) => reader,
+            AudioData::Complete(_) => unreachable!(),
+        };
+        assert!(writer.append(&[1, 2]));
+
+        let handle = std::thread::spawn(move || {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).unwrap();
+            data
+        });
+        assert!(writer.append(&[3]));
+        drop(writer);
+
+        assert_eq!(handle.join().unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_audio_stream_seek_end() {
+        use std::io::{ErrorKind, Seek, SeekFrom};
+
+        let (source, writer) = AudioSource::stream(AudioFormat::Mp3, AudioOrigin::Tts);
+        let mut reader = match source.data {
+            AudioData::Stream(reader) => reader,
+            AudioData::Complete(_) => unreachable!(),
+        };
+        assert!(writer.append(&[1, 2, 3]));
+
+        // 还在写入时不会阻塞
+        let err = reader.seek(SeekFrom::End(0)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+
+        writer.close();
+        assert_eq!(reader.seek(SeekFrom::End(-1)).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_audio_format_detect() {
+        assert_eq!(AudioFormat::detect(b"ID3\x04"), AudioFormat::Mp3);
+        assert_eq!(AudioFormat::detect(&[0xFF, 0xFB, 0x90]), AudioFormat::Mp3);
+        assert_eq!(AudioFormat::detect(&[0xFF, 0xF1, 0x50]), AudioFormat::Aac);
+        assert_eq!(AudioFormat::detect(b"RIFF\0\0\0\0WAVEfmt "), AudioFormat::Wav);
+        assert_eq!(AudioFormat::detect(b"fLaC"), AudioFormat::Flac);
+        assert_eq!(AudioFormat::detect(b"\0\0\0\x20ftypM4A "), AudioFormat::M4a);
+
+        let mut ogg = b"OggS".to_vec();
+        ogg.resize(28, 0);
+        ogg.extend_from_slice(b"OpusHead");
+        assert_eq!(AudioFormat::detect(&ogg), AudioFormat::Opus);
+        ogg[28..36].copy_from_slice(b"\x01vorbis\0");
+        assert_eq!(AudioFormat::detect(&ogg), AudioFormat::Ogg);
 
-        map.remove(&id)
+        assert_eq!(AudioFormat::detect(b"abc"), AudioFormat::Unknown);
     }
 }
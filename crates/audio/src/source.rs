@@ -0,0 +1,204 @@
+use std::{sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{audio_stream, AudioStreamReader, AudioStreamWriter};
+
+/// 音频的容器或编码格式
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AudioFormat {
+    /// 未知格式，由解码器自行探测
+    Unknown,
+    Mp3,
+    Wav,
+    /// 没有文件头的16位有符号小端PCM
+    Pcm,
+    /// Ogg容器里的Vorbis
+    Ogg,
+    Flac,
+    /// ADTS封装的AAC
+    Aac,
+    /// MP4容器里的AAC
+    M4a,
+    /// Ogg容器里的Opus
+    Opus,
+}
+
+impl AudioFormat {
+    /// 根据文件扩展名判断格式，不认识的扩展名返回`Unknown`
+    pub fn from_extension(extension: &str) -> Self {
+        match extension.to_ascii_lowercase().as_str() {
+            "mp3" => AudioFormat::Mp3,
+            "wav" | "wave" => AudioFormat::Wav,
+            "pcm" => AudioFormat::Pcm,
+            "ogg" | "oga" => AudioFormat::Ogg,
+            "flac" => AudioFormat::Flac,
+            "aac" => AudioFormat::Aac,
+            "m4a" | "mp4" => AudioFormat::M4a,
+            "opus" => AudioFormat::Opus,
+            _ => AudioFormat::Unknown,
+        }
+    }
+
+    /// 根据数据开头的字节判断格式，判断不出来时返回`Unknown`
+    pub fn detect(header: &[u8]) -> Self {
+        if header.starts_with(b"OggS") {
+            // 第一个Ogg页只有一个段，编码的标识紧跟在27字节的页头和1字节的段表之后
+            if header.get(28..36) == Some(b"OpusHead") {
+                AudioFormat::Opus
+            } else {
+                AudioFormat::Ogg
+            }
+        } else if header.starts_with(b"fLaC") {
+            AudioFormat::Flac
+        } else if header.starts_with(b"RIFF") && header.get(8..12) == Some(b"WAVE") {
+            AudioFormat::Wav
+        } else if header.get(4..8) == Some(b"ftyp") {
+            AudioFormat::M4a
+        } else if header.starts_with(b"ID3") {
+            AudioFormat::Mp3
+        } else if header.len() >= 2 && header[0] == 0xFF && header[1] & 0xE0 == 0xE0 {
+            // MPEG音频帧的同步字，layer为0的是ADTS
+            if header[1] & 0x06 == 0 {
+                AudioFormat::Aac
+            } else {
+                AudioFormat::Mp3
+            }
+        } else {
+            AudioFormat::Unknown
+        }
+    }
+
+    /// 格式的名字，和序列化的结果一样
+    pub fn name(self) -> &'static str {
+        match self {
+            AudioFormat::Unknown => "unknown",
+            AudioFormat::Mp3 => "mp3",
+            AudioFormat::Wav => "wav",
+            AudioFormat::Pcm => "pcm",
+            AudioFormat::Ogg => "ogg",
+            AudioFormat::Flac => "flac",
+            AudioFormat::Aac => "aac",
+            AudioFormat::M4a => "m4a",
+            AudioFormat::Opus => "opus",
+        }
+    }
+}
+
+/// 音频源的来源
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AudioOrigin {
+    Tts,
+    File,
+    Generated,
+}
+
+/// 音频源的数据
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AudioData {
+    /// 完整的音频数据，复制音频源时共享同一份数据
+    Complete(Arc<[u8]>),
+    /// 还在写入中的音频流
+    Stream(AudioStreamReader),
+}
+
+impl AudioData {
+    /// 数据的字节数，音频流返回当前已经写入的字节数
+    #[inline]
+    pub fn len(&self) -> usize {
+        match self {
+            AudioData::Complete(data) => data.len(),
+            AudioData::Stream(reader) => reader.len(),
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AudioSource {
+    pub data: AudioData,
+    pub format: AudioFormat,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u16>,
+    pub duration: Option<Duration>,
+    pub origin: AudioOrigin,
+}
+
+impl AudioSource {
+    #[inline]
+    pub fn new(data: Vec<u8>, format: AudioFormat, origin: AudioOrigin) -> Self {
+        Self {
+            data: AudioData::Complete(data.into()),
+            format,
+            sample_rate: None,
+            channels: None,
+            duration: None,
+            origin,
+        }
+    }
+
+    /// 没有文件头的16位有符号小端PCM，时长由数据长度计算
+    pub fn pcm(data: Vec<u8>, sample_rate: u32, channels: u16, origin: AudioOrigin) -> Self {
+        let duration = pcm_duration(data.len(), sample_rate, channels);
+
+        Self {
+            data: AudioData::Complete(data.into()),
+            format: AudioFormat::Pcm,
+            sample_rate: Some(sample_rate),
+            channels: Some(channels),
+            duration,
+            origin,
+        }
+    }
+
+    /// 新建一个可以持续写入的音频源，返回音频源和写入端
+    pub fn stream(format: AudioFormat, origin: AudioOrigin) -> (Self, AudioStreamWriter) {
+        let (writer, reader) = audio_stream();
+
+        (
+            Self {
+                data: AudioData::Stream(reader),
+                format,
+                sample_rate: None,
+                channels: None,
+                duration: None,
+                origin,
+            },
+            writer,
+        )
+    }
+
+    #[inline]
+    pub fn is_stream(&self) -> bool {
+        matches!(self.data, AudioData::Stream(_))
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+/// 计算16位PCM数据的时长
+pub fn pcm_duration(bytes: usize, sample_rate: u32, channels: u16) -> Option<Duration> {
+    if sample_rate == 0 || channels == 0 {
+        return None;
+    }
+
+    let frames = (bytes / 2 / channels as usize) as u64;
+
+    Some(Duration::from_nanos(
+        frames * 1_000_000_000 / sample_rate as u64,
+    ))
+}
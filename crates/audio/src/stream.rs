@@ -0,0 +1,200 @@
+use std::{
+    io::{Error, ErrorKind, Read, Result, Seek, SeekFrom},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+};
+
+#[derive(Debug, Default)]
+struct Buffer {
+    data: Vec<u8>,
+    closed: bool,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    buffer: Mutex<Buffer>,
+    condvar: Condvar,
+}
+
+impl Shared {
+    #[inline]
+    fn lock(&self) -> MutexGuard<'_, Buffer> {
+        self.buffer.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    #[inline]
+    fn close(&self) {
+        self.lock().closed = true;
+        self.condvar.notify_all();
+    }
+}
+
+#[derive(Debug)]
+struct WriterGuard(Arc<Shared>);
+
+impl Drop for WriterGuard {
+    #[inline]
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+/// 音频流的写入端，所有写入端都被drop后音频流会自动关闭
+#[derive(Clone, Debug)]
+pub struct AudioStreamWriter(Arc<WriterGuard>);
+
+impl AudioStreamWriter {
+    /// 追加数据，音频流已经关闭时返回`false`
+    pub fn append(&self, data: &[u8]) -> bool {
+        let shared = &(self.0).0;
+        {
+            let mut buffer = shared.lock();
+            if buffer.closed {
+                return false;
+            }
+            buffer.data.extend_from_slice(data);
+        }
+        shared.condvar.notify_all();
+
+        true
+    }
+
+    /// 关闭音频流，之后读取端读完已有的数据就会结束
+    #[inline]
+    pub fn close(&self) {
+        (self.0).0.close();
+    }
+
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        (self.0).0.lock().closed
+    }
+}
+
+/// 音频流的读取端，数据不够时会阻塞直到有新数据或者音频流关闭，
+/// 所以不要在音频输出的回调里直接读取
+#[derive(Debug)]
+pub struct AudioStreamReader {
+    shared: Arc<Shared>,
+    position: u64,
+}
+
+impl AudioStreamReader {
+    /// 当前已经写入的字节数
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.shared.lock().data.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        self.shared.lock().closed
+    }
+}
+
+impl Clone for AudioStreamReader {
+    /// 新的读取端从头开始读取
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            shared: Arc::clone(&self.shared),
+            position: 0,
+        }
+    }
+}
+
+impl PartialEq for AudioStreamReader {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.shared, &other.shared) && self.position == other.position
+    }
+}
+
+impl Eq for AudioStreamReader {}
+
+impl Read for AudioStreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut buffer = self.shared.lock();
+        loop {
+            let len = buffer.data.len() as u64;
+            if self.position < len {
+                let start = self.position as usize;
+                let n = buf.len().min(buffer.data.len() - start);
+                buf[..n].copy_from_slice(&buffer.data[start..start + n]);
+                self.position += n as u64;
+
+                return Ok(n);
+            }
+
+            if buffer.closed {
+                return Ok(0);
+            }
+
+            buffer = self
+                .shared
+                .condvar
+                .wait(buffer)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+#[inline]
+fn add_offset(base: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        base.checked_add(offset as u64)
+    } else {
+        base.checked_sub(offset.unsigned_abs())
+    }
+}
+
+impl Seek for AudioStreamReader {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => add_offset(self.position, offset),
+            SeekFrom::End(offset) => {
+                // 音频流的长度要等到关闭后才能确定，还在写入时不等待，直接返回错误
+                let buffer = self.shared.lock();
+                if !buffer.closed {
+                    return Err(Error::new(
+                        ErrorKind::Unsupported,
+                        "cannot seek from the end of an open audio stream",
+                    ));
+                }
+
+                add_offset(buffer.data.len() as u64, offset)
+            }
+        };
+
+        self.position = position.ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+
+        Ok(self.position)
+    }
+}
+
+/// 新建一个音频流，返回写入端和读取端
+pub fn audio_stream() -> (AudioStreamWriter, AudioStreamReader) {
+    let shared = Arc::new(Shared::default());
+
+    (
+        AudioStreamWriter(Arc::new(WriterGuard(Arc::clone(&shared)))),
+        AudioStreamReader {
+            shared,
+            position: 0,
+        },
+    )
+}
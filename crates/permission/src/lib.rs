@@ -0,0 +1,550 @@
+mod permission;
+
+pub use permission::*;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{read_to_string, rename, write},
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use once_cell::sync::OnceCell;
+use serde::{Serialize, Serializer};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// 请求用户同意应用使用权限，参数为应用id和权限，会阻塞直到用户做出选择
+pub type Prompt = Arc<dyn Fn(&str, &Permission) -> bool + Send + Sync>;
+
+static GLOBAL: OnceCell<PermissionManager> = OnceCell::new();
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid permission: {0}")]
+    InvalidPermission(String),
+    #[error("app is not loaded: {0}")]
+    UnknownApp(String),
+    #[error("app {app_id} didn't declare permission {permission}")]
+    NotDeclared { app_id: String, permission: String },
+    #[error("app {app_id} is denied permission {permission}")]
+    Denied { app_id: String, permission: String },
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    SerdeJsonError(#[from] serde_json::Error),
+    #[error(transparent)]
+    RandomError(#[from] getrandom::Error),
+    #[error("invalid app token")]
+    InvalidToken,
+    #[error("only neotool can call this command")]
+    NotHost,
+    #[error("neotool token is already issued")]
+    HostTokenIssued,
+}
+
+impl Serialize for Error {
+    #[inline]
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+/// 应用申请的权限和用户的选择
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PermissionStatus {
+    pub permission: String,
+    pub description: String,
+    /// `None`为用户还没做出选择
+    pub granted: Option<bool>,
+}
+
+// 应用id到权限到用户是否同意
+type Decisions = BTreeMap<String, BTreeMap<String, bool>>;
+
+// token的字节数
+const TOKEN_LEN: usize = 32;
+
+// 随机生成的token，十六进制
+fn new_token() -> Result<String> {
+    let mut bytes = [0u8; TOKEN_LEN];
+    getrandom::getrandom(&mut bytes)?;
+
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+#[derive(Default)]
+struct Registry {
+    declared: Mutex<HashMap<String, Vec<Permission>>>,
+    // 应用id到应用所在的目录
+    dirs: Mutex<HashMap<String, PathBuf>>,
+    // token到应用id
+    tokens: Mutex<HashMap<String, String>>,
+    // neotool界面的token
+    host_token: Mutex<Option<String>>,
+    decisions: Mutex<Decisions>,
+    // 保存用户选择的文件，没有时不保存
+    path: Mutex<Option<PathBuf>>,
+    prompt: RwLock<Option<Prompt>>,
+    // 启动时从neotool的设置读取的应用目录
+    apps_dir: RwLock<Option<PathBuf>>,
+    // 同一时间只请求一次用户同意
+    prompting: tokio::sync::Mutex<()>,
+}
+
+/// 应用权限的注册表，clone得到的是同一个注册表的句柄。
+/// 应用用加载时发放的token调用需要权限的命令，token在这里对应到应用id，应用不能冒充其它应用。
+/// 应用和neotool的界面在同一个webview里运行，这不能防止恶意的应用拦截其它应用的IPC调用。
+#[derive(Clone, Default)]
+pub struct PermissionManager(Arc<Registry>);
+
+impl PermissionManager {
+    /// 进程内共享的注册表，所有插件都应该通过它检查权限
+    #[inline]
+    pub fn global() -> Self {
+        GLOBAL.get_or_init(Self::default).clone()
+    }
+
+    /// 从文件读取用户的选择，之后用户的选择都会保存到这个文件
+    pub fn set_storage(&self, path: PathBuf) -> Result<()> {
+        let decisions = match read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Decisions::new(),
+            Err(e) => return Err(e.into()),
+        };
+        *self.0.decisions.lock().unwrap_or_else(|e| e.into_inner()) = decisions;
+        *self.0.path.lock().unwrap_or_else(|e| e.into_inner()) = Some(path);
+
+        Ok(())
+    }
+
+    /// 设置请求用户同意的方法，没有设置时所有没有做出选择的权限都会被拒绝
+    #[inline]
+    pub fn set_prompt(&self, prompt: Prompt) {
+        *self.0.prompt.write().unwrap_or_else(|e| e.into_inner()) = Some(prompt);
+    }
+
+    /// 设置应用目录，启动时从neotool的设置读取
+    #[inline]
+    pub fn set_apps_dir(&self, dir: PathBuf) {
+        *self.0.apps_dir.write().unwrap_or_else(|e| e.into_inner()) = Some(dir);
+    }
+
+    /// 应用目录，还没设置时为`None`
+    #[inline]
+    pub fn apps_dir(&self) -> Option<PathBuf> {
+        self.0
+            .apps_dir
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// 设置应用所在的目录和申请的权限，加载应用时调用
+    #[inline]
+    pub fn declare(&self, app_id: &str, dir: PathBuf, permissions: Vec<Permission>) {
+        self.0
+            .declared
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(app_id.to_string(), permissions);
+        self.0
+            .dirs
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(app_id.to_string(), dir);
+    }
+
+    /// 应用所在的目录，插件只允许应用读取自己目录里的应用文件，应用没有加载时为`None`
+    #[inline]
+    pub fn app_dir(&self, app_id: &str) -> Option<PathBuf> {
+        self.0
+            .dirs
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(app_id)
+            .cloned()
+    }
+
+    /// 移除应用申请的权限并让应用的token失效，用户的选择会保留
+    #[inline]
+    pub fn undeclare(&self, app_id: &str) {
+        self.0
+            .declared
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(app_id);
+        self.0
+            .dirs
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(app_id);
+        self.0
+            .tokens
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|_, id| id != app_id);
+    }
+
+    /// 给加载的应用发放token，之前发放给这个应用的token会失效
+    pub fn issue_token(&self, app_id: &str) -> Result<String> {
+        if !self
+            .0
+            .declared
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains_key(app_id)
+        {
+            return Err(Error::UnknownApp(app_id.to_string()));
+        }
+        let token = new_token()?;
+        let mut tokens = self.0.tokens.lock().unwrap_or_else(|e| e.into_inner());
+        tokens.retain(|_, id| id != app_id);
+        tokens.insert(token.clone(), app_id.to_string());
+
+        Ok(token)
+    }
+
+    /// token对应的应用id
+    #[inline]
+    pub fn app_id(&self, token: &str) -> Result<String> {
+        self.0
+            .tokens
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(token)
+            .cloned()
+            .ok_or(Error::InvalidToken)
+    }
+
+    /// 检查token对应的应用是否可以使用权限，返回应用id
+    #[inline]
+    pub async fn authorize(&self, token: &str, requested: &Permission) -> Result<String> {
+        let app_id = self.app_id(token)?;
+        self.check(&app_id, requested).await?;
+
+        Ok(app_id)
+    }
+
+    /// 发放neotool界面的token，`revoke_all`之前只能发放一次，
+    /// 所以要在加载应用之前调用
+    pub fn issue_host_token(&self) -> Result<String> {
+        let mut host_token = self.0.host_token.lock().unwrap_or_else(|e| e.into_inner());
+        if host_token.is_some() {
+            return Err(Error::HostTokenIssued);
+        }
+        let token = new_token()?;
+        *host_token = Some(token.clone());
+
+        Ok(token)
+    }
+
+    /// 检查是不是neotool界面的token
+    #[inline]
+    pub fn check_host(&self, token: &str) -> Result<()> {
+        match &*self.0.host_token.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some(host_token) if host_token == token => Ok(()),
+            _ => Err(Error::NotHost),
+        }
+    }
+
+    /// 让所有的token失效，neotool的界面重新加载时调用
+    #[inline]
+    pub fn revoke_all(&self) {
+        self.0
+            .tokens
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        self.0
+            .host_token
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+    }
+
+    #[inline]
+    fn decision(&self, app_id: &str, permission: &str) -> Option<bool> {
+        self.0
+            .decisions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(app_id)
+            .and_then(|d| d.get(permission))
+            .copied()
+    }
+
+    /// 应用申请的权限和用户的选择
+    pub fn permissions(&self, app_id: &str) -> Result<Vec<PermissionStatus>> {
+        let declared = self.0.declared.lock().unwrap_or_else(|e| e.into_inner());
+        let permissions = declared
+            .get(app_id)
+            .ok_or_else(|| Error::UnknownApp(app_id.to_string()))?;
+
+        Ok(permissions
+            .iter()
+            .map(|permission| {
+                let name = permission.to_string();
+                PermissionStatus {
+                    granted: self.decision(app_id, &name),
+                    permission: name,
+                    description: permission.description(),
+                }
+            })
+            .collect())
+    }
+
+    /// 修改用户的选择，`None`为下次使用时重新请求用户同意
+    pub fn set_granted(&self, app_id: &str, permission: &str, granted: Option<bool>) -> Result<()> {
+        let mut decisions = self.0.decisions.lock().unwrap_or_else(|e| e.into_inner());
+        match granted {
+            Some(granted) => {
+                decisions
+                    .entry(app_id.to_string())
+                    .or_default()
+                    .insert(permission.to_string(), granted);
+            }
+            None => {
+                if let Some(d) = decisions.get_mut(app_id) {
+                    d.remove(permission);
+                    if d.is_empty() {
+                        decisions.remove(app_id);
+                    }
+                }
+            }
+        }
+
+        match &*self.0.path.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some(path) => {
+                // 先写临时文件再改名，避免写到一半时文件损坏
+                let tmp = path.with_extension("tmp");
+                write(&tmp, serde_json::to_vec_pretty(&*decisions)?)?;
+                rename(tmp, path)?;
+
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// 检查应用是否可以使用权限，用户还没做出选择时请求用户同意并保存选择
+    pub async fn check(&self, app_id: &str, requested: &Permission) -> Result<()> {
+        let declared = {
+            let declared = self.0.declared.lock().unwrap_or_else(|e| e.into_inner());
+            declared
+                .get(app_id)
+                .ok_or_else(|| Error::UnknownApp(app_id.to_string()))?
+                .iter()
+                .find(|p| p.covers(requested))
+                .cloned()
+                .ok_or_else(|| Error::NotDeclared {
+                    app_id: app_id.to_string(),
+                    permission: requested.to_string(),
+                })?
+        };
+        let name = declared.to_string();
+        let result = |granted: bool| {
+            if granted {
+                Ok(())
+            } else {
+                Err(Error::Denied {
+                    app_id: app_id.to_string(),
+                    permission: requested.to_string(),
+                })
+            }
+        };
+
+        if let Some(granted) = self.decision(app_id, &name) {
+            return result(granted);
+        }
+        let _guard = self.0.prompting.lock().await;
+        // 等待的时候用户可能已经做出了选择
+        if let Some(granted) = self.decision(app_id, &name) {
+            return result(granted);
+        }
+
+        let prompt = self
+            .0
+            .prompt
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        let prompt = match prompt {
+            Some(prompt) => prompt,
+            None => return result(false),
+        };
+        let id = app_id.to_string();
+        let granted = tokio::task::spawn_blocking(move || prompt(&id, &declared))
+            .await
+            .unwrap_or(false);
+        self.set_granted(app_id, &name, Some(granted))?;
+
+        result(granted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn test_permission_parse() {
+        for s in [
+            "keyboard.simulate",
+            "keyboard.listen",
+            "audio",
+            "tts",
+            "spark",
+            "secrets:xunfei",
+            "serve_files",
+            "fs:*",
+        ] {
+            assert_eq!(s.parse::<Permission>().unwrap().to_string(), s);
+        }
+        for s in ["", "keyboard", "secrets:", "fs:", "fs:relative/path"] {
+            assert!(s.parse::<Permission>().is_err());
+        }
+
+        let root = std::env::temp_dir();
+        let declared: Permission = format!("fs:{}", root.display()).parse().unwrap();
+        assert!(declared.covers(&Permission::fs(root.join("a"))));
+        assert!(!Permission::fs(root.join("a")).covers(&Permission::fs(&root)));
+        assert!(Permission::Fs(None).covers(&Permission::fs(&root)));
+        assert!(!Permission::Secrets("a".to_string()).covers(&Permission::Secrets("b".to_string())));
+    }
+
+    #[test]
+    fn test_permission_parse_for_app() {
+        let app_dir = std::env::temp_dir().join("app");
+        for (s, path) in [
+            ("fs:$APP", app_dir.clone()),
+            ("fs:$APP/", app_dir.clone()),
+            ("fs:$APP/web", app_dir.join("web")),
+            ("fs:$APP/web/sticker", app_dir.join("web").join("sticker")),
+        ] {
+            assert_eq!(
+                Permission::parse_for_app(s, &app_dir).unwrap(),
+                Permission::fs(path)
+            );
+        }
+        for s in [
+            "fs:$APPS",
+            "fs:$APP/../other",
+            "fs:$APP//etc",
+            "fs:$APP/web/../..",
+        ] {
+            assert!(Permission::parse_for_app(s, &app_dir).is_err());
+        }
+        assert_eq!(
+            Permission::parse_for_app("audio", &app_dir).unwrap(),
+            Permission::Audio
+        );
+        assert_eq!(
+            Permission::parse_for_app("fs:*", &app_dir).unwrap(),
+            Permission::Fs(None)
+        );
+        assert!("fs:$APP/web".parse::<Permission>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_permission_check() {
+        let manager = PermissionManager::default();
+        let prompts = Arc::new(AtomicU32::new(0));
+        {
+            let prompts = prompts.clone();
+            manager.set_prompt(Arc::new(move |_, permission| {
+                prompts.fetch_add(1, Ordering::Relaxed);
+                *permission == Permission::Tts
+            }));
+        }
+        manager.declare(
+            "app",
+            PathBuf::from("app"),
+            vec![Permission::Tts, Permission::Spark],
+        );
+
+        assert!(matches!(
+            manager.check("other", &Permission::Tts).await,
+            Err(Error::UnknownApp(_))
+        ));
+        assert!(matches!(
+            manager.check("app", &Permission::Audio).await,
+            Err(Error::NotDeclared { .. })
+        ));
+        assert_eq!(prompts.load(Ordering::Relaxed), 0);
+
+        manager.check("app", &Permission::Tts).await.unwrap();
+        manager.check("app", &Permission::Tts).await.unwrap();
+        assert!(matches!(
+            manager.check("app", &Permission::Spark).await,
+            Err(Error::Denied { .. })
+        ));
+        assert!(manager.check("app", &Permission::Spark).await.is_err());
+        assert_eq!(prompts.load(Ordering::Relaxed), 2);
+
+        let status = manager.permissions("app").unwrap();
+        assert_eq!(status[0].granted, Some(true));
+        assert_eq!(status[1].granted, Some(false));
+
+        manager.set_granted("app", "spark", None).unwrap();
+        assert!(manager.check("app", &Permission::Spark).await.is_err());
+        assert_eq!(prompts.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_permission_token() {
+        let manager = PermissionManager::default();
+        manager.set_prompt(Arc::new(|_, _| true));
+        manager.declare("app", PathBuf::from("app"), vec![Permission::Tts]);
+        manager.declare("other", PathBuf::from("other"), vec![Permission::Spark]);
+
+        assert!(matches!(
+            manager.issue_token("unknown"),
+            Err(Error::UnknownApp(_))
+        ));
+        let old = manager.issue_token("app").unwrap();
+        let token = manager.issue_token("app").unwrap();
+        assert_ne!(old, token);
+        assert!(matches!(manager.app_id(&old), Err(Error::InvalidToken)));
+        assert_eq!(manager.app_id(&token).unwrap(), "app");
+        assert!(matches!(
+            manager.authorize("app", &Permission::Tts).await,
+            Err(Error::InvalidToken)
+        ));
+        assert_eq!(
+            manager.authorize(&token, &Permission::Tts).await.unwrap(),
+            "app"
+        );
+        // 不能用自己的token使用其它应用申请的权限
+        assert!(matches!(
+            manager.authorize(&token, &Permission::Spark).await,
+            Err(Error::NotDeclared { .. })
+        ));
+
+        let host = manager.issue_host_token().unwrap();
+        assert!(matches!(
+            manager.issue_host_token(),
+            Err(Error::HostTokenIssued)
+        ));
+        manager.check_host(&host).unwrap();
+        assert!(matches!(manager.check_host(&token), Err(Error::NotHost)));
+
+        manager.revoke_all();
+        assert!(manager.app_id(&token).is_err());
+        assert!(manager.check_host(&host).is_err());
+        assert_ne!(manager.issue_host_token().unwrap(), host);
+
+        let token = manager.issue_token("other").unwrap();
+        assert_eq!(manager.app_dir("other"), Some(PathBuf::from("other")));
+        manager.undeclare("other");
+        assert!(manager.app_id(&token).is_err());
+        assert_eq!(manager.app_dir("other"), None);
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    fmt,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::Error;
+
+const SECRETS_PREFIX: &str = "secrets:";
+
+const FS_PREFIX: &str = "fs:";
+
+// 任意路径
+const ANY_PATH: &str = "*";
+
+// 应用所在的目录，`fs:$APP/<path>`为应用目录下的相对路径
+const APP_DIR: &str = "$APP";
+
+/// 应用可以在`neotool.app.json`的`permissions`里申请的权限
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Permission {
+    /// `keyboard.simulate`，模拟键盘输入
+    KeyboardSimulate,
+    /// `keyboard.listen`，监听键盘
+    KeyboardListen,
+    /// `audio`，播放音频
+    Audio,
+    /// `tts`，语音合成
+    Tts,
+    /// `spark`，星火大模型
+    Spark,
+    /// `secrets:<service>`，读写指定服务的密钥
+    Secrets(String),
+    /// `serve_files`，开启文件服务器
+    ServeFiles,
+    /// `fs:<path>`，修改指定的绝对路径下的文件，`fs:*`为任意路径，对应`None`，
+    /// `fs:$APP/<path>`为应用目录下的路径，由`parse_for_app`展开成绝对路径
+    Fs(Option<PathBuf>),
+}
+
+impl Permission {
+    /// 申请的权限是否包含请求的权限，只有`fs`权限按路径前缀判断
+    pub fn covers(&self, requested: &Permission) -> bool {
+        match (self, requested) {
+            (Self::Fs(None), Self::Fs(_)) => true,
+            (Self::Fs(Some(declared)), Self::Fs(Some(path))) => path.starts_with(declared),
+            _ => self == requested,
+        }
+    }
+
+    /// 请求用户同意时显示的说明
+    pub fn description(&self) -> String {
+        match self {
+            Self::KeyboardSimulate => "模拟键盘输入".to_string(),
+            Self::KeyboardListen => "监听键盘输入".to_string(),
+            Self::Audio => "播放音频".to_string(),
+            Self::Tts => "使用语音合成".to_string(),
+            Self::Spark => "使用星火大模型".to_string(),
+            Self::Secrets(service) => format!("读写{}的密钥", service),
+            Self::ServeFiles => "开启文件服务器".to_string(),
+            Self::Fs(None) => "修改任意路径下的文件".to_string(),
+            Self::Fs(Some(path)) => format!("修改{}下的文件", path.display()),
+        }
+    }
+
+    /// 请求访问`path`的`fs`权限
+    #[inline]
+    pub fn fs(path: impl AsRef<Path>) -> Self {
+        Self::Fs(Some(path.as_ref().to_path_buf()))
+    }
+
+    /// 解析应用申请的权限，`fs:$APP/<path>`按应用所在的目录`app_dir`展开，
+    /// `<path>`只能是不包含`..`的相对路径
+    pub fn parse_for_app(s: &str, app_dir: &Path) -> Result<Self, Error> {
+        let path = match s
+            .strip_prefix(FS_PREFIX)
+            .and_then(|s| s.strip_prefix(APP_DIR))
+        {
+            Some(path) => path,
+            None => return s.parse(),
+        };
+        let relative = match path {
+            "" => "",
+            _ => path
+                .strip_prefix('/')
+                .ok_or_else(|| Error::InvalidPermission(s.to_string()))?,
+        };
+        if Path::new(relative)
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(Error::InvalidPermission(s.to_string()));
+        }
+
+        Ok(Self::fs(app_dir.join(relative)))
+    }
+}
+
+impl FromStr for Permission {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidPermission(s.to_string());
+        Ok(match s {
+            "keyboard.simulate" => Self::KeyboardSimulate,
+            "keyboard.listen" => Self::KeyboardListen,
+            "audio" => Self::Audio,
+            "tts" => Self::Tts,
+            "spark" => Self::Spark,
+            "serve_files" => Self::ServeFiles,
+            _ if s.starts_with(SECRETS_PREFIX) => {
+                let service = &s[SECRETS_PREFIX.len()..];
+                if service.is_empty() {
+                    return Err(invalid());
+                }
+                Self::Secrets(service.to_string())
+            }
+            _ if s.starts_with(FS_PREFIX) => match &s[FS_PREFIX.len()..] {
+                ANY_PATH => Self::Fs(None),
+                path if Path::new(path).is_absolute() => Self::fs(path),
+                _ => return Err(invalid()),
+            },
+            _ => return Err(invalid()),
+        })
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::KeyboardSimulate => write!(f, "keyboard.simulate"),
+            Self::KeyboardListen => write!(f, "keyboard.listen"),
+            Self::Audio => write!(f, "audio"),
+            Self::Tts => write!(f, "tts"),
+            Self::Spark => write!(f, "spark"),
+            Self::Secrets(service) => write!(f, "{}{}", SECRETS_PREFIX, service),
+            Self::ServeFiles => write!(f, "serve_files"),
+            Self::Fs(None) => write!(f, "{}{}", FS_PREFIX, ANY_PATH),
+            Self::Fs(Some(path)) => write!(f, "{}{}", FS_PREFIX, path.display()),
+        }
+    }
+}
@@ -268,6 +268,19 @@ export class EventHandler {
     }
   }
 
+  // 添加或者更新应用的配置，重新加载应用时使用
+  setAppConfig(config: AppConfig, data?: AppData): void {
+    this.configs.set(config.id, config);
+    if (data && !this.data.has(config.id)) {
+      this.data.set(config.id, data);
+    }
+  }
+
+  removeApp(id: string): void {
+    this.configs.delete(id);
+    this.data.delete(id);
+  }
+
   appData(id: string): AppData | undefined {
     return this.data.get(id);
   }
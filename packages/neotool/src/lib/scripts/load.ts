@@ -1,27 +1,45 @@
 import { convertFileSrc } from '@tauri-apps/api/tauri';
-import { BaseDirectory, createDir, exists, readTextFile, writeTextFile } from '@tauri-apps/api/fs';
 import { join } from '@tauri-apps/api/path';
 import {
   loadAppsConfig,
+  loadNeotoolConfig,
+  saveNeotoolConfig,
   canonicalize,
-  type AppConfig
+  neotoolToken,
+  type AppConfig,
+  type AppDiagnostic,
+  type SignatureStatus
 } from 'tauri-plugin-acfunlive-neotool-base-api';
 
 export type Config = {
   liverUID?: number;
+  // 应用目录，由base插件在启动时读取，修改后要重启才生效
   appsDir: string;
   appData: Record<string, { enable: boolean }>;
+  // 信任的应用发布者的ed25519公钥，base64，由base插件读取
+  trustedKeys?: string[];
+  // 只加载被信任的公钥签名的应用，由base插件读取
+  requireSigned?: boolean;
 };
 
-const configFile = 'neotool.conf.json';
-
 const defaultAppsDir = './apps';
 
-const fsOption = { dir: BaseDirectory.AppConfig };
+// neotool的token只保存在这里，不能传给应用
+let hostToken: Promise<string> | undefined;
+
+// 第一次调用要在加载应用之前
+export function getHostToken(): Promise<string> {
+  if (!hostToken) {
+    hostToken = neotoolToken();
+  }
+
+  return hostToken;
+}
 
+// 设置文件不在fs的scope里，只能通过base插件读写
 export async function loadConfig(): Promise<Config> {
-  if (await exists(configFile, fsOption)) {
-    const config: Config = JSON.parse(await readTextFile(configFile, fsOption));
+  const config = (await loadNeotoolConfig(await getHostToken())) as Config | null;
+  if (config) {
     if ((config.liverUID !== undefined && config.liverUID <= 0) || config.liverUID === null) {
       config.liverUID = undefined;
     }
@@ -35,42 +53,107 @@ export async function loadConfig(): Promise<Config> {
 }
 
 export async function saveConfig(config: Config): Promise<void> {
-  if (!(await exists(configFile, fsOption))) {
-    await createDir('', { dir: fsOption.dir, recursive: true });
-  }
-
-  await writeTextFile(configFile, JSON.stringify(config, null, 2), fsOption);
+  await saveNeotoolConfig(await getHostToken(), config);
 }
 
-export async function loadApp(jsPath: string, target: Element, cssPath?: string): Promise<void> {
+// version变化时重新加载文件，返回用来卸载应用的函数，token作为应用的属性传入
+export async function loadApp(
+  jsPath: string,
+  target: Element,
+  token: string,
+  cssPath?: string,
+  version = 0
+): Promise<() => void> {
+  const query = version > 0 ? `?t=${version}` : '';
+  let link: HTMLLinkElement | undefined;
   if (cssPath) {
     if (import.meta.env?.DEV) {
-      await import(/* @vite-ignore */ cssPath);
+      await import(/* @vite-ignore */ cssPath + query);
     } else {
-      const css = convertFileSrc(cssPath);
-      document
-        .getElementsByTagName('head')[0]
-        .insertAdjacentHTML('beforeend', `<link rel="stylesheet" href="${css}" />`);
+      link = document.createElement('link');
+      link.rel = 'stylesheet';
+      link.href = convertFileSrc(cssPath) + query;
+      document.getElementsByTagName('head')[0].appendChild(link);
     }
   }
   const module = import.meta.env?.DEV
-    ? await import(/* @vite-ignore */ jsPath)
-    : await import(/* @vite-ignore */ convertFileSrc(jsPath));
+    ? await import(/* @vite-ignore */ jsPath + query)
+    : await import(/* @vite-ignore */ convertFileSrc(jsPath) + query);
   const component = module.default;
 
-  new component({
-    target: target
+  const app = new component({
+    target: target,
+    props: { token }
   });
+
+  return () => {
+    app.$destroy();
+    link?.remove();
+  };
+}
+
+function formatSignature(signature: SignatureStatus): string {
+  switch (signature.status) {
+    case 'unsigned':
+      return 'app is not signed';
+    case 'trusted':
+      return `app is signed by ${signature.key}`;
+    case 'untrusted':
+      return `app is signed by untrusted key ${signature.key}`;
+    case 'invalid':
+      return `invalid signature: ${signature.message}`;
+  }
 }
 
-export async function loadAppConfigs(appsDir: string): Promise<AppConfig[]> {
-  const configs = await loadAppsConfig(appsDir);
+export function formatAppDiagnostic(diagnostic: AppDiagnostic): string {
+  const problem = diagnostic.problem;
+  switch (problem.type) {
+    case 'missingManifest':
+      return `${diagnostic.path}: missing neotool.app.json`;
+    case 'io':
+      return `${diagnostic.path}: ${problem.message}`;
+    case 'invalidJson':
+      return `${diagnostic.path}: invalid neotool.app.json: ${problem.message}`;
+    case 'missingField':
+      return `${diagnostic.path}: missing field ${problem.field} in neotool.app.json`;
+    case 'fileNotFound':
+      return `${diagnostic.path}: ${problem.field} file not found: ${problem.file}`;
+    case 'invalidField':
+      return `${diagnostic.path}: invalid ${problem.field}: ${problem.message}`;
+    case 'unsupportedManifestVersion':
+      return `${diagnostic.path}: unsupported manifest version ${problem.version}`;
+    case 'incompatibleHost':
+      return `${diagnostic.path}: requires neotool ${problem.required}, current version is ${problem.current}`;
+    case 'duplicateId':
+      return `${diagnostic.path}: app id ${problem.id} is already used by ${problem.path}`;
+    case 'notTrusted':
+      return `${diagnostic.path}: ${formatSignature(problem.signature)}`;
+  }
+}
+
+// 没有加载的应用目录的问题会显示在主页上
+export async function loadAppConfigs(): Promise<{
+  configs: AppConfig[];
+  diagnostics: AppDiagnostic[];
+}> {
+  const { apps: configs, diagnostics } = await loadAppsConfig(await getHostToken());
+  for (const diagnostic of diagnostics) {
+    console.log(`failed to load app ${formatAppDiagnostic(diagnostic)}`);
+  }
   for (const config of configs) {
-    config.entry = await canonicalize(await join(config.path, config.entry));
-    if (config.css) {
-      config.css = await canonicalize(await join(config.path, config.css));
-    }
+    await resolveAppPaths(config);
   }
 
-  return configs;
+  return { configs, diagnostics };
+}
+
+// 把应用配置里的文件路径改为绝对路径
+export async function resolveAppPaths(config: AppConfig): Promise<void> {
+  config.entry = await canonicalize(await join(config.path, config.entry));
+  if (config.css) {
+    config.css = await canonicalize(await join(config.path, config.css));
+  }
+  if (config.icon) {
+    config.icon = await canonicalize(await join(config.path, config.icon));
+  }
 }
@@ -1,6 +1,6 @@
 import { BackendSession, EventHandler, neotoolID } from '@acfunlive-neotool/shared';
 import tauriSession from 'acfunlive-backend-js/tauri.js';
-import type { AppConfig } from 'tauri-plugin-acfunlive-neotool-base-api';
+import type { AppConfig, AppDiagnostic } from 'tauri-plugin-acfunlive-neotool-base-api';
 
 import type { Config } from '$lib/scripts/load';
 
@@ -10,6 +10,8 @@ export const ssr = false;
 export type LoadedData = {
   config: Config;
   appConfigs: AppConfig[];
+  // 没有加载的应用目录的问题
+  appDiagnostics: AppDiagnostic[];
   session: BackendSession;
   eventHandler: EventHandler;
 };
@@ -19,8 +21,10 @@ export async function load(): Promise<LoadedData> {
     // 因为top level不能有Node没有的东西，所以要动态加载
     const load = await import('$lib/scripts/load');
 
+    // 在加载应用之前获取neotool的token
+    await load.getHostToken();
     const config = await load.loadConfig();
-    const appConfigs = await load.loadAppConfigs(config.appsDir);
+    const { configs: appConfigs, diagnostics: appDiagnostics } = await load.loadAppConfigs();
     const session = new BackendSession(
       tauriSession(),
       neotoolID,
@@ -38,6 +42,7 @@ export async function load(): Promise<LoadedData> {
     return {
       config,
       appConfigs,
+      appDiagnostics,
       session,
       eventHandler
     };
@@ -1,61 +1,459 @@
+import { listen, type UnlistenFn } from '@tauri-apps/api/event';
 import { invoke } from '@tauri-apps/api/tauri';
 
 export type AudioSourceId = number;
 
+export type AudioFormat =
+  | 'unknown'
+  | 'mp3'
+  | 'wav'
+  | 'pcm'
+  | 'ogg'
+  | 'flac'
+  | 'aac'
+  | 'm4a'
+  | 'opus';
+
+export type AudioOrigin = 'tts' | 'file' | 'generated';
+
+export type AudioSourceInfo = {
+  id: AudioSourceId;
+  bytes: number;
+  format: AudioFormat;
+  sampleRate?: number;
+  channels?: number;
+  // 单位为秒
+  duration?: number;
+  origin: AudioOrigin;
+  age: number;
+};
+
+export async function audioSourceInfo(audioSourceId: AudioSourceId): Promise<AudioSourceInfo> {
+  return await invoke('plugin:acfunlive-neotool-audio|get_audio_source_info', { audioSourceId });
+}
+
+export type OutputDevice = {
+  name: string;
+  isDefault: boolean;
+};
+
+export async function supportedFormats(): Promise<AudioFormat[]> {
+  return await invoke('plugin:acfunlive-neotool-audio|supported_formats');
+}
+
+// 不输出声音的设备，没有声卡时默认设备也是它
+export const NULL_OUTPUT = 'null';
+
+// 把声音写进应用缓存目录下audio目录里的WAV文件的设备，name只能是文件名，
+// 没有声音时不写入，文件达到4GiB后不再写入
+export function fileOutput(name: string): string {
+  return `file:${name}`;
+}
+
+// 最后一个是不输出声音的设备
+export async function listOutputDevices(): Promise<OutputDevice[]> {
+  return await invoke('plugin:acfunlive-neotool-audio|list_output_devices');
+}
+
+export type BusInfo = {
+  name: string;
+  volume: number;
+  muted: boolean;
+  ducked: boolean;
+  active: number;
+};
+
+// gain的范围为0到1，attack和release的单位为毫秒
+export type Ducking = {
+  enabled: boolean;
+  gain: number;
+  attack: number;
+  release: number;
+};
+
+export async function listBuses(): Promise<BusInfo[]> {
+  return await invoke('plugin:acfunlive-neotool-audio|list_buses');
+}
+
+// 修改总线、总音量、闪避和响度标准化的设置需要audio权限，会影响所有应用的音频，
+// 总线和总音量的范围为0到2，大于1时放大
+export async function setBusVolume(token: string, bus: string, volume: number): Promise<void> {
+  await invoke('plugin:acfunlive-neotool-audio|set_bus_volume', { token, bus, volume });
+}
+
+export async function setBusMuted(token: string, bus: string, muted: boolean): Promise<void> {
+  await invoke('plugin:acfunlive-neotool-audio|set_bus_muted', { token, bus, muted });
+}
+
+export async function setBusDucked(token: string, bus: string, ducked: boolean): Promise<void> {
+  await invoke('plugin:acfunlive-neotool-audio|set_bus_ducked', { token, bus, ducked });
+}
+
+export async function masterVolume(): Promise<number> {
+  return await invoke('plugin:acfunlive-neotool-audio|get_master_volume');
+}
+
+export async function setMasterVolume(token: string, volume: number): Promise<void> {
+  await invoke('plugin:acfunlive-neotool-audio|set_master_volume', { token, volume });
+}
+
+// 默认关闭，只影响之后添加的音频源。
+// 音频流（比如ttsStream）在写完前就开始播放，没法测量综合响度，不做标准化，
+// 需要标准化时用tts或者ttsFull得到完整的音频源
+export type Normalization = {
+  enabled: boolean;
+  // 目标响度，单位为LUFS
+  target: number;
+  // 最多提高多少dB
+  maxGain: number;
+};
+
+export async function normalization(): Promise<Normalization> {
+  return await invoke('plugin:acfunlive-neotool-audio|get_normalization');
+}
+
+export async function setNormalization(token: string, normalization: Normalization): Promise<void> {
+  await invoke('plugin:acfunlive-neotool-audio|set_normalization', { token, normalization });
+}
+
+export async function ducking(): Promise<Ducking> {
+  return await invoke('plugin:acfunlive-neotool-audio|get_ducking');
+}
+
+export async function setDucking(token: string, ducking: Ducking): Promise<void> {
+  await invoke('plugin:acfunlive-neotool-audio|set_ducking', { token, ducking });
+}
+
+// 时间的单位为毫秒，值为0时不启用对应的效果，超出范围时setEffects会报错
+export type Effects = {
+  // 0.25到4
+  speed: number;
+  // 0.25到4
+  pitch: number;
+  // 最长60000
+  fadeIn: number;
+  // 最长60000，音频流（比如ttsStream）写完之前不知道时长，不会淡出
+  fadeOut: number;
+  // 最长60000
+  stopFade: number;
+  // 最长2000
+  echoDelay: number;
+  // 0到1，不包括1
+  echoFeedback: number;
+  // 0到1
+  echoMix: number;
+  // 单位为Hz，20到20000
+  lowPass: number;
+};
+
+// level事件只发给neotool里的应用，OBS浏览器源之类的页面需要应用自己转发，
+// serve_files只提供静态文件，没有WebSocket
+export type Analysis = {
+  enabled: boolean;
+  // 发送level事件的间隔，单位为毫秒，间隔超过1秒左右时只分析最近的采样
+  interval: number;
+  // 频谱的频带数量，频带按对数划分，0为不计算频谱
+  bands: number;
+};
+
+export type QueueOptions = {
+  // 优先级，越大越先播放，相同优先级的按加入顺序播放
+  priority?: number;
+  // 打断正在播放的音频源并马上播放
+  interrupt?: boolean;
+  // 等待播放的音频源已经有这么多时丢弃新的音频源
+  maxQueueLen?: number;
+  // 这段时间内加入过数据相同的音频源时丢弃新的音频源，单位为毫秒
+  dedupWindow?: number;
+  // 是否做响度标准化，不设置时按全局设置，音频流不做标准化
+  normalize?: boolean;
+};
+
+export type QueueItem = {
+  audioSourceId: AudioSourceId;
+  priority: number;
+  playing: boolean;
+  duration?: number;
+};
+
+// 时间的单位都是秒，不知道时长时为undefined
+export type AudioProgress = {
+  audioSourceId?: AudioSourceId;
+  position: number;
+  duration?: number;
+  // 正在播放和等待播放的音频源的总时长
+  totalDuration?: number;
+};
+
+export type DropReason = 'queueFull' | 'duplicate';
+
+export type AudioEventKind =
+  | { type: 'sourceStarted'; audioSourceId: AudioSourceId }
+  | { type: 'sourceFinished'; audioSourceId: AudioSourceId }
+  | { type: 'queueEmpty' }
+  | { type: 'sourceDropped'; audioSourceId: AudioSourceId; reason: DropReason }
+  | { type: 'error'; message: string }
+  // rms和peak的范围是0到1，spectrum是各个频带的幅度
+  | { type: 'level'; rms: number; peak: number; spectrum: number[] }
+  // position的单位为秒
+  | { type: 'position'; audioSourceId: AudioSourceId; position: number };
+
+export type AudioEvent = { audioId: number } & AudioEventKind;
+
+const audioEvent = 'acfunlive-neotool-audio:event';
+
 export class Audio {
   readonly #id: number;
+  readonly #token: string;
 
-  private constructor(id: number) {
+  private constructor(id: number, token: string) {
     this.#id = id;
+    this.#token = token;
+  }
+
+  // 需要audio权限，token是neotool加载应用时传入的token，
+  // 不指定设备时使用默认设备，不指定总线时使用voice总线，总线只有voice、sfx和music
+  static async newAudio(token: string, device?: string, bus?: string): Promise<Audio> {
+    const id: number = await invoke('plugin:acfunlive-neotool-audio|new_audio', {
+      token,
+      device,
+      bus
+    });
+
+    return new Audio(id, token);
   }
 
-  static async newAudio(): Promise<Audio> {
-    const id: number = await invoke('plugin:acfunlive-neotool-audio|new_audio');
+  async device(): Promise<string> {
+    return await invoke('plugin:acfunlive-neotool-audio|get_audio_device', {
+      token: this.#token,
+      audioId: this.#id
+    });
+  }
 
-    return new Audio(id);
+  async setDevice(device?: string): Promise<void> {
+    await invoke('plugin:acfunlive-neotool-audio|set_audio_device', {
+      token: this.#token,
+      audioId: this.#id,
+      device
+    });
   }
 
   async delete(): Promise<void> {
-    await invoke('plugin:acfunlive-neotool-audio|delete_audio', { audioId: this.#id });
+    await invoke('plugin:acfunlive-neotool-audio|delete_audio', {
+      token: this.#token,
+      audioId: this.#id
+    });
   }
 
   async isEmpty(): Promise<boolean> {
     return await invoke('plugin:acfunlive-neotool-audio|is_audio_queue_empty', {
+      token: this.#token,
       audioId: this.#id
     });
   }
 
-  async add(audioSourceId: AudioSourceId): Promise<void> {
-    await invoke('plugin:acfunlive-neotool-audio|add_audio', { audioId: this.#id, audioSourceId });
+  async bus(): Promise<string> {
+    return await invoke('plugin:acfunlive-neotool-audio|get_audio_bus', {
+      token: this.#token,
+      audioId: this.#id
+    });
   }
 
-  async addAll(audioSourceIdList: AudioSourceId[]): Promise<void> {
+  // 总线不存在时报错
+  async setBus(bus: string): Promise<void> {
+    await invoke('plugin:acfunlive-neotool-audio|set_audio_bus', {
+      token: this.#token,
+      audioId: this.#id,
+      bus
+    });
+  }
+
+  async listen(handler: (event: AudioEventKind) => void): Promise<UnlistenFn> {
+    return await listen<AudioEvent>(audioEvent, (event) => {
+      if (event.payload.audioId === this.#id) {
+        handler(event.payload);
+      }
+    });
+  }
+
+  // 单位为毫秒，0为不发送播放进度事件
+  async setPositionInterval(interval: number): Promise<void> {
+    await invoke('plugin:acfunlive-neotool-audio|set_position_interval', {
+      token: this.#token,
+      audioId: this.#id,
+      interval
+    });
+  }
+
+  // 没有加入队列的音频源会发送sourceDropped事件
+  async add(audioSourceId: AudioSourceId, options?: QueueOptions): Promise<void> {
+    await invoke('plugin:acfunlive-neotool-audio|add_audio', {
+      token: this.#token,
+      audioId: this.#id,
+      audioSourceId,
+      options
+    });
+  }
+
+  // 只能播放应用自己的目录或应用数据目录里的文件，以及文件系统scope允许的文件
+  async addFile(path: string, options?: QueueOptions): Promise<AudioSourceId> {
+    return await invoke('plugin:acfunlive-neotool-audio|add_audio_file', {
+      token: this.#token,
+      audioId: this.#id,
+      path,
+      options
+    });
+  }
+
+  // PCM数据需要指定采样率和声道数
+  async addBytes(
+    bytes: Uint8Array | number[],
+    format?: AudioFormat,
+    sampleRate?: number,
+    channels?: number,
+    options?: QueueOptions
+  ): Promise<AudioSourceId> {
+    return await invoke('plugin:acfunlive-neotool-audio|add_audio_bytes', {
+      token: this.#token,
+      audioId: this.#id,
+      bytes: Array.from(bytes),
+      format,
+      sampleRate,
+      channels,
+      options
+    });
+  }
+
+  async addAll(audioSourceIdList: AudioSourceId[], options?: QueueOptions): Promise<void> {
     for (const id of audioSourceIdList) {
-      await this.add(id);
+      await this.add(id, options);
     }
   }
 
+  // 正在播放的音频源排在最前面
+  async queue(): Promise<QueueItem[]> {
+    return await invoke('plugin:acfunlive-neotool-audio|list_audio_queue', {
+      token: this.#token,
+      audioId: this.#id
+    });
+  }
+
+  // 正在播放的音频源会被跳过
+  async remove(audioSourceId: AudioSourceId): Promise<boolean> {
+    return await invoke('plugin:acfunlive-neotool-audio|remove_from_audio_queue', {
+      token: this.#token,
+      audioId: this.#id,
+      audioSourceId
+    });
+  }
+
+  async progress(): Promise<AudioProgress> {
+    return await invoke('plugin:acfunlive-neotool-audio|get_audio_progress', {
+      token: this.#token,
+      audioId: this.#id
+    });
+  }
+
+  // 跳转到正在播放的音频源的position秒处，音频源过期或者被挤出注册表后不能跳转，
+  // 音频流（比如ttsStream）不能跳转
+  async seek(position: number): Promise<void> {
+    await invoke('plugin:acfunlive-neotool-audio|seek_audio', {
+      token: this.#token,
+      audioId: this.#id,
+      position
+    });
+  }
+
+  // 跳到下一个音频源
+  async skip(): Promise<boolean> {
+    return await invoke('plugin:acfunlive-neotool-audio|skip_audio', {
+      token: this.#token,
+      audioId: this.#id
+    });
+  }
+
+  // index为0时下一个播放
+  async move(audioSourceId: AudioSourceId, index: number): Promise<boolean> {
+    return await invoke('plugin:acfunlive-neotool-audio|move_in_audio_queue', {
+      token: this.#token,
+      audioId: this.#id,
+      audioSourceId,
+      index
+    });
+  }
+
   async volume(): Promise<number> {
-    return await invoke('plugin:acfunlive-neotool-audio|get_volume', { audioId: this.#id });
+    return await invoke('plugin:acfunlive-neotool-audio|get_volume', {
+      token: this.#token,
+      audioId: this.#id
+    });
   }
 
   async setVolume(volume: number): Promise<void> {
-    await invoke('plugin:acfunlive-neotool-audio|set_volume', { audioId: this.#id, volume });
+    await invoke('plugin:acfunlive-neotool-audio|set_volume', {
+      token: this.#token,
+      audioId: this.#id,
+      volume
+    });
+  }
+
+  async effects(): Promise<Effects> {
+    return await invoke('plugin:acfunlive-neotool-audio|get_effects', {
+      token: this.#token,
+      audioId: this.#id
+    });
+  }
+
+  // 只修改给出的效果，其它效果保持不变
+  async setEffects(effects: Partial<Effects>): Promise<void> {
+    await invoke('plugin:acfunlive-neotool-audio|set_effects', {
+      token: this.#token,
+      audioId: this.#id,
+      effects: { ...(await this.effects()), ...effects }
+    });
+  }
+
+  async analysis(): Promise<Analysis> {
+    return await invoke('plugin:acfunlive-neotool-audio|get_analysis', {
+      token: this.#token,
+      audioId: this.#id
+    });
+  }
+
+  // 只修改给出的设置，开启后会发送level事件
+  async setAnalysis(analysis: Partial<Analysis>): Promise<void> {
+    await invoke('plugin:acfunlive-neotool-audio|set_analysis', {
+      token: this.#token,
+      audioId: this.#id,
+      analysis: { ...(await this.analysis()), ...analysis }
+    });
   }
 
   async play(): Promise<void> {
-    await invoke('plugin:acfunlive-neotool-audio|play_audio', { audioId: this.#id });
+    await invoke('plugin:acfunlive-neotool-audio|play_audio', {
+      token: this.#token,
+      audioId: this.#id
+    });
   }
 
   async pause(): Promise<void> {
-    await invoke('plugin:acfunlive-neotool-audio|pause_audio', { audioId: this.#id });
+    await invoke('plugin:acfunlive-neotool-audio|pause_audio', {
+      token: this.#token,
+      audioId: this.#id
+    });
   }
 
   async stop(): Promise<void> {
-    await invoke('plugin:acfunlive-neotool-audio|stop_audio', { audioId: this.#id });
+    await invoke('plugin:acfunlive-neotool-audio|stop_audio', {
+      token: this.#token,
+      audioId: this.#id
+    });
   }
 
   async clear(): Promise<void> {
-    await invoke('plugin:acfunlive-neotool-audio|clear_audio', { audioId: this.#id });
+    await invoke('plugin:acfunlive-neotool-audio|clear_audio', {
+      token: this.#token,
+      audioId: this.#id
+    });
   }
 }
@@ -0,0 +1,418 @@
+use std::{
+    f32::consts::PI,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    thread,
+    time::Duration,
+};
+
+use rodio::Source;
+use serde::{Deserialize, Serialize};
+
+use crate::event::{AudioEventKind, EventState};
+
+// FFT的长度，必须是2的幂
+const FFT_SIZE: usize = 1024;
+
+// 频谱的最低频率
+const MIN_FREQUENCY: f32 = 20.0;
+
+// 频谱的最高频率，超过奈奎斯特频率时用奈奎斯特频率
+const MAX_FREQUENCY: f32 = 16000.0;
+
+const MAX_BANDS: u32 = 256;
+
+// 音频线程写入、分析线程读取的环形缓冲区能放下的单声道采样数，必须是2的幂，
+// 间隔太长时只分析最近的这么多采样
+const BUFFER_SIZE: usize = 1 << 16;
+
+/// 音量和频谱分析的设置
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct Analysis {
+    pub(crate) enabled: bool,
+    /// 发送分析结果的间隔，单位为毫秒
+    pub(crate) interval: u32,
+    /// 频谱的频带数量，频带按对数划分，0为不计算频谱
+    pub(crate) bands: u32,
+}
+
+impl Default for Analysis {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval: 33,
+            bands: 32,
+        }
+    }
+}
+
+/// 音频的分析设置和待分析的采样，音频线程只读写原子变量，分析在单独的线程里进行
+#[derive(Debug)]
+pub(crate) struct AnalysisState {
+    enabled: AtomicBool,
+    interval: AtomicU32,
+    bands: AtomicU32,
+    // 单声道采样的环形缓冲区，保存的是f32的比特
+    buffer: Box<[AtomicU32]>,
+    // 写入过的总采样数
+    written: AtomicU64,
+    sample_rate: AtomicU32,
+    // 分析线程是否在运行
+    running: AtomicBool,
+}
+
+impl Default for AnalysisState {
+    #[inline]
+    fn default() -> Self {
+        let analysis = Analysis::default();
+
+        Self {
+            enabled: AtomicBool::new(analysis.enabled),
+            interval: AtomicU32::new(analysis.interval),
+            bands: AtomicU32::new(analysis.bands),
+            buffer: (0..BUFFER_SIZE).map(|_| AtomicU32::new(0)).collect(),
+            written: AtomicU64::new(0),
+            sample_rate: AtomicU32::new(0),
+            running: AtomicBool::new(false),
+        }
+    }
+}
+
+impl AnalysisState {
+    #[inline]
+    pub(crate) fn analysis(&self) -> Analysis {
+        Analysis {
+            enabled: self.enabled.load(Ordering::Relaxed),
+            interval: self.interval.load(Ordering::Relaxed),
+            bands: self.bands.load(Ordering::Relaxed),
+        }
+    }
+
+    /// 开启时启动分析线程，关闭或者音频被删除后分析线程退出
+    pub(crate) fn set_analysis(self: &Arc<Self>, analysis: Analysis, events: &Arc<EventState>) {
+        self.interval
+            .store(analysis.interval.max(1), Ordering::Relaxed);
+        self.bands
+            .store(analysis.bands.min(MAX_BANDS), Ordering::Relaxed);
+        self.enabled.store(analysis.enabled, Ordering::Relaxed);
+
+        if analysis.enabled && !self.running.swap(true, Ordering::AcqRel) {
+            let state = Arc::downgrade(self);
+            let events = Arc::clone(events);
+            let spawned = thread::Builder::new()
+                .name("audio-analysis".to_string())
+                .spawn(move || run(state, events));
+            if spawned.is_err() {
+                self.running.store(false, Ordering::Release);
+            }
+        }
+    }
+
+    // 在音频线程里调用，不分配内存也不加锁
+    #[inline]
+    fn push(&self, sample: f32, sample_rate: u32) {
+        let written = self.written.load(Ordering::Relaxed);
+        self.buffer[written as usize % BUFFER_SIZE].store(sample.to_bits(), Ordering::Relaxed);
+        self.sample_rate.store(sample_rate, Ordering::Relaxed);
+        self.written.store(written + 1, Ordering::Release);
+    }
+
+    #[inline]
+    fn sample(&self, index: u64) -> f32 {
+        f32::from_bits(self.buffer[index as usize % BUFFER_SIZE].load(Ordering::Relaxed))
+    }
+}
+
+/// 分析线程读取环形缓冲区的位置
+struct Reader {
+    read: u64,
+    // 播放时发送过分析结果，停止播放时要再发送一次静音
+    active: bool,
+}
+
+impl Reader {
+    #[inline]
+    fn new(state: &AnalysisState) -> Self {
+        Self {
+            read: state.written.load(Ordering::Acquire),
+            active: false,
+        }
+    }
+
+    /// 分析上次读取后写入的采样，没有新的采样时返回`None`，刚停止播放时返回静音
+    fn level(&mut self, state: &AnalysisState) -> Option<AudioEventKind> {
+        let written = state.written.load(Ordering::Acquire);
+        let bands = state.bands.load(Ordering::Relaxed) as usize;
+        if written == self.read {
+            return if self.active {
+                self.active = false;
+                Some(AudioEventKind::Level {
+                    rms: 0.0,
+                    peak: 0.0,
+                    spectrum: vec![0.0; bands],
+                })
+            } else {
+                None
+            };
+        }
+
+        let start = self.read.max(written.saturating_sub(BUFFER_SIZE as u64));
+        self.read = written;
+        self.active = true;
+
+        let (square_sum, peak) = (start..written)
+            .map(|i| state.sample(i))
+            .fold((0.0, 0.0), |(sum, peak): (f32, f32), s| {
+                (sum + s * s, peak.max(s.abs()))
+            });
+        let rms = (square_sum / (written - start) as f32).sqrt();
+        let sample_rate = state.sample_rate.load(Ordering::Relaxed);
+        let spectrum = if bands > 0 && sample_rate > 0 {
+            let samples: Vec<f32> = (written.saturating_sub(FFT_SIZE as u64)..written)
+                .map(|i| state.sample(i))
+                .collect();
+            let mut window = vec![0.0; FFT_SIZE - samples.len()];
+            window.extend(samples);
+            spectrum(&window, sample_rate, bands)
+        } else {
+            Vec::new()
+        };
+
+        Some(AudioEventKind::Level {
+            rms,
+            peak,
+            spectrum,
+        })
+    }
+}
+
+// 分析线程，按设置的间隔发送音量和频谱事件
+fn run(state: Weak<AnalysisState>, events: Arc<EventState>) {
+    let mut reader = match state.upgrade() {
+        Some(state) => Reader::new(&state),
+        None => return,
+    };
+
+    loop {
+        let interval = match state.upgrade() {
+            Some(state) => state.interval.load(Ordering::Relaxed),
+            None => return,
+        };
+        thread::sleep(Duration::from_millis(interval.into()));
+
+        let state = match state.upgrade() {
+            Some(state) => state,
+            None => return,
+        };
+        if !state.enabled.load(Ordering::Relaxed) {
+            if reader.active {
+                reader.read = state.written.load(Ordering::Acquire);
+                if let Some(level) = reader.level(&state) {
+                    events.send(level);
+                }
+            }
+            state.running.store(false, Ordering::Release);
+            // 退出前又被开启时继续运行
+            if !state.enabled.load(Ordering::Relaxed) || state.running.swap(true, Ordering::AcqRel)
+            {
+                return;
+            }
+            continue;
+        }
+
+        if let Some(level) = reader.level(&state) {
+            events.send(level);
+        }
+    }
+}
+
+/// 原地计算的基2 FFT，长度必须是2的幂
+fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let a = start + k;
+                let b = a + len / 2;
+                let re_b = re[b] * cos - im[b] * sin;
+                let im_b = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - re_b;
+                im[b] = im[a] - im_b;
+                re[a] += re_b;
+                im[a] += im_b;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+/// 计算按对数划分的频带的幅度，每个频带取里面最大的幅度，范围大约是0到1
+fn spectrum(samples: &[f32], sample_rate: u32, bands: usize) -> Vec<f32> {
+    let n = samples.len();
+    let mut re: Vec<f32> = samples
+        .iter()
+        .enumerate()
+        .map(|(i, sample)| {
+            // Hann窗
+            let window = 0.5 - 0.5 * (2.0 * PI * i as f32 / (n - 1) as f32).cos();
+            sample * window
+        })
+        .collect();
+    let mut im = vec![0.0; n];
+    fft(&mut re, &mut im);
+
+    // Hann窗的系数和是n/2，单频正弦波的幅度算出来是1
+    let scale = 4.0 / n as f32;
+    let magnitude = |bin: usize| (re[bin] * re[bin] + im[bin] * im[bin]).sqrt() * scale;
+
+    let bin_width = sample_rate as f32 / n as f32;
+    let max = MAX_FREQUENCY.min(sample_rate as f32 / 2.0);
+    let ratio = (max / MIN_FREQUENCY).max(1.0);
+    let mut low = ((MIN_FREQUENCY / bin_width) as usize).clamp(1, n / 2 - 1);
+
+    (1..=bands)
+        .map(|band| {
+            let frequency = MIN_FREQUENCY * ratio.powf(band as f32 / bands as f32);
+            let high = ((frequency / bin_width) as usize).clamp(low + 1, n / 2);
+            let value = (low..high).map(magnitude).fold(0.0, f32::max);
+            low = high.min(n / 2 - 1);
+
+            value.min(1.0)
+        })
+        .collect()
+}
+
+/// 把播放的音频混成单声道写进环形缓冲区，交给分析线程分析
+pub(crate) struct Analyzed<S> {
+    inner: S,
+    state: Arc<AnalysisState>,
+    channel: u16,
+    frame: f32,
+}
+
+impl<S> Analyzed<S> {
+    #[inline]
+    pub(crate) fn new(inner: S, state: Arc<AnalysisState>) -> Self {
+        Self {
+            inner,
+            state,
+            channel: 0,
+            frame: 0.0,
+        }
+    }
+}
+
+impl<S: Source<Item = f32>> Iterator for Analyzed<S> {
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let sample = self.inner.next()?;
+        if !self.state.enabled.load(Ordering::Relaxed) {
+            self.channel = 0;
+            self.frame = 0.0;
+            return Some(sample);
+        }
+
+        let channels = self.inner.channels().max(1);
+        self.frame += sample / channels as f32;
+        self.channel += 1;
+        if self.channel >= channels {
+            self.state.push(self.frame, self.inner.sample_rate());
+            self.channel = 0;
+            self.frame = 0.0;
+        }
+
+        Some(sample)
+    }
+}
+
+impl<S: Source<Item = f32>> Source for Analyzed<S> {
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rodio::buffer::SamplesBuffer;
+
+    use super::*;
+
+    #[test]
+    fn test_analysis_level() {
+        let state = Arc::new(AnalysisState::default());
+        state.enabled.store(true, Ordering::Relaxed);
+        let mut reader = Reader::new(&state);
+        assert!(reader.level(&state).is_none());
+
+        // 0.5幅度的1kHz正弦波，两个声道一样
+        let samples: Vec<f32> = (0..4800)
+            .flat_map(|i| {
+                let sample = (2.0 * PI * 1000.0 * i as f32 / 48000.0).sin() * 0.5;
+                [sample, sample]
+            })
+            .collect();
+        let source = SamplesBuffer::new(2, 48000, samples);
+        assert_eq!(Analyzed::new(source, Arc::clone(&state)).count(), 9600);
+
+        let (rms, peak, spectrum) = match reader.level(&state) {
+            Some(AudioEventKind::Level {
+                rms,
+                peak,
+                spectrum,
+            }) => (rms, peak, spectrum),
+            _ => unreachable!(),
+        };
+        assert!((rms - 0.5 / 2f32.sqrt()).abs() < 0.01);
+        assert!((peak - 0.5).abs() < 0.01);
+        assert_eq!(spectrum.len(), 32);
+        let loudest = spectrum.iter().cloned().fold(0.0, f32::max);
+        assert!(loudest > 0.35 && loudest < 0.55);
+
+        // 停止播放后发送一次静音
+        match reader.level(&state) {
+            Some(AudioEventKind::Level { rms, peak, .. }) => assert_eq!((rms, peak), (0.0, 0.0)),
+            _ => unreachable!(),
+        }
+        assert!(reader.level(&state).is_none());
+    }
+}
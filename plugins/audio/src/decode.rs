@@ -0,0 +1,541 @@
+use std::{
+    io::{self, BufReader, Cursor, Read, Seek, SeekFrom},
+    sync::mpsc::{sync_channel, Receiver, TryRecvError},
+    thread,
+    time::Duration,
+};
+
+use acfunlive_neotool_audio::{AudioData, AudioFormat, AudioSource};
+use audiopus::{
+    coder::Decoder as OpusDecoder, packet::Packet as OpusPacket, Channels, MutSignals, SampleRate,
+};
+use ogg::PacketReader;
+use rodio::{
+    buffer::SamplesBuffer,
+    decoder::{DecoderError, Mp4Type},
+    Decoder, Source,
+};
+
+use crate::{Error, Result};
+
+pub(crate) type BoxedSource = Box<dyn Source<Item = i16> + Send>;
+
+/// 能解码的格式
+pub(crate) const SUPPORTED_FORMATS: &[AudioFormat] = &[
+    AudioFormat::Mp3,
+    AudioFormat::Wav,
+    AudioFormat::Pcm,
+    AudioFormat::Ogg,
+    AudioFormat::Flac,
+    AudioFormat::Aac,
+    AudioFormat::M4a,
+    AudioFormat::Opus,
+];
+
+// 判断格式需要的字节数
+const HEADER_LEN: usize = 36;
+
+// Opus解码后的采样率
+const OPUS_SAMPLE_RATE: u32 = 48000;
+
+// 一个Opus包最长120毫秒，48kHz下是5760个采样帧
+const OPUS_MAX_FRAMES: usize = 5760;
+
+// 解码线程每次发送的采样帧数
+const CHUNK_FRAMES: usize = 1024;
+
+// 解码线程最多预先解码的块数
+const CHUNK_CAPACITY: usize = 16;
+
+/// 逐个读取16位有符号小端PCM采样，用于还在写入中的音频流
+struct PcmSource<R: Read> {
+    reader: BufReader<R>,
+    channels: u16,
+    sample_rate: u32,
+}
+
+impl<R: Read> Iterator for PcmSource<R> {
+    type Item = i16;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut bytes = [0; 2];
+        self.reader.read_exact(&mut bytes).ok()?;
+
+        Some(i16::from_le_bytes(bytes))
+    }
+}
+
+impl<R: Read> Source for PcmSource<R> {
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// 解码Ogg容器里的Opus，只支持单声道和立体声
+struct OpusSource<R: Read + Seek> {
+    reader: PacketReader<R>,
+    decoder: OpusDecoder,
+    channels: u16,
+    // 开头还要丢掉的采样帧数
+    pre_skip: usize,
+    // 已经解码的采样帧数，包括开头丢掉的
+    granule: u64,
+    buffer: Vec<i16>,
+    position: usize,
+}
+
+impl<R: Read + Seek> OpusSource<R> {
+    fn new(reader: R) -> Result<Self> {
+        let mut reader = PacketReader::new(reader);
+        // 第一个包是OpusHead，第二个包是OpusTags
+        let head = reader.read_packet_expected()?.data;
+        if head.len() < 19 || !head.starts_with(b"OpusHead") {
+            return Err(Error::InvalidOpusHeader);
+        }
+        let channels = match (head[18], head[9]) {
+            (0, 1) => Channels::Mono,
+            (0, 2) => Channels::Stereo,
+            _ => return Err(Error::UnsupportedFormat(AudioFormat::Opus)),
+        };
+        let pre_skip = u16::from_le_bytes([head[10], head[11]]);
+        // 单位是1/256 dB，和Opus解码器的增益一样
+        let gain = i16::from_le_bytes([head[16], head[17]]);
+        let _ = reader.read_packet_expected()?;
+
+        let decoder = OpusDecoder::new(SampleRate::Hz48000, channels)?;
+        if gain != 0 {
+            decoder.set_gain(gain.into())?;
+        }
+
+        Ok(Self {
+            reader,
+            decoder,
+            channels: head[9].into(),
+            pre_skip: pre_skip.into(),
+            granule: 0,
+            buffer: Vec::new(),
+            position: 0,
+        })
+    }
+
+    fn decode_packet(&mut self, data: &[u8]) -> audiopus::Result<usize> {
+        self.buffer
+            .resize(OPUS_MAX_FRAMES * self.channels as usize, 0);
+        let packet = OpusPacket::try_from(data)?;
+        let output = MutSignals::try_from(&mut self.buffer)?;
+
+        self.decoder.decode(Some(packet), output, false)
+    }
+}
+
+impl<R: Read + Seek> Iterator for OpusSource<R> {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(&sample) = self.buffer.get(self.position) {
+                self.position += 1;
+                return Some(sample);
+            }
+
+            let packet = self.reader.read_packet().ok()??;
+            let mut frames = match self.decode_packet(&packet.data) {
+                Ok(frames) => frames,
+                // 跳过解码失败的包
+                Err(_) => {
+                    self.buffer.clear();
+                    continue;
+                }
+            };
+            // 最后一页的granule position是总采样帧数，多出来的是补齐的数据
+            if packet.last_in_stream() {
+                let end = packet.absgp_page().saturating_sub(self.granule);
+                frames = frames.min(end.try_into().unwrap_or(usize::MAX));
+            }
+            self.granule += frames as u64;
+            self.buffer.truncate(frames * self.channels as usize);
+
+            let skip = self.pre_skip.min(frames);
+            self.pre_skip -= skip;
+            self.position = skip * self.channels as usize;
+        }
+    }
+}
+
+impl<R: Read + Seek> Source for OpusSource<R> {
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        OPUS_SAMPLE_RATE
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// 在单独的线程里解码音频流，播放时数据不够就输出静音，不会阻塞音频输出的回调
+struct StreamSource {
+    receiver: Receiver<Vec<i16>>,
+    chunk: std::vec::IntoIter<i16>,
+    // 数据不够时还要输出的静音采样数，总是凑够整帧，避免声道错位
+    silence: usize,
+    channels: u16,
+    sample_rate: u32,
+    total_duration: Option<Duration>,
+}
+
+impl StreamSource {
+    fn new(mut source: BoxedSource) -> Result<Self> {
+        let channels = source.channels();
+        let sample_rate = source.sample_rate();
+        let total_duration = source.total_duration();
+        let (sender, receiver) = sync_channel(CHUNK_CAPACITY);
+        let chunk_len = CHUNK_FRAMES * channels.max(1) as usize;
+        // 播放结束后接收端被drop，解码线程在下一次发送时退出；
+        // 写入端一直不关闭的话解码线程会阻塞在读取上，直到音频流关闭
+        thread::Builder::new()
+            .name("audio-stream-decoder".to_string())
+            .spawn(move || loop {
+                let chunk: Vec<i16> = source.by_ref().take(chunk_len).collect();
+                if chunk.is_empty() || sender.send(chunk).is_err() {
+                    break;
+                }
+            })?;
+
+        Ok(Self {
+            receiver,
+            chunk: Vec::new().into_iter(),
+            silence: 0,
+            channels,
+            sample_rate,
+            total_duration,
+        })
+    }
+}
+
+impl Iterator for StreamSource {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.silence > 0 {
+            self.silence -= 1;
+            return Some(0);
+        }
+
+        if let Some(sample) = self.chunk.next() {
+            return Some(sample);
+        }
+
+        match self.receiver.try_recv() {
+            Ok(chunk) => {
+                self.chunk = chunk.into_iter();
+                self.chunk.next()
+            }
+            Err(TryRecvError::Empty) => {
+                self.silence = self.channels.max(1) as usize - 1;
+                Some(0)
+            }
+            Err(TryRecvError::Disconnected) => None,
+        }
+    }
+}
+
+impl Source for StreamSource {
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.total_duration
+    }
+}
+
+#[inline]
+fn pcm_parameters(source: &AudioSource) -> Result<(u32, u16)> {
+    match (source.sample_rate, source.channels) {
+        (Some(sample_rate), Some(channels)) if sample_rate > 0 && channels > 0 => {
+            Ok((sample_rate, channels))
+        }
+        _ => Err(Error::MissingPcmParameters),
+    }
+}
+
+/// 读取数据开头判断格式，读完后回到开头
+fn detect<R: Read + Seek>(reader: &mut R) -> io::Result<AudioFormat> {
+    let mut header = [0; HEADER_LEN];
+    let mut len = 0;
+    while len < HEADER_LEN {
+        match reader.read(&mut header[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    reader.seek(SeekFrom::Start(0))?;
+
+    Ok(AudioFormat::detect(&header[..len]))
+}
+
+fn decode_reader<R>(format: AudioFormat, mut reader: R) -> Result<BoxedSource>
+where
+    R: Read + Seek + Send + Sync + 'static,
+{
+    let format = match format {
+        AudioFormat::Unknown => detect(&mut reader)?,
+        format => format,
+    };
+    let decoder = match format {
+        AudioFormat::Opus => return Ok(Box::new(OpusSource::new(reader)?)),
+        AudioFormat::Mp3 => Decoder::new_mp3(reader),
+        AudioFormat::Wav => Decoder::new_wav(reader),
+        AudioFormat::Flac => Decoder::new_flac(reader),
+        AudioFormat::Aac => Decoder::new_aac(reader),
+        AudioFormat::M4a => Decoder::new_mp4(reader, Mp4Type::M4a),
+        AudioFormat::Unknown | AudioFormat::Pcm | AudioFormat::Ogg => Decoder::new(reader),
+    };
+
+    match decoder {
+        Ok(decoder) => Ok(Box::new(decoder)),
+        Err(DecoderError::UnrecognizedFormat) => Err(Error::UnsupportedFormat(format)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// 解码音频源。音频流要先读到开头的数据才能创建解码器，这时会阻塞，所以不要在异步任务里直接调用。
+/// 之后音频流在单独的线程里解码，返回的音频源不会阻塞。
+pub(crate) fn decode(source: AudioSource) -> Result<BoxedSource> {
+    if source.format == AudioFormat::Pcm {
+        let (sample_rate, channels) = pcm_parameters(&source)?;
+
+        return Ok(match source.data {
+            AudioData::Complete(data) => {
+                let samples: Vec<i16> = data
+                    .chunks_exact(2)
+                    .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                    .collect();
+
+                Box::new(SamplesBuffer::new(channels, sample_rate, samples))
+            }
+            AudioData::Stream(reader) => Box::new(StreamSource::new(Box::new(PcmSource {
+                reader: BufReader::new(reader),
+                channels,
+                sample_rate,
+            }))?),
+        });
+    }
+
+    match source.data {
+        AudioData::Complete(data) => decode_reader(source.format, Cursor::new(data)),
+        AudioData::Stream(reader) => Ok(Box::new(StreamSource::new(decode_reader(
+            source.format,
+            reader,
+        )?)?)),
+    }
+}
+
+/// 解码完整的音频源数出时长，用在解码器不知道时长的格式（比如MP3），解码失败时返回`None`
+pub(crate) fn decoded_duration(source: AudioSource) -> Option<Duration> {
+    let decoded = decode(source).ok()?;
+    let samples_per_second = decoded.sample_rate() as u64 * decoded.channels() as u64;
+    if samples_per_second == 0 {
+        return None;
+    }
+    let samples = decoded.count() as u64;
+
+    Some(Duration::from_nanos(
+        samples * 1_000_000_000 / samples_per_second,
+    ))
+}
+
+/// 从`position`秒处开始解码完整的音频源，返回解码后的音频源和跳过的帧数。
+/// 音频流还没写入的部分会被当作静音跳过，所以音频流不能跳转。
+pub(crate) fn decode_at(source: AudioSource, position: f64) -> Result<(BoxedSource, u64)> {
+    if source.is_stream() {
+        return Err(Error::StreamNotSeekable);
+    }
+
+    let mut source = decode(source)?;
+    let frames = (position.max(0.0) * source.sample_rate() as f64) as u64;
+    let samples = frames * source.channels() as u64;
+    for _ in 0..samples {
+        if source.next().is_none() {
+            break;
+        }
+    }
+
+    Ok((source, frames))
+}
+
+#[cfg(test)]
+mod tests {
+    use acfunlive_neotool_audio::AudioOrigin;
+    use audiopus::{coder::Encoder, Application};
+    use ogg::{PacketWriteEndInfo, PacketWriter};
+
+    use super::*;
+
+    // 生成一段440Hz正弦波的Ogg Opus数据，mapping是OpusHead里的声道映射
+    fn opus_sine(channels: u8, mapping: u8, frames: usize, pre_skip: u16) -> Vec<u8> {
+        const PACKET_FRAMES: usize = 960;
+        let opus_channels = if channels == 1 {
+            Channels::Mono
+        } else {
+            Channels::Stereo
+        };
+        let encoder = Encoder::new(SampleRate::Hz48000, opus_channels, Application::Audio).unwrap();
+        let mut writer = PacketWriter::new(Vec::new());
+
+        let mut head = b"OpusHead\x01".to_vec();
+        head.push(channels);
+        head.extend_from_slice(&pre_skip.to_le_bytes());
+        head.extend_from_slice(&OPUS_SAMPLE_RATE.to_le_bytes());
+        head.extend_from_slice(&[0, 0, mapping]);
+        writer
+            .write_packet(head.into(), 1, PacketWriteEndInfo::EndPage, 0)
+            .unwrap();
+        writer
+            .write_packet(
+                b"OpusTags\0\0\0\0\0\0\0\0".to_vec().into(),
+                1,
+                PacketWriteEndInfo::EndPage,
+                0,
+            )
+            .unwrap();
+
+        let total = frames + pre_skip as usize;
+        for start in (0..total).step_by(PACKET_FRAMES) {
+            let input: Vec<i16> = (start..start + PACKET_FRAMES)
+                .flat_map(|n| {
+                    let t = n as f32 / OPUS_SAMPLE_RATE as f32;
+                    let sample = ((t * 440.0 * std::f32::consts::TAU).sin() * 8000.0) as i16;
+                    vec![sample; channels.into()]
+                })
+                .collect();
+            let mut output = vec![0; 4000];
+            let len = encoder.encode(&input, &mut output).unwrap();
+            output.truncate(len);
+
+            let end = start + PACKET_FRAMES;
+            let (info, granule) = if end >= total {
+                (PacketWriteEndInfo::EndStream, total)
+            } else {
+                (PacketWriteEndInfo::NormalPacket, end)
+            };
+            writer
+                .write_packet(output.into(), 1, info, granule as u64)
+                .unwrap();
+        }
+
+        writer.into_inner()
+    }
+
+    #[test]
+    fn test_decode_opus() {
+        for channels in [1, 2] {
+            let data = opus_sine(channels, 0, 10000, 312);
+            let source = AudioSource::new(data, AudioFormat::Unknown, AudioOrigin::File);
+            let decoded = decode(source).unwrap();
+            assert_eq!(decoded.channels(), channels as u16);
+            assert_eq!(decoded.sample_rate(), OPUS_SAMPLE_RATE);
+
+            // 去掉开头的pre-skip和最后补齐的数据
+            let samples: Vec<i16> = decoded.collect();
+            assert_eq!(samples.len(), 10000 * channels as usize);
+            assert!(samples.iter().any(|&s| s.abs() > 1000));
+        }
+
+        // 不支持多声道的映射
+        let data = opus_sine(2, 1, 1000, 0);
+        let source = AudioSource::new(data, AudioFormat::Opus, AudioOrigin::File);
+        assert!(matches!(
+            decode(source),
+            Err(Error::UnsupportedFormat(AudioFormat::Opus))
+        ));
+    }
+
+    #[test]
+    fn test_decode_stream_without_blocking() {
+        let (mut source, writer) = AudioSource::stream(AudioFormat::Pcm, AudioOrigin::Tts);
+        source.sample_rate = Some(8000);
+        source.channels = Some(2);
+        let mut decoded = decode(source).unwrap();
+
+        // 还没有数据时输出整帧的静音
+        assert_eq!(decoded.next(), Some(0));
+        assert_eq!(decoded.next(), Some(0));
+
+        let samples: [i16; 4] = [1, -1, 2, -2];
+        let data: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        assert!(writer.append(&data));
+        drop(writer);
+
+        let rest: Vec<i16> = decoded.collect();
+        let start = rest.iter().position(|&s| s != 0).unwrap();
+        assert_eq!(start % 2, 0);
+        assert_eq!(&rest[start..], &samples);
+    }
+
+    #[test]
+    fn test_decode_at() {
+        let samples: Vec<i16> = (0..2000).collect();
+        let data = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        let source = AudioSource::pcm(data, 1000, 2, AudioOrigin::Generated);
+
+        let (mut decoded, frames) = decode_at(source.clone(), 0.5).unwrap();
+        assert_eq!(frames, 500);
+        assert_eq!(decoded.next(), Some(1000));
+        let (mut decoded, _) = decode_at(source.clone(), 10.0).unwrap();
+        assert_eq!(decoded.next(), None);
+        assert_eq!(decoded_duration(source), Some(Duration::from_secs(1)));
+
+        // 音频流还没写入的部分不能跳过
+        let (mut source, _writer) = AudioSource::stream(AudioFormat::Pcm, AudioOrigin::Tts);
+        source.sample_rate = Some(1000);
+        source.channels = Some(2);
+        assert!(matches!(
+            decode_at(source, 0.5),
+            Err(Error::StreamNotSeekable)
+        ));
+    }
+}
@@ -0,0 +1,516 @@
+use std::{
+    f32::consts::PI,
+    ops::RangeInclusive,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use rodio::Source;
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Result};
+
+// 速度和音调倍数的范围
+const RATIO_RANGE: RangeInclusive<f32> = 0.25..=4.0;
+
+// 淡入淡出的最长时间
+const MAX_FADE: u32 = 60_000;
+
+// 回声的最长延迟，回声的缓冲区在音频线程里分配，不能太大
+const MAX_ECHO_DELAY: u32 = 2_000;
+
+// 低通滤波截止频率的范围
+const LOW_PASS_RANGE: RangeInclusive<u32> = 20..=20_000;
+
+/// 音频的效果设置，时间的单位都是毫秒，值为0时不启用对应的效果
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct Effects {
+    /// 播放速度，不改变音调，范围为0.25到4
+    pub(crate) speed: f32,
+    /// 音调的倍数，不改变播放速度，范围为0.25到4
+    pub(crate) pitch: f32,
+    /// 每个音频源开头的淡入时间，最长60秒
+    pub(crate) fade_in: u32,
+    /// 每个音频源结尾的淡出时间，最长60秒。音频流写完之前不知道时长，不会淡出
+    pub(crate) fade_out: u32,
+    /// 停止播放时的淡出时间，最长60秒
+    pub(crate) stop_fade: u32,
+    /// 回声的延迟，最长2秒
+    pub(crate) echo_delay: u32,
+    /// 回声的反馈比例，越大回声持续越久，用来模拟简单的混响，范围为0到1（不包括1）
+    pub(crate) echo_feedback: f32,
+    /// 回声混入的比例，范围为0到1
+    pub(crate) echo_mix: f32,
+    /// 低通滤波的截止频率，单位为Hz，范围为20到20000
+    pub(crate) low_pass: u32,
+}
+
+impl Default for Effects {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            speed: 1.0,
+            pitch: 1.0,
+            fade_in: 0,
+            fade_out: 0,
+            stop_fade: 0,
+            echo_delay: 0,
+            echo_feedback: 0.0,
+            echo_mix: 0.0,
+            low_pass: 0,
+        }
+    }
+}
+
+impl Effects {
+    /// 检查设置是否在允许的范围内
+    pub(crate) fn check(&self) -> Result<()> {
+        let invalid = |reason| Err(Error::InvalidEffects(reason));
+        if !RATIO_RANGE.contains(&self.speed) {
+            return invalid("speed must be between 0.25 and 4");
+        }
+        if !RATIO_RANGE.contains(&self.pitch) {
+            return invalid("pitch must be between 0.25 and 4");
+        }
+        if self.fade_in > MAX_FADE || self.fade_out > MAX_FADE || self.stop_fade > MAX_FADE {
+            return invalid("fade time must not exceed 60000 ms");
+        }
+        if self.echo_delay > MAX_ECHO_DELAY {
+            return invalid("echo delay must not exceed 2000 ms");
+        }
+        if !(0.0..1.0).contains(&self.echo_feedback) {
+            return invalid("echo feedback must be at least 0 and less than 1");
+        }
+        if !(0.0..=1.0).contains(&self.echo_mix) {
+            return invalid("echo mix must be between 0 and 1");
+        }
+        if self.low_pass != 0 && !LOW_PASS_RANGE.contains(&self.low_pass) {
+            return invalid("low pass cutoff must be between 20 and 20000 Hz");
+        }
+
+        Ok(())
+    }
+
+    // 速度由Sink调整，它同时会改变音调，所以音调要反过来补偿
+    #[inline]
+    fn pitch_ratio(&self) -> f32 {
+        let speed = if self.speed > 0.0 { self.speed } else { 1.0 };
+        let pitch = if self.pitch > 0.0 { self.pitch } else { 1.0 };
+
+        (pitch / speed).clamp(0.25, 4.0)
+    }
+}
+
+/// 音频和它队列里的音频源共享的效果状态
+#[derive(Debug, Default)]
+pub(crate) struct EffectState {
+    effects: RwLock<Effects>,
+    // 每次修改设置都加一，音频线程发现变化后才重新读取设置
+    version: AtomicU64,
+    stopping: AtomicBool,
+    // 每次开始或者结束停止前的淡出都加一，淡出结束时用来判断这次停止是否还有效
+    stop_generation: AtomicU64,
+}
+
+impl EffectState {
+    #[inline]
+    pub(crate) fn effects(&self) -> Effects {
+        *self.effects.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    #[inline]
+    pub(crate) fn set_effects(&self, effects: Effects) {
+        *self.effects.write().unwrap_or_else(|e| e.into_inner()) = effects;
+        self.version.fetch_add(1, Ordering::Release);
+    }
+
+    #[inline]
+    pub(crate) fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::Acquire)
+    }
+
+    /// 开始停止前的淡出，返回这次停止的序号
+    #[inline]
+    pub(crate) fn start_stopping(&self) -> u64 {
+        let generation = self.stop_generation.fetch_add(1, Ordering::AcqRel) + 1;
+        self.stopping.store(true, Ordering::Release);
+
+        generation
+    }
+
+    /// 淡出结束，之后又开始或者取消了停止时返回`false`，这时不应该再停止播放
+    #[inline]
+    pub(crate) fn finish_stopping(&self, generation: u64) -> bool {
+        let finished = self
+            .stop_generation
+            .compare_exchange(
+                generation,
+                generation + 1,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_ok();
+        if finished {
+            self.stopping.store(false, Ordering::Release);
+        }
+
+        finished
+    }
+
+    /// 取消停止前的淡出，还在等待的停止不会再生效
+    #[inline]
+    pub(crate) fn cancel_stopping(&self) {
+        self.stop_generation.fetch_add(1, Ordering::AcqRel);
+        self.stopping.store(false, Ordering::Release);
+    }
+}
+
+/// 延迟线实现的变调，两个读取位置交替淡入淡出
+struct PitchShifter {
+    buffer: Vec<f32>,
+    write: usize,
+    delay: f32,
+}
+
+impl PitchShifter {
+    #[inline]
+    fn new(sample_rate: u32) -> Self {
+        // 40毫秒的窗口
+        let len = (sample_rate as usize / 25).max(64);
+
+        Self {
+            buffer: vec![0.0; len],
+            write: 0,
+            delay: 0.0,
+        }
+    }
+
+    #[inline]
+    fn read(&self, delay: f32) -> f32 {
+        let len = self.buffer.len();
+        let position = (self.write + len) as f32 - delay;
+        let index = position.floor();
+        let fraction = position - index;
+        let a = self.buffer[index as usize % len];
+        let b = self.buffer[(index as usize + 1) % len];
+
+        a + (b - a) * fraction
+    }
+
+    fn process(&mut self, sample: f32, ratio: f32) -> f32 {
+        let len = self.buffer.len() as f32;
+        self.buffer[self.write] = sample;
+
+        let first = self.delay;
+        let second = (self.delay + len / 2.0) % len;
+        let gain = 1.0 - (2.0 * first / len - 1.0).abs();
+        let output = self.read(first) * gain + self.read(second) * (1.0 - gain);
+
+        self.delay = (self.delay + 1.0 - ratio).rem_euclid(len);
+        self.write = (self.write + 1) % self.buffer.len();
+
+        output
+    }
+}
+
+/// 按效果设置处理音频源
+pub(crate) struct Effected<S> {
+    inner: S,
+    state: Arc<EffectState>,
+    version: u64,
+    effects: Effects,
+    channel: usize,
+    // 已经播放的帧数
+    frames: u64,
+    // 音频源的时长，解码器不知道时长时由外面算出来
+    duration: Option<Duration>,
+    stop_gain: f32,
+    shifters: Vec<PitchShifter>,
+    low_pass: Vec<f32>,
+    echo: Vec<f32>,
+    echo_index: usize,
+}
+
+impl<S: Source<Item = i16>> Effected<S> {
+    #[inline]
+    pub(crate) fn new(inner: S, state: Arc<EffectState>) -> Self {
+        let version = state.version.load(Ordering::Acquire);
+        let effects = state.effects();
+
+        Self {
+            inner,
+            state,
+            version,
+            effects,
+            channel: 0,
+            frames: 0,
+            duration: None,
+            stop_gain: 1.0,
+            shifters: Vec::new(),
+            low_pass: Vec::new(),
+            echo: Vec::new(),
+            echo_index: 0,
+        }
+    }
+
+    /// 跳转后接着播放，淡入淡出从第`frames`帧开始计算
+    #[inline]
+    pub(crate) fn at(mut self, frames: u64) -> Self {
+        self.frames = frames;
+
+        self
+    }
+
+    /// 音频源的时长，用来计算结尾的淡出，`None`时使用解码器给出的时长
+    #[inline]
+    pub(crate) fn with_duration(mut self, duration: Option<Duration>) -> Self {
+        self.duration = duration;
+
+        self
+    }
+
+    #[inline]
+    fn samples_per_second(&self) -> f32 {
+        self.inner.sample_rate() as f32 * self.inner.channels() as f32
+    }
+
+    fn envelope(&mut self) -> f32 {
+        let position = self.frames as f32 / self.inner.sample_rate().max(1) as f32 * 1000.0;
+        let mut gain = 1.0;
+        if self.effects.fade_in > 0 {
+            gain *= (position / self.effects.fade_in as f32).min(1.0);
+        }
+        if self.effects.fade_out > 0 {
+            if let Some(total) = self.duration.or_else(|| self.inner.total_duration()) {
+                let remaining = total.as_secs_f32() * 1000.0 - position;
+                gain *= (remaining / self.effects.fade_out as f32).clamp(0.0, 1.0);
+            }
+        }
+
+        // 停止被取消后慢慢恢复音量，不会突然变大
+        if self.state.stopping.load(Ordering::Acquire) || self.stop_gain < 1.0 {
+            let samples = self.effects.stop_fade as f32 / 1000.0 * self.samples_per_second();
+            let step = if samples < 1.0 { 1.0 } else { 1.0 / samples };
+            self.stop_gain = if self.state.stopping.load(Ordering::Acquire) {
+                (self.stop_gain - step).max(0.0)
+            } else {
+                (self.stop_gain + step).min(1.0)
+            };
+        }
+
+        gain * self.stop_gain
+    }
+
+    fn pitch_shift(&mut self, sample: f32) -> f32 {
+        let ratio = self.effects.pitch_ratio();
+        if (ratio - 1.0).abs() < f32::EPSILON {
+            return sample;
+        }
+
+        let channels = self.inner.channels() as usize;
+        if self.shifters.len() != channels {
+            let sample_rate = self.inner.sample_rate();
+            self.shifters = (0..channels)
+                .map(|_| PitchShifter::new(sample_rate))
+                .collect();
+        }
+
+        self.shifters[self.channel].process(sample, ratio)
+    }
+
+    fn low_pass(&mut self, sample: f32) -> f32 {
+        if self.effects.low_pass == 0 {
+            return sample;
+        }
+
+        let channels = self.inner.channels() as usize;
+        if self.low_pass.len() != channels {
+            self.low_pass = vec![0.0; channels];
+        }
+        let sample_rate = self.inner.sample_rate().max(1) as f32;
+        let alpha = 1.0 - (-2.0 * PI * self.effects.low_pass as f32 / sample_rate).exp();
+        let previous = &mut self.low_pass[self.channel];
+        *previous += alpha * (sample - *previous);
+
+        *previous
+    }
+
+    fn echo(&mut self, sample: f32) -> f32 {
+        if self.effects.echo_delay == 0 || self.effects.echo_mix <= 0.0 {
+            return sample;
+        }
+
+        let channels = self.inner.channels().max(1) as usize;
+        let frames =
+            (self.effects.echo_delay as f32 / 1000.0 * self.inner.sample_rate() as f32) as usize;
+        let len = frames.max(1) * channels;
+        if self.echo.len() != len {
+            self.echo = vec![0.0; len];
+            self.echo_index = 0;
+        }
+
+        let delayed = self.echo[self.echo_index];
+        self.echo[self.echo_index] = sample + delayed * self.effects.echo_feedback.clamp(0.0, 0.95);
+        self.echo_index = (self.echo_index + 1) % len;
+
+        sample + delayed * self.effects.echo_mix
+    }
+}
+
+impl<S: Source<Item = i16>> Iterator for Effected<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let sample = self.inner.next()? as f32 / 32768.0;
+
+        let version = self.state.version.load(Ordering::Acquire);
+        if version != self.version {
+            self.version = version;
+            self.effects = self.state.effects();
+        }
+
+        let gain = self.envelope();
+        let sample = self.pitch_shift(sample);
+        let sample = self.low_pass(sample);
+        let sample = self.echo(sample) * gain;
+
+        self.channel += 1;
+        if self.channel >= self.inner.channels() as usize {
+            self.channel = 0;
+            self.frames += 1;
+        }
+
+        Some(sample.clamp(-1.0, 1.0))
+    }
+}
+
+impl<S: Source<Item = i16>> Source for Effected<S> {
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rodio::buffer::SamplesBuffer;
+
+    use super::*;
+
+    #[test]
+    fn test_effects_check() {
+        assert!(Effects::default().check().is_ok());
+
+        let invalid = [
+            Effects {
+                speed: 0.0,
+                ..Default::default()
+            },
+            Effects {
+                pitch: f32::NAN,
+                ..Default::default()
+            },
+            Effects {
+                stop_fade: MAX_FADE + 1,
+                ..Default::default()
+            },
+            Effects {
+                echo_delay: u32::MAX,
+                ..Default::default()
+            },
+            Effects {
+                echo_feedback: 1.0,
+                ..Default::default()
+            },
+            Effects {
+                echo_mix: -0.5,
+                ..Default::default()
+            },
+            Effects {
+                low_pass: 5,
+                ..Default::default()
+            },
+        ];
+        for effects in invalid {
+            assert!(
+                matches!(effects.check(), Err(Error::InvalidEffects(_))),
+                "{effects:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_effects_stopping() {
+        let state = EffectState::default();
+        let first = state.start_stopping();
+        let second = state.start_stopping();
+        assert!(!state.finish_stopping(first));
+        assert!(state.is_stopping());
+        assert!(state.finish_stopping(second));
+        assert!(!state.is_stopping());
+
+        // 取消后等待中的停止不再生效
+        let generation = state.start_stopping();
+        state.cancel_stopping();
+        assert!(!state.finish_stopping(generation));
+    }
+
+    #[test]
+    fn test_effects_fade_out() {
+        let state = Arc::new(EffectState::default());
+        state.set_effects(Effects {
+            fade_out: 10,
+            ..Default::default()
+        });
+        // 解码器给出的时长是100毫秒，外面给出的时长优先
+        let source = SamplesBuffer::new(1, 1000, vec![i16::MAX; 100]);
+        let effected = Effected::new(source, state).with_duration(Some(Duration::from_millis(50)));
+        let samples: Vec<f32> = effected.collect();
+        assert!(samples[39] > 0.99);
+        assert!(samples[45] < 0.6);
+        assert_eq!(samples[50], 0.0);
+    }
+
+    #[test]
+    fn test_effects_stop_fade() {
+        let state = Arc::new(EffectState::default());
+        state.set_effects(Effects {
+            stop_fade: 10,
+            ..Default::default()
+        });
+        let source = SamplesBuffer::new(1, 1000, vec![i16::MAX; 100]);
+        let mut effected = Effected::new(source, Arc::clone(&state));
+        assert!(effected.next().unwrap() > 0.99);
+
+        // 10毫秒是10个采样
+        state.start_stopping();
+        let faded: Vec<f32> = effected.by_ref().take(10).collect();
+        assert!(faded[0] < 1.0);
+        assert_eq!(faded[9], 0.0);
+
+        // 取消停止后音量慢慢恢复
+        state.cancel_stopping();
+        let restored: Vec<f32> = effected.by_ref().take(10).collect();
+        assert!(restored[0] < 0.2);
+        assert!(restored[9] > 0.99);
+    }
+}
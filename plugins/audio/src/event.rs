@@ -0,0 +1,255 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use acfunlive_neotool_audio::AudioSourceId;
+use rodio::Source;
+use serde::Serialize;
+use tauri::{async_runtime::spawn, AppHandle, Manager, Runtime};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+use crate::{queue::DropReason, AudioId};
+
+/// 前端监听的事件名
+pub(crate) const AUDIO_EVENT: &str = "acfunlive-neotool-audio:event";
+
+/// 默认每隔多少毫秒发送一次播放进度
+pub(crate) const DEFAULT_POSITION_INTERVAL: u64 = 250;
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub(crate) enum AudioEventKind {
+    #[serde(rename_all = "camelCase")]
+    SourceStarted { audio_source_id: AudioSourceId },
+    #[serde(rename_all = "camelCase")]
+    SourceFinished { audio_source_id: AudioSourceId },
+    QueueEmpty,
+    /// 音频源没有加入播放队列
+    #[serde(rename_all = "camelCase")]
+    SourceDropped {
+        audio_source_id: AudioSourceId,
+        reason: DropReason,
+    },
+    Error { message: String },
+    /// 正在播放的声音的音量和频谱，`rms`和`peak`的范围是0到1
+    Level {
+        rms: f32,
+        peak: f32,
+        spectrum: Vec<f32>,
+    },
+    /// 当前音频源的播放进度，单位为秒
+    #[serde(rename_all = "camelCase")]
+    Position {
+        audio_source_id: AudioSourceId,
+        position: f64,
+    },
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct AudioEvent {
+    audio_id: AudioId,
+    #[serde(flatten)]
+    kind: AudioEventKind,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct EventSender(UnboundedSender<AudioEvent>);
+
+impl EventSender {
+    /// 新建事件发送端，事件在单独的任务里发给前端，所以可以在音频线程里发送
+    pub(crate) fn new<R: Runtime>(app: AppHandle<R>) -> Self {
+        let (sender, mut receiver) = unbounded_channel::<AudioEvent>();
+        spawn(async move {
+            while let Some(event) = receiver.recv().await {
+                let _ = app.emit_all(AUDIO_EVENT, event);
+            }
+        });
+
+        Self(sender)
+    }
+
+    #[inline]
+    pub(crate) fn send(&self, audio_id: AudioId, kind: AudioEventKind) {
+        let _ = self.0.send(AudioEvent { audio_id, kind });
+    }
+}
+
+/// 音频的事件状态，由音频和它队列里的音频源共享
+#[derive(Debug)]
+pub(crate) struct EventState {
+    audio_id: AudioId,
+    sender: EventSender,
+    queued: AtomicUsize,
+    // 单位为毫秒，0为不发送播放进度
+    position_interval: AtomicU64,
+    // 正在播放的音频源的播放进度，保存的是f64的比特
+    position: AtomicU64,
+}
+
+impl EventState {
+    #[inline]
+    pub(crate) fn new(audio_id: AudioId, sender: EventSender) -> Arc<Self> {
+        Arc::new(Self {
+            audio_id,
+            sender,
+            queued: AtomicUsize::new(0),
+            position_interval: AtomicU64::new(DEFAULT_POSITION_INTERVAL),
+            position: AtomicU64::new(0),
+        })
+    }
+
+    #[inline]
+    pub(crate) fn send(&self, kind: AudioEventKind) {
+        self.sender.send(self.audio_id, kind);
+    }
+
+    #[inline]
+    pub(crate) fn set_position_interval(&self, interval: u64) {
+        self.position_interval.store(interval, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn position_interval(&self) -> f64 {
+        self.position_interval.load(Ordering::Relaxed) as f64 / 1000.0
+    }
+
+    /// 正在播放的音频源的播放进度，单位为秒
+    #[inline]
+    pub(crate) fn position(&self) -> f64 {
+        f64::from_bits(self.position.load(Ordering::Relaxed))
+    }
+
+    #[inline]
+    fn set_position(&self, position: f64) {
+        self.position.store(position.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// 包装音频源，播放时发送开始、结束和进度事件
+pub(crate) struct Tracked<S> {
+    inner: S,
+    audio_source_id: AudioSourceId,
+    state: Arc<EventState>,
+    started: bool,
+    finished: bool,
+    position: f64,
+    reported: f64,
+}
+
+impl<S> Tracked<S> {
+    #[inline]
+    pub(crate) fn new(inner: S, audio_source_id: AudioSourceId, state: Arc<EventState>) -> Self {
+        state.queued.fetch_add(1, Ordering::AcqRel);
+
+        Self {
+            inner,
+            audio_source_id,
+            state,
+            started: false,
+            finished: false,
+            position: 0.0,
+            reported: 0.0,
+        }
+    }
+
+    /// 跳转后接着播放，从`position`秒开始计算进度，不再发送开始事件
+    #[inline]
+    pub(crate) fn at(mut self, position: f64) -> Self {
+        self.started = true;
+        self.position = position;
+        self.reported = position;
+
+        self
+    }
+
+    // 被播放完或者被清出队列时调用
+    fn leave_queue(&mut self) {
+        self.finished = true;
+        if self.state.queued.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.state.send(AudioEventKind::QueueEmpty);
+        }
+    }
+}
+
+impl<S: Source> Iterator for Tracked<S>
+where
+    S::Item: rodio::Sample,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let sample = self.inner.next();
+        if sample.is_none() {
+            if !self.finished {
+                self.state.send(AudioEventKind::SourceFinished {
+                    audio_source_id: self.audio_source_id,
+                });
+                self.leave_queue();
+            }
+
+            return None;
+        }
+
+        if !self.started {
+            self.started = true;
+            self.state.send(AudioEventKind::SourceStarted {
+                audio_source_id: self.audio_source_id,
+            });
+        }
+
+        let samples_per_second = self.inner.channels() as u32 * self.inner.sample_rate();
+        if samples_per_second > 0 {
+            self.position += 1.0 / samples_per_second as f64;
+        }
+        self.state.set_position(self.position);
+        let interval = self.state.position_interval();
+        if interval > 0.0 && self.position - self.reported >= interval {
+            self.reported = self.position;
+            self.state.send(AudioEventKind::Position {
+                audio_source_id: self.audio_source_id,
+                position: self.position,
+            });
+        }
+
+        sample
+    }
+}
+
+impl<S: Source> Source for Tracked<S>
+where
+    S::Item: rodio::Sample,
+{
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+impl<S> Drop for Tracked<S> {
+    #[inline]
+    fn drop(&mut self) {
+        if !self.finished {
+            self.leave_queue();
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use std::path::{Path, PathBuf};
+
+use acfunlive_neotool_audio::{AudioFormat, AudioOrigin, AudioSource};
+use acfunlive_neotool_permission::PermissionManager;
+use tauri::{AppHandle, Manager, Runtime};
+use tokio::fs::{canonicalize, read};
+
+use crate::{Error, Result};
+
+/// 允许`app_id`读取音频文件的目录：应用数据目录和应用自己所在的目录
+fn allowed_roots<R: Runtime>(app: &AppHandle<R>, app_id: &str) -> Vec<PathBuf> {
+    [
+        app.path_resolver().app_data_dir(),
+        PermissionManager::global().app_dir(app_id),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+/// 检查路径是否在允许的目录或者文件系统scope里，返回规范化后的路径
+async fn check_path<R: Runtime>(app: &AppHandle<R>, app_id: &str, path: &str) -> Result<PathBuf> {
+    let path = canonicalize(path).await?;
+    for root in allowed_roots(app, app_id) {
+        if let Ok(root) = canonicalize(&root).await {
+            if path.starts_with(&root) {
+                return Ok(path);
+            }
+        }
+    }
+
+    if app.fs_scope().is_allowed(&path) {
+        Ok(path)
+    } else {
+        Err(Error::PathNotAllowed(path.to_string_lossy().into_owned()))
+    }
+}
+
+#[inline]
+fn format_of(path: &Path) -> AudioFormat {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .map(AudioFormat::from_extension)
+        .unwrap_or(AudioFormat::Unknown)
+}
+
+/// 读取`app_id`能读取的音频文件
+pub(crate) async fn read_audio_file<R: Runtime>(
+    app: &AppHandle<R>,
+    app_id: &str,
+    path: &str,
+) -> Result<AudioSource> {
+    let path = check_path(app, app_id, path).await?;
+    let format = format_of(&path);
+    if format == AudioFormat::Pcm {
+        return Err(Error::MissingPcmParameters);
+    }
+
+    Ok(AudioSource::new(
+        read(&path).await?,
+        format,
+        AudioOrigin::File,
+    ))
+}
@@ -1,37 +1,104 @@
-use std::{collections::HashMap, io::Cursor, mem::ManuallyDrop};
+mod analysis;
+mod decode;
+mod effects;
+mod event;
+mod file;
+mod loudness;
+mod mixer;
+mod output;
+mod queue;
 
-use acfunlive_neotool_audio::{AudioSourceId, AudioSourceManager};
-use once_cell::sync::{Lazy, OnceCell};
-use rodio::{Decoder, OutputStream, OutputStreamHandle, Sink};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use acfunlive_neotool_audio::{
+    AudioFormat, AudioOrigin, AudioSource, AudioSourceId, AudioSourceInfo, AudioSourceManager,
+};
+use acfunlive_neotool_permission::{Permission, PermissionManager};
+use once_cell::sync::Lazy;
+use rodio::{Sink, Source};
 use serde::{Serialize, Serializer};
 use tauri::{
     command,
     plugin::{Builder, TauriPlugin},
-    Manager, Runtime, State,
+    AppHandle, Manager, Runtime, State,
+};
+use tokio::{
+    sync::{Mutex, MutexGuard},
+    time::sleep,
+};
+
+use analysis::{Analysis, AnalysisState, Analyzed};
+use decode::{decode, decode_at, decoded_duration, BoxedSource, SUPPORTED_FORMATS};
+use effects::{EffectState, Effected, Effects};
+use event::{AudioEventKind, EventSender, EventState, Tracked};
+use file::read_audio_file;
+use loudness::{amplified, Loudness, Normalization};
+use mixer::{Bus, BusInfo, Ducking, Mixer, Track, VOICE_BUS};
+use output::{list_devices, Output, OutputDevice, OutputManager};
+use queue::{
+    fingerprint, PlayQueue, QueueEntry, QueueItemInfo, QueueOptions, QueueSource, QueuedSource,
+    SourceRef,
 };
-use tokio::sync::Mutex;
 
 type AudioId = u32;
 
 static ID: Lazy<Mutex<AudioId>> = Lazy::new(|| Mutex::new(0));
 
-static HANDLE: OnceCell<OutputStreamHandle> = OnceCell::new();
-
 type Result<T> = std::result::Result<T, Error>;
 
 #[allow(clippy::enum_variant_names)]
 #[derive(Debug, thiserror::Error)]
 enum Error {
     #[error(transparent)]
-    RodioPlayError(#[from] rodio::PlayError),
+    TauriError(#[from] tauri::Error),
     #[error(transparent)]
     RodioDecoderError(#[from] rodio::decoder::DecoderError),
-    #[error("no stream handle")]
-    NoStreamHanlde,
+    #[error(transparent)]
+    RodioStreamError(#[from] rodio::StreamError),
+    #[error(transparent)]
+    DevicesError(#[from] rodio::DevicesError),
+    #[error(transparent)]
+    DeviceNameError(#[from] rodio::cpal::DeviceNameError),
+    #[error("no default output device")]
+    NoDefaultOutputDevice,
+    #[error("output device not found: {0}")]
+    OutputDeviceNotFound(String),
+    #[error("output thread exited unexpectedly")]
+    OutputThreadExited,
+    #[error("invalid output file: {0}")]
+    InvalidOutputFile(String),
     #[error("no audio")]
     NoAudio,
     #[error("no audio source")]
     NoAudioSource,
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error("PCM audio source needs sample rate and channels")]
+    MissingPcmParameters,
+    #[error("path is not allowed: {0}")]
+    PathNotAllowed(String),
+    #[error("unsupported audio format: {}", .0.name())]
+    UnsupportedFormat(AudioFormat),
+    #[error("invalid Opus header")]
+    InvalidOpusHeader,
+    #[error(transparent)]
+    OggError(#[from] ogg::OggReadError),
+    #[error(transparent)]
+    OpusError(#[from] audiopus::Error),
+    #[error("no audio source is playing")]
+    NotPlaying,
+    #[error("can't seek in an audio stream")]
+    StreamNotSeekable,
+    #[error("bus not found: {0}")]
+    BusNotFound(String),
+    #[error("volume must be between 0 and 2: {0}")]
+    InvalidVolume(f32),
+    #[error("ducking gain must be between 0 and 1: {0}")]
+    InvalidDucking(f32),
+    #[error("invalid effects: {0}")]
+    InvalidEffects(&'static str),
+    #[error(transparent)]
+    PermissionError(#[from] acfunlive_neotool_permission::Error),
 }
 
 impl Serialize for Error {
@@ -52,139 +119,977 @@ async fn new_id() -> AudioId {
     *id
 }
 
+struct Audio {
+    // 创建音频的应用
+    app_id: String,
+    sink: Sink,
+    // `None`为默认设备
+    device: Option<String>,
+    output: Arc<Output>,
+    events: Arc<EventState>,
+    bus: Arc<Bus>,
+    track: Arc<Track>,
+    effects: Arc<EffectState>,
+    queue: Arc<PlayQueue>,
+    analysis: Arc<AnalysisState>,
+}
+
+impl Audio {
+    async fn new(
+        app_id: String,
+        outputs: &OutputManager,
+        device: Option<String>,
+        events: Arc<EventState>,
+        bus: Arc<Bus>,
+    ) -> Result<Self> {
+        let output = outputs.get(device.as_deref()).await?;
+        let sink = output.new_sink();
+
+        Ok(Self {
+            app_id,
+            sink,
+            device,
+            output,
+            events,
+            bus,
+            track: Arc::default(),
+            effects: Arc::default(),
+            queue: Arc::default(),
+            analysis: Arc::default(),
+        })
+    }
+
+    /// 换到新的输出，保留音量、速度和暂停状态，正在播放的音频源会被丢弃
+    fn set_output(&mut self, output: Arc<Output>) {
+        let sink = output.new_sink();
+        sink.set_volume(self.sink.volume());
+        sink.set_speed(self.sink.speed());
+        if self.sink.is_paused() {
+            sink.pause();
+        }
+        if let Some(source) = self.queue.restart() {
+            sink.append(self.analyzed(source));
+        }
+        self.sink = sink;
+        self.output = output;
+    }
+
+    #[inline]
+    fn analyzed(&self, source: QueueSource) -> Analyzed<QueueSource> {
+        Analyzed::new(source, Arc::clone(&self.analysis))
+    }
+
+    /// 给解码后的音频源加上混音、效果和事件，`frames`为跳转后开始播放的帧
+    fn wrap(
+        &self,
+        mixer: &Mixer,
+        source: BoxedSource,
+        audio_source_id: AudioSourceId,
+        duration: Option<Duration>,
+        frames: u64,
+    ) -> QueuedSource {
+        let position = frames as f64 / source.sample_rate().max(1) as f64;
+
+        Box::new(
+            Tracked::new(
+                Effected::new(
+                    mixer.mix(source, Arc::clone(&self.bus), &self.track),
+                    Arc::clone(&self.effects),
+                )
+                .with_duration(duration)
+                .at(frames),
+                audio_source_id,
+                Arc::clone(&self.events),
+            )
+            .at(position),
+        )
+    }
+
+    /// 输出设备播放出错（比如设备被拔出）后换到新的输出，没出错时不会列出设备
+    async fn recover(&mut self, outputs: &OutputManager) -> Result<()> {
+        if self.output.has_failed() {
+            let output = outputs.get(self.device.as_deref()).await?;
+            self.set_output(output);
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Default)]
-struct AudioManager(Mutex<HashMap<AudioId, Sink>>);
+struct AudioManager(Mutex<HashMap<AudioId, Audio>>);
+
+impl AudioManager {
+    /// 锁住音频表，只能取到`app_id`创建的音频
+    #[inline]
+    async fn lock<'a>(&'a self, app_id: &'a str) -> OwnedAudios<'a> {
+        OwnedAudios {
+            app_id,
+            map: self.0.lock().await,
+        }
+    }
+}
+
+struct OwnedAudios<'a> {
+    app_id: &'a str,
+    map: MutexGuard<'a, HashMap<AudioId, Audio>>,
+}
+
+impl OwnedAudios<'_> {
+    /// 其它应用创建的音频和不存在的音频一样返回`NoAudio`
+    #[inline]
+    fn get(&self, audio_id: AudioId) -> Result<&Audio> {
+        self.map
+            .get(&audio_id)
+            .filter(|audio| audio.app_id == self.app_id)
+            .ok_or(Error::NoAudio)
+    }
+
+    #[inline]
+    fn get_mut(&mut self, audio_id: AudioId) -> Result<&mut Audio> {
+        match self.map.get_mut(&audio_id) {
+            Some(audio) if audio.app_id == self.app_id => Ok(audio),
+            _ => Err(Error::NoAudio),
+        }
+    }
+
+    #[inline]
+    fn remove(&mut self, audio_id: AudioId) -> Option<Audio> {
+        self.get(audio_id).ok()?;
+        self.map.remove(&audio_id)
+    }
+}
+
+/// 检查应用有没有音频权限，返回应用ID
+#[inline]
+async fn authorize(token: &str) -> Result<String> {
+    Ok(PermissionManager::global()
+        .authorize(token, &Permission::Audio)
+        .await?)
+}
+
+#[command]
+#[inline]
+async fn list_output_devices() -> Result<Vec<OutputDevice>> {
+    tauri::async_runtime::spawn_blocking(list_devices).await?
+}
+
+/// 能播放的音频格式
+#[command]
+#[inline]
+fn supported_formats() -> Vec<AudioFormat> {
+    SUPPORTED_FORMATS.to_vec()
+}
 
 #[command]
 #[inline]
-async fn new_audio(manager: State<'_, AudioManager>) -> Result<AudioId> {
+async fn new_audio(
+    audio_manager: State<'_, AudioManager>,
+    output_manager: State<'_, OutputManager>,
+    event_sender: State<'_, EventSender>,
+    mixer: State<'_, Mixer>,
+    token: String,
+    device: Option<String>,
+    bus: Option<String>,
+) -> Result<AudioId> {
+    let app_id = authorize(&token).await?;
     let id = new_id().await;
-    let sink = Sink::try_new(HANDLE.get().ok_or(Error::NoStreamHanlde)?)?;
+    let events = EventState::new(id, event_sender.inner().clone());
+    let bus = mixer.bus(bus.as_deref().unwrap_or(VOICE_BUS))?;
+    let audio = Audio::new(app_id, &output_manager, device, events, bus).await?;
 
-    manager.0.lock().await.insert(id, sink);
+    audio_manager.0.lock().await.insert(id, audio);
 
     Ok(id)
 }
 
+#[command]
+async fn set_audio_device(
+    audio_manager: State<'_, AudioManager>,
+    output_manager: State<'_, OutputManager>,
+    token: String,
+    audio_id: AudioId,
+    device: Option<String>,
+) -> Result<()> {
+    let app_id = authorize(&token).await?;
+    let mut audios = audio_manager.lock(&app_id).await;
+    let audio = audios.get_mut(audio_id)?;
+    let output = output_manager.get(device.as_deref()).await?;
+    audio.set_output(output);
+    audio.device = device;
+
+    Ok(())
+}
+
+/// 返回音频正在使用的输出设备名
 #[command]
 #[inline]
-async fn delete_audio(manager: State<'_, AudioManager>, audio_id: AudioId) -> Result<()> {
-    manager.0.lock().await.remove(&audio_id);
+async fn get_audio_device(
+    manager: State<'_, AudioManager>,
+    token: String,
+    audio_id: AudioId,
+) -> Result<String> {
+    let app_id = authorize(&token).await?;
+    let audios = manager.lock(&app_id).await;
+
+    Ok(audios.get(audio_id)?.output.name().to_string())
+}
+
+#[command]
+#[inline]
+async fn delete_audio(
+    manager: State<'_, AudioManager>,
+    token: String,
+    audio_id: AudioId,
+) -> Result<()> {
+    let app_id = authorize(&token).await?;
+    manager.lock(&app_id).await.remove(audio_id);
 
     Ok(())
 }
 
 #[command]
 #[inline]
-async fn is_audio_queue_empty(manager: State<'_, AudioManager>, audio_id: AudioId) -> Result<bool> {
-    let map = manager.0.lock().await;
+async fn is_audio_queue_empty(
+    manager: State<'_, AudioManager>,
+    token: String,
+    audio_id: AudioId,
+) -> Result<bool> {
+    let app_id = authorize(&token).await?;
+    let audios = manager.lock(&app_id).await;
+    let audio = audios.get(audio_id)?;
+
+    Ok(audio.sink.empty() && audio.queue.is_empty())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn add_audio_source(
+    audio_manager: &AudioManager,
+    output_manager: &OutputManager,
+    audio_source_manager: &AudioSourceManager,
+    mixer: &Mixer,
+    loudness: &Loudness,
+    app_id: &str,
+    audio_id: AudioId,
+    audio_source_id: AudioSourceId,
+    options: QueueOptions,
+) -> Result<()> {
+    let source = {
+        audio_source_manager
+            .peek(audio_source_id)
+            .await
+            .ok_or(Error::NoAudioSource)?
+    };
+    // 音频源留在注册表里用来跳转，没加入队列或者离开队列时删除
+    let data = SourceRef::new(audio_source_manager.clone(), audio_source_id);
+
+    let (queue, events) = {
+        let audios = audio_manager.lock(app_id).await;
+        let audio = audios.get(audio_id)?;
+        (Arc::clone(&audio.queue), Arc::clone(&audio.events))
+    };
+    // 不去重时不用算指纹
+    let hash = options.dedup_window.and_then(|_| fingerprint(&source));
+    // 先检查一次，不会加入队列的音频源不用解码
+    if let Err(reason) = queue.admit(hash, &options) {
+        events.send(AudioEventKind::SourceDropped {
+            audio_source_id,
+            reason,
+        });
+
+        return Ok(());
+    }
+
+    let gain = loudness.gain(&source, hash, options.normalize).await?;
+    let source_duration = source.duration;
+    // 复制完整的音频源不会复制数据
+    let complete = (!source.is_stream()).then(|| source.clone());
+    // 音频流要等到有足够的数据才能开始解码
+    let source = if source.is_stream() {
+        tauri::async_runtime::spawn_blocking(move || decode(source)).await??
+    } else {
+        decode(source)?
+    };
+    // 解码器不知道时长（比如MP3）时数出完整的音频源的时长，结尾的淡出要用到
+    let duration = match (source.total_duration().or(source_duration), complete) {
+        (None, Some(complete)) => {
+            tauri::async_runtime::spawn_blocking(move || decoded_duration(complete)).await?
+        }
+        (duration, _) => duration,
+    };
+    let source = amplified(source, gain);
+
+    let mut audios = audio_manager.lock(app_id).await;
+    let audio = audios.get_mut(audio_id)?;
+    audio.recover(output_manager).await?;
+    // 之前的停止还在淡出时马上停止，新的音频源不会被淡出或者停止
+    if audio.effects.is_stopping() {
+        audio.queue.clear();
+        audio.sink.stop();
+    }
+    audio.effects.cancel_stopping();
+    // 解码时队列可能变了，加入队列都要拿着`AudioManager`的锁，这次检查和加入队列之间队列不会变长
+    if let Err(reason) = audio.queue.admit(hash, &options) {
+        audio.events.send(AudioEventKind::SourceDropped {
+            audio_source_id,
+            reason,
+        });
+
+        return Ok(());
+    }
+    let entry = QueueEntry {
+        audio_source_id,
+        source: audio.wrap(mixer, source, audio_source_id, duration, 0),
+        duration,
+        data,
+        gain,
+    };
+    if let Some(source) = audio.queue.push(entry, hash, &options) {
+        audio.sink.append(audio.analyzed(source));
+    }
 
-    Ok(map.get(&audio_id).ok_or(Error::NoAudio)?.empty())
+    Ok(())
 }
 
+/// 出错时同时发送错误事件
+#[inline]
+fn report_error<T>(event_sender: &EventSender, audio_id: AudioId, result: Result<T>) -> Result<T> {
+    if let Err(e) = &result {
+        event_sender.send(
+            audio_id,
+            AudioEventKind::Error {
+                message: e.to_string(),
+            },
+        );
+    }
+
+    result
+}
+
+#[allow(clippy::too_many_arguments)]
 #[command]
 async fn add_audio(
     audio_manager: State<'_, AudioManager>,
+    output_manager: State<'_, OutputManager>,
     audio_source_manager: State<'_, AudioSourceManager>,
+    event_sender: State<'_, EventSender>,
+    mixer: State<'_, Mixer>,
+    loudness: State<'_, Loudness>,
+    token: String,
     audio_id: AudioId,
     audio_source_id: AudioSourceId,
+    options: Option<QueueOptions>,
 ) -> Result<()> {
-    let source = {
-        audio_source_manager
-            .get_and_remove(audio_source_id)
+    let app_id = authorize(&token).await?;
+    let result = add_audio_source(
+        &audio_manager,
+        &output_manager,
+        &audio_source_manager,
+        &mixer,
+        &loudness,
+        &app_id,
+        audio_id,
+        audio_source_id,
+        options.unwrap_or_default(),
+    )
+    .await;
+
+    report_error(&event_sender, audio_id, result)
+}
+
+/// 注册新的音频源并加入播放队列，返回音频源ID
+async fn add_new_source<R: Runtime>(
+    app: &AppHandle<R>,
+    app_id: &str,
+    audio_id: AudioId,
+    source: Result<AudioSource>,
+    options: Option<QueueOptions>,
+) -> Result<AudioSourceId> {
+    let audio_source_manager = app.state::<AudioSourceManager>();
+    let result = match source {
+        Ok(source) => {
+            let audio_source_id = audio_source_manager.add(source).await;
+            add_audio_source(
+                &app.state::<AudioManager>(),
+                &app.state::<OutputManager>(),
+                &audio_source_manager,
+                &app.state::<Mixer>(),
+                &app.state::<Loudness>(),
+                app_id,
+                audio_id,
+                audio_source_id,
+                options.unwrap_or_default(),
+            )
             .await
-            .ok_or(Error::NoAudioSource)?
+            .map(|_| audio_source_id)
+        }
+        Err(e) => Err(e),
     };
 
-    let map = audio_manager.0.lock().await;
-    map.get(&audio_id)
-        .ok_or(Error::NoAudio)?
-        .append(Decoder::new(Cursor::new(source))?);
+    report_error(&app.state::<EventSender>(), audio_id, result)
+}
+
+/// 播放应用自己的目录或者应用数据目录里的音频文件
+#[command]
+#[inline]
+async fn add_audio_file<R: Runtime>(
+    app: AppHandle<R>,
+    token: String,
+    audio_id: AudioId,
+    path: String,
+    options: Option<QueueOptions>,
+) -> Result<AudioSourceId> {
+    let app_id = authorize(&token).await?;
+    let source = read_audio_file(&app, &app_id, &path).await;
+
+    add_new_source(&app, &app_id, audio_id, source, options).await
+}
+
+/// 播放前端传来的音频数据，不指定格式时由解码器探测，PCM数据需要指定采样率和声道数
+#[allow(clippy::too_many_arguments)]
+#[command]
+#[inline]
+async fn add_audio_bytes<R: Runtime>(
+    app: AppHandle<R>,
+    token: String,
+    audio_id: AudioId,
+    bytes: Vec<u8>,
+    format: Option<AudioFormat>,
+    sample_rate: Option<u32>,
+    channels: Option<u16>,
+    options: Option<QueueOptions>,
+) -> Result<AudioSourceId> {
+    let app_id = authorize(&token).await?;
+    let source = match (format, sample_rate, channels) {
+        (Some(AudioFormat::Pcm), Some(sample_rate), Some(channels)) => {
+            AudioSource::pcm(bytes, sample_rate, channels, AudioOrigin::Generated)
+        }
+        _ => AudioSource::new(
+            bytes,
+            format.unwrap_or(AudioFormat::Unknown),
+            AudioOrigin::Generated,
+        ),
+    };
+
+    add_new_source(&app, &app_id, audio_id, Ok(source), options).await
+}
+
+/// 设置发送播放进度事件的间隔，单位为毫秒，0为不发送
+#[command]
+#[inline]
+async fn set_position_interval(
+    manager: State<'_, AudioManager>,
+    token: String,
+    audio_id: AudioId,
+    interval: u64,
+) -> Result<()> {
+    let app_id = authorize(&token).await?;
+    let audios = manager.lock(&app_id).await;
+    audios.get(audio_id)?.events.set_position_interval(interval);
 
     Ok(())
 }
 
 #[command]
 #[inline]
-async fn get_volume(manager: State<'_, AudioManager>, audio_id: AudioId) -> Result<f32> {
-    let map = manager.0.lock().await;
+async fn get_audio_source_info(
+    manager: State<'_, AudioSourceManager>,
+    audio_source_id: AudioSourceId,
+) -> Result<AudioSourceInfo> {
+    manager
+        .info(audio_source_id)
+        .await
+        .ok_or(Error::NoAudioSource)
+}
+
+#[command]
+#[inline]
+async fn get_volume(
+    manager: State<'_, AudioManager>,
+    token: String,
+    audio_id: AudioId,
+) -> Result<f32> {
+    let app_id = authorize(&token).await?;
+    let audios = manager.lock(&app_id).await;
 
-    Ok(map.get(&audio_id).ok_or(Error::NoAudio)?.volume())
+    Ok(audios.get(audio_id)?.sink.volume())
 }
 
 #[command]
 #[inline]
 async fn set_volume(
     manager: State<'_, AudioManager>,
+    token: String,
     audio_id: AudioId,
     volume: f32,
 ) -> Result<()> {
-    let map = manager.0.lock().await;
-    map.get(&audio_id).ok_or(Error::NoAudio)?.set_volume(volume);
+    let app_id = authorize(&token).await?;
+    let audios = manager.lock(&app_id).await;
+    audios.get(audio_id)?.sink.set_volume(volume);
+
+    Ok(())
+}
+
+#[command]
+#[inline]
+async fn play_audio(
+    manager: State<'_, AudioManager>,
+    token: String,
+    audio_id: AudioId,
+) -> Result<()> {
+    let app_id = authorize(&token).await?;
+    let audios = manager.lock(&app_id).await;
+    let audio = audios.get(audio_id)?;
+    audio.track.set_paused(false);
+    audio.sink.play();
+
+    Ok(())
+}
+
+#[command]
+#[inline]
+async fn pause_audio(
+    manager: State<'_, AudioManager>,
+    token: String,
+    audio_id: AudioId,
+) -> Result<()> {
+    let app_id = authorize(&token).await?;
+    let audios = manager.lock(&app_id).await;
+    let audio = audios.get(audio_id)?;
+    audio.track.set_paused(true);
+    audio.sink.pause();
+
+    Ok(())
+}
+
+/// 设置了停止淡出时先淡出再停止
+#[command]
+async fn stop_audio(
+    manager: State<'_, AudioManager>,
+    token: String,
+    audio_id: AudioId,
+) -> Result<()> {
+    let app_id = authorize(&token).await?;
+    let (fade, generation) = {
+        let audios = manager.lock(&app_id).await;
+        let audio = audios.get(audio_id)?;
+        let fade = audio.effects.effects().stop_fade;
+        if fade == 0 || audio.sink.empty() || audio.sink.is_paused() {
+            audio.effects.cancel_stopping();
+            audio.queue.clear();
+            audio.sink.stop();
+            return Ok(());
+        }
+
+        (fade, audio.effects.start_stopping())
+    };
+
+    sleep(Duration::from_millis(fade as u64)).await;
+
+    let audios = manager.lock(&app_id).await;
+    let audio = audios.get(audio_id)?;
+    // 淡出期间添加了新的音频源或者再次停止时，这次停止已经被处理
+    if audio.effects.finish_stopping(generation) {
+        audio.queue.clear();
+        audio.sink.stop();
+    }
 
     Ok(())
 }
 
 #[command]
 #[inline]
-async fn play_audio(manager: State<'_, AudioManager>, audio_id: AudioId) -> Result<()> {
-    let map = manager.0.lock().await;
-    map.get(&audio_id).ok_or(Error::NoAudio)?.play();
+async fn get_effects(
+    manager: State<'_, AudioManager>,
+    token: String,
+    audio_id: AudioId,
+) -> Result<Effects> {
+    let app_id = authorize(&token).await?;
+    let audios = manager.lock(&app_id).await;
+
+    Ok(audios.get(audio_id)?.effects.effects())
+}
+
+/// 修改音频的效果，正在播放的音频源也会马上生效
+#[command]
+#[inline]
+async fn set_effects(
+    manager: State<'_, AudioManager>,
+    token: String,
+    audio_id: AudioId,
+    effects: Effects,
+) -> Result<()> {
+    effects.check()?;
+    let app_id = authorize(&token).await?;
+    let audios = manager.lock(&app_id).await;
+    let audio = audios.get(audio_id)?;
+    audio.sink.set_speed(effects.speed);
+    audio.effects.set_effects(effects);
 
     Ok(())
 }
 
 #[command]
 #[inline]
-async fn pause_audio(manager: State<'_, AudioManager>, audio_id: AudioId) -> Result<()> {
-    let map = manager.0.lock().await;
-    map.get(&audio_id).ok_or(Error::NoAudio)?.pause();
+async fn clear_audio(
+    manager: State<'_, AudioManager>,
+    token: String,
+    audio_id: AudioId,
+) -> Result<()> {
+    let app_id = authorize(&token).await?;
+    let audios = manager.lock(&app_id).await;
+    let audio = audios.get(audio_id)?;
+    audio.queue.clear();
+    audio.sink.clear();
 
     Ok(())
 }
 
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AudioProgress {
+    /// 正在播放的音频源
+    audio_source_id: Option<AudioSourceId>,
+    /// 以下的单位都是秒
+    position: f64,
+    duration: Option<f64>,
+    /// 正在播放和等待播放的音频源的总时长
+    total_duration: Option<f64>,
+}
+
+/// 返回播放进度，不知道时长时为`None`
 #[command]
 #[inline]
-async fn stop_audio(manager: State<'_, AudioManager>, audio_id: AudioId) -> Result<()> {
-    let map = manager.0.lock().await;
-    map.get(&audio_id).ok_or(Error::NoAudio)?.stop();
+async fn get_audio_progress(
+    manager: State<'_, AudioManager>,
+    token: String,
+    audio_id: AudioId,
+) -> Result<AudioProgress> {
+    let app_id = authorize(&token).await?;
+    let audios = manager.lock(&app_id).await;
+    let audio = audios.get(audio_id)?;
+    let current = audio.queue.current();
+
+    Ok(AudioProgress {
+        audio_source_id: current.map(|(id, _)| id),
+        position: if current.is_some() {
+            audio.events.position()
+        } else {
+            0.0
+        },
+        duration: current
+            .and_then(|(_, duration)| duration)
+            .map(|d| d.as_secs_f64()),
+        total_duration: audio.queue.total_duration().map(|d| d.as_secs_f64()),
+    })
+}
+
+/// 跳转到正在播放的音频源的`position`秒处，音频源会从注册表里取出重新解码，
+/// 音频源因为过期或者超出字节数上限被删除后不能跳转，音频流不能跳转
+#[command]
+async fn seek_audio(
+    audio_manager: State<'_, AudioManager>,
+    audio_source_manager: State<'_, AudioSourceManager>,
+    mixer: State<'_, Mixer>,
+    token: String,
+    audio_id: AudioId,
+    position: f64,
+) -> Result<()> {
+    let app_id = authorize(&token).await?;
+    let (audio_source_id, gain, duration) = {
+        let audios = audio_manager.lock(&app_id).await;
+        audios
+            .get(audio_id)?
+            .queue
+            .current_entry()
+            .ok_or(Error::NotPlaying)?
+    };
+    let data = audio_source_manager
+        .peek(audio_source_id)
+        .await
+        .ok_or(Error::NoAudioSource)?;
+
+    let (source, frames) = tauri::async_runtime::spawn_blocking(move || -> Result<_> {
+        let (source, frames) = decode_at(data, position)?;
+
+        Ok((amplified(source, gain), frames))
+    })
+    .await??;
+
+    let audios = audio_manager.lock(&app_id).await;
+    let audio = audios.get(audio_id)?;
+    let source = audio.wrap(&mixer, source, audio_source_id, duration, frames);
+    if audio.queue.replace_current(audio_source_id, source) {
+        Ok(())
+    } else {
+        Err(Error::NotPlaying)
+    }
+}
+
+/// 跳到下一个音频源，返回是否有正在播放的音频源
+#[command]
+#[inline]
+async fn skip_audio(
+    manager: State<'_, AudioManager>,
+    token: String,
+    audio_id: AudioId,
+) -> Result<bool> {
+    let app_id = authorize(&token).await?;
+    let audios = manager.lock(&app_id).await;
+
+    Ok(audios.get(audio_id)?.queue.skip())
+}
+
+#[command]
+#[inline]
+async fn get_analysis(
+    manager: State<'_, AudioManager>,
+    token: String,
+    audio_id: AudioId,
+) -> Result<Analysis> {
+    let app_id = authorize(&token).await?;
+    let audios = manager.lock(&app_id).await;
+
+    Ok(audios.get(audio_id)?.analysis.analysis())
+}
+
+/// 开启后按设置的间隔发送音量和频谱事件，可以用来做口型同步和可视化
+#[command]
+#[inline]
+async fn set_analysis(
+    manager: State<'_, AudioManager>,
+    token: String,
+    audio_id: AudioId,
+    analysis: Analysis,
+) -> Result<()> {
+    let app_id = authorize(&token).await?;
+    let audios = manager.lock(&app_id).await;
+    let audio = audios.get(audio_id)?;
+    audio.analysis.set_analysis(analysis, &audio.events);
 
     Ok(())
 }
 
+/// 返回播放队列，正在播放的音频源排在最前面
 #[command]
 #[inline]
-async fn clear_audio(manager: State<'_, AudioManager>, audio_id: AudioId) -> Result<()> {
-    let map = manager.0.lock().await;
-    map.get(&audio_id).ok_or(Error::NoAudio)?.clear();
+async fn list_audio_queue(
+    manager: State<'_, AudioManager>,
+    token: String,
+    audio_id: AudioId,
+) -> Result<Vec<QueueItemInfo>> {
+    let app_id = authorize(&token).await?;
+    let audios = manager.lock(&app_id).await;
+
+    Ok(audios.get(audio_id)?.queue.list())
+}
+
+/// 从播放队列里移除音频源，正在播放的会被跳过，返回是否找到了这个音频源
+#[command]
+#[inline]
+async fn remove_from_audio_queue(
+    manager: State<'_, AudioManager>,
+    token: String,
+    audio_id: AudioId,
+    audio_source_id: AudioSourceId,
+) -> Result<bool> {
+    let app_id = authorize(&token).await?;
+    let audios = manager.lock(&app_id).await;
+
+    Ok(audios.get(audio_id)?.queue.remove(audio_source_id))
+}
+
+/// 把等待播放的音频源移到`index`，0为下一个播放，返回是否找到了这个音频源
+#[command]
+#[inline]
+async fn move_in_audio_queue(
+    manager: State<'_, AudioManager>,
+    token: String,
+    audio_id: AudioId,
+    audio_source_id: AudioSourceId,
+    index: usize,
+) -> Result<bool> {
+    let app_id = authorize(&token).await?;
+    let audios = manager.lock(&app_id).await;
+
+    Ok(audios.get(audio_id)?.queue.move_to(audio_source_id, index))
+}
+
+#[command]
+#[inline]
+async fn get_audio_bus(
+    manager: State<'_, AudioManager>,
+    token: String,
+    audio_id: AudioId,
+) -> Result<String> {
+    let app_id = authorize(&token).await?;
+    let audios = manager.lock(&app_id).await;
+
+    Ok(audios.get(audio_id)?.bus.name().to_string())
+}
+
+/// 改变音频所在的总线，只影响之后添加的音频源
+#[command]
+#[inline]
+async fn set_audio_bus(
+    audio_manager: State<'_, AudioManager>,
+    mixer: State<'_, Mixer>,
+    token: String,
+    audio_id: AudioId,
+    bus: String,
+) -> Result<()> {
+    let app_id = authorize(&token).await?;
+    let mut audios = audio_manager.lock(&app_id).await;
+    audios.get_mut(audio_id)?.bus = mixer.bus(&bus)?;
+
+    Ok(())
+}
+
+#[command]
+#[inline]
+fn list_buses(mixer: State<'_, Mixer>) -> Vec<BusInfo> {
+    mixer.buses()
+}
+
+#[command]
+#[inline]
+async fn set_bus_volume(
+    mixer: State<'_, Mixer>,
+    token: String,
+    bus: String,
+    volume: f32,
+) -> Result<()> {
+    authorize(&token).await?;
+    mixer.set_bus_volume(&bus, volume)
+}
+
+#[command]
+#[inline]
+async fn set_bus_muted(
+    mixer: State<'_, Mixer>,
+    token: String,
+    bus: String,
+    muted: bool,
+) -> Result<()> {
+    authorize(&token).await?;
+    mixer.set_bus_muted(&bus, muted)
+}
+
+#[command]
+#[inline]
+async fn set_bus_ducked(
+    mixer: State<'_, Mixer>,
+    token: String,
+    bus: String,
+    ducked: bool,
+) -> Result<()> {
+    authorize(&token).await?;
+    mixer.set_bus_ducked(&bus, ducked)
+}
+
+#[command]
+#[inline]
+fn get_master_volume(mixer: State<'_, Mixer>) -> f32 {
+    mixer.master_volume()
+}
+
+#[command]
+#[inline]
+async fn set_master_volume(mixer: State<'_, Mixer>, token: String, volume: f32) -> Result<()> {
+    authorize(&token).await?;
+    mixer.set_master_volume(volume)
+}
+
+#[command]
+#[inline]
+fn get_normalization(loudness: State<'_, Loudness>) -> Normalization {
+    loudness.normalization()
+}
+
+/// 只影响之后添加的音频源
+#[command]
+#[inline]
+async fn set_normalization(
+    loudness: State<'_, Loudness>,
+    token: String,
+    normalization: Normalization,
+) -> Result<()> {
+    authorize(&token).await?;
+    loudness.set_normalization(normalization);
 
     Ok(())
 }
 
+#[command]
+#[inline]
+fn get_ducking(mixer: State<'_, Mixer>) -> Ducking {
+    mixer.ducking()
+}
+
+#[command]
+#[inline]
+async fn set_ducking(mixer: State<'_, Mixer>, token: String, ducking: Ducking) -> Result<()> {
+    authorize(&token).await?;
+    mixer.set_ducking(ducking)
+}
+
 /// Initializes the plugin.
 #[inline]
 pub fn init<R: Runtime>() -> TauriPlugin<R> {
     Builder::new("acfunlive-neotool-audio")
         .invoke_handler(tauri::generate_handler![
+            list_output_devices,
+            supported_formats,
             new_audio,
+            set_audio_device,
+            get_audio_device,
             delete_audio,
             is_audio_queue_empty,
             add_audio,
+            add_audio_file,
+            add_audio_bytes,
+            set_position_interval,
+            get_audio_source_info,
             get_volume,
             set_volume,
             play_audio,
             pause_audio,
             stop_audio,
-            clear_audio
+            clear_audio,
+            list_audio_queue,
+            remove_from_audio_queue,
+            move_in_audio_queue,
+            get_audio_progress,
+            seek_audio,
+            skip_audio,
+            get_effects,
+            set_effects,
+            get_analysis,
+            set_analysis,
+            get_audio_bus,
+            set_audio_bus,
+            list_buses,
+            set_bus_volume,
+            set_bus_muted,
+            set_bus_ducked,
+            get_master_volume,
+            set_master_volume,
+            get_ducking,
+            set_ducking,
+            get_normalization,
+            set_normalization
         ])
         .setup(|app| {
-            app.manage(AudioSourceManager::default());
+            app.manage(AudioSourceManager::global());
             app.manage(AudioManager::default());
-
-            let (stream, handle) = OutputStream::try_default()?;
-            if HANDLE.set(handle).is_ok() {
-                let _ = ManuallyDrop::new(stream);
-            }
+            app.manage(OutputManager::new(
+                app.path_resolver()
+                    .app_cache_dir()
+                    .map(|dir| dir.join("audio")),
+            ));
+            app.manage(EventSender::new(app.app_handle()));
+            app.manage(Mixer::default());
+            app.manage(Loudness::default());
 
             Ok(())
         })
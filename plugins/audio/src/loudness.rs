@@ -0,0 +1,341 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    f64::consts::PI,
+    sync::Mutex,
+};
+
+use acfunlive_neotool_audio::AudioSource;
+use rodio::Source;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    decode::{decode, BoxedSource},
+    queue, Result,
+};
+
+// 缓存的测量结果的数量
+const CACHE_LIMIT: usize = 1024;
+
+// 低于这个响度的块不参与计算
+const ABSOLUTE_GATE: f64 = -70.0;
+
+const RELATIVE_GATE: f64 = -10.0;
+
+/// 响度标准化的设置，默认关闭
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct Normalization {
+    pub(crate) enabled: bool,
+    /// 目标响度，单位为LUFS
+    pub(crate) target: f64,
+    /// 最多提高多少dB，避免把底噪放大太多
+    pub(crate) max_gain: f64,
+}
+
+impl Default for Normalization {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            target: -16.0,
+            max_gain: 12.0,
+        }
+    }
+}
+
+/// 音频源的测量结果
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Measurement {
+    /// 综合响度，单位为LUFS
+    loudness: f64,
+    /// 采样的峰值，范围是0到1
+    peak: f64,
+}
+
+impl Measurement {
+    /// 达到目标响度需要的增益，不会让峰值超过1
+    fn gain(&self, normalization: &Normalization) -> f32 {
+        let db = (normalization.target - self.loudness).min(normalization.max_gain);
+        let mut gain = 10f64.powf(db / 20.0);
+        if self.peak > 0.0 {
+            gain = gain.min(1.0 / self.peak);
+        }
+
+        gain as f32
+    }
+}
+
+/// 二阶IIR滤波器
+#[derive(Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    #[inline]
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self {
+            b,
+            a,
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    #[inline]
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+
+        y
+    }
+}
+
+/// ITU-R BS.1770的K加权滤波器，按采样率计算系数
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let rate = sample_rate as f64;
+
+    // 高架滤波器，模拟头部的影响
+    let f0 = 1_681.974_450_955_533;
+    let gain = 3.999_843_853_973_347;
+    let q = 0.707_175_236_955_419_6;
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.499_666_774_154_541_6);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    // 高通滤波器
+    let f0 = 38.135_470_876_024_44;
+    let q = 0.500_327_037_323_877_3;
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    [shelf, high_pass]
+}
+
+#[inline]
+fn block_loudness(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+/// 测量综合响度，400毫秒的块每100毫秒计算一次，所有声道的权重都是1。
+/// 静音的音频源返回`None`。
+pub(crate) fn measure(mut source: BoxedSource) -> Option<Measurement> {
+    let channels = source.channels().max(1) as usize;
+    let sample_rate = source.sample_rate().max(1);
+    let mut filters = vec![k_weighting(sample_rate); channels];
+    let step = (sample_rate as usize / 10).max(1);
+
+    // 每100毫秒的能量，最后一个可能不满100毫秒
+    let mut steps = Vec::new();
+    let mut energy = 0.0;
+    let mut frames = 0;
+    let mut channel = 0;
+    let mut peak = 0.0f64;
+    for sample in source.by_ref() {
+        let x = sample as f64 / 32768.0;
+        peak = peak.max(x.abs());
+        let [shelf, high_pass] = &mut filters[channel];
+        let y = high_pass.process(shelf.process(x));
+        energy += y * y;
+
+        channel += 1;
+        if channel == channels {
+            channel = 0;
+            frames += 1;
+            if frames == step {
+                steps.push(energy / step as f64);
+                energy = 0.0;
+                frames = 0;
+            }
+        }
+    }
+
+    // 不足一个块时整个音频源作为一个块
+    let blocks: Vec<f64> = if steps.len() < 4 {
+        let total = steps.len() * step + frames;
+        if total == 0 {
+            return None;
+        }
+        vec![(steps.iter().map(|e| e * step as f64).sum::<f64>() + energy) / total as f64]
+    } else {
+        steps
+            .windows(4)
+            .map(|w| w.iter().sum::<f64>() / 4.0)
+            .collect()
+    };
+
+    let gated = |threshold: f64| -> Option<f64> {
+        let (sum, count) = blocks
+            .iter()
+            .filter(|e| block_loudness(**e) > threshold)
+            .fold((0.0, 0), |(sum, count), e| (sum + e, count + 1));
+
+        (count > 0).then(|| sum / count as f64)
+    };
+    let relative = block_loudness(gated(ABSOLUTE_GATE)?) + RELATIVE_GATE;
+    let loudness = block_loudness(gated(relative.max(ABSOLUTE_GATE))?);
+
+    Some(Measurement { loudness, peak })
+}
+
+/// 按增益放大音频源
+#[inline]
+pub(crate) fn amplified(source: BoxedSource, gain: f32) -> BoxedSource {
+    if (gain - 1.0).abs() < f32::EPSILON {
+        source
+    } else {
+        Box::new(source.amplify(gain))
+    }
+}
+
+#[derive(Default)]
+struct Cache {
+    map: HashMap<u64, Option<Measurement>>,
+    order: VecDeque<u64>,
+}
+
+/// 响度标准化的设置和按音频源指纹缓存的测量结果
+#[derive(Default)]
+pub(crate) struct Loudness {
+    normalization: Mutex<Normalization>,
+    cache: Mutex<Cache>,
+}
+
+impl Loudness {
+    #[inline]
+    pub(crate) fn normalization(&self) -> Normalization {
+        *self.normalization.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    #[inline]
+    pub(crate) fn set_normalization(&self, normalization: Normalization) {
+        *self.normalization.lock().unwrap_or_else(|e| e.into_inner()) = normalization;
+    }
+
+    #[inline]
+    fn cached(&self, fingerprint: u64) -> Option<Option<Measurement>> {
+        let cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+
+        cache.map.get(&fingerprint).copied()
+    }
+
+    fn insert(&self, fingerprint: u64, measurement: Option<Measurement>) {
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        if cache.map.insert(fingerprint, measurement).is_none() {
+            cache.order.push_back(fingerprint);
+            if cache.order.len() > CACHE_LIMIT {
+                if let Some(oldest) = cache.order.pop_front() {
+                    cache.map.remove(&oldest);
+                }
+            }
+        }
+    }
+
+    /// 返回音频源需要的增益，`normalize`为`None`时按全局设置。
+    /// 综合响度要测量完整的音频才能算出来，而音频流（比如`tts_stream`）在数据写完前就开始播放，
+    /// 边播放边调整增益的话同一句话的音量会忽大忽小，所以音频流不做标准化。
+    pub(crate) async fn gain(
+        &self,
+        source: &AudioSource,
+        fingerprint: Option<u64>,
+        normalize: Option<bool>,
+    ) -> Result<f32> {
+        let normalization = self.normalization();
+        if !normalize.unwrap_or(normalization.enabled) || source.is_stream() {
+            return Ok(1.0);
+        }
+
+        // 去重时已经算过指纹的话不用再算
+        let fingerprint = fingerprint.or_else(|| queue::fingerprint(source));
+        let measurement = match fingerprint.and_then(|f| self.cached(f)) {
+            Some(measurement) => measurement,
+            None => {
+                let data = source.clone();
+                let measurement =
+                    tauri::async_runtime::spawn_blocking(move || decode(data).map(measure))
+                        .await??;
+                if let Some(fingerprint) = fingerprint {
+                    self.insert(fingerprint, measurement);
+                }
+
+                measurement
+            }
+        };
+
+        Ok(measurement.map(|m| m.gain(&normalization)).unwrap_or(1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rodio::buffer::SamplesBuffer;
+
+    use super::*;
+
+    // 3秒48kHz的1kHz正弦波，每个声道一样
+    fn sine(amplitude: f32, channels: u16) -> BoxedSource {
+        let samples: Vec<i16> = (0..48000 * 3)
+            .flat_map(|i| {
+                let t = i as f32 / 48000.0;
+                let sample = (2.0 * std::f32::consts::PI * 1000.0 * t).sin() * amplitude;
+                vec![(sample * 32767.0) as i16; channels.into()]
+            })
+            .collect();
+
+        Box::new(SamplesBuffer::new(channels, 48000, samples))
+    }
+
+    #[test]
+    fn test_measure() {
+        // BS.1770里单声道0 dBFS的1kHz正弦波是-3.01 LUFS，幅度减半低6.02 dB
+        let measurement = measure(sine(0.5, 1)).unwrap();
+        assert!((measurement.loudness + 9.03).abs() < 0.1);
+        assert!((measurement.peak - 0.5).abs() < 0.01);
+
+        // 两个声道的能量相加，高3.01 dB
+        let measurement = measure(sine(0.5, 2)).unwrap();
+        assert!((measurement.loudness + 6.02).abs() < 0.1);
+
+        assert!(measure(sine(0.0, 1)).is_none());
+    }
+
+    #[test]
+    fn test_measurement_gain() {
+        let normalization = Normalization {
+            enabled: true,
+            ..Default::default()
+        };
+
+        // 从-9.03 LUFS降到-16 LUFS
+        let gain = measure(sine(0.5, 1)).unwrap().gain(&normalization);
+        assert!((20.0 * gain.log10() + 6.97).abs() < 0.1);
+
+        // 最多提高12 dB，而且峰值不超过1
+        let gain = measure(sine(0.01, 1)).unwrap().gain(&normalization);
+        assert!((20.0 * gain.log10() - 12.0).abs() < 0.01);
+        let measurement = Measurement {
+            loudness: -30.0,
+            peak: 0.5,
+        };
+        assert!((measurement.gain(&normalization) - 2.0).abs() < 1e-6);
+    }
+}
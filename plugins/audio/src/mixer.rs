@@ -0,0 +1,534 @@
+use std::{
+    collections::HashMap,
+    ops::RangeInclusive,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
+
+use rodio::{Sample, Source};
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Result};
+
+/// 播放TTS的总线，这个总线播放时会压低其它设置了ducked的总线
+pub(crate) const VOICE_BUS: &str = "voice";
+
+pub(crate) const SFX_BUS: &str = "sfx";
+
+pub(crate) const MUSIC_BUS: &str = "music";
+
+// 总线和总音量的范围，大于1时放大
+const VOLUME_RANGE: RangeInclusive<f32> = 0.0..=2.0;
+
+// 压低后的音量的范围
+const DUCKING_GAIN_RANGE: RangeInclusive<f32> = 0.0..=1.0;
+
+#[inline]
+fn check_volume(volume: f32) -> Result<()> {
+    if VOLUME_RANGE.contains(&volume) {
+        Ok(())
+    } else {
+        Err(Error::InvalidVolume(volume))
+    }
+}
+
+#[derive(Debug, Default)]
+struct AtomicF32(AtomicU32);
+
+impl AtomicF32 {
+    #[inline]
+    fn new(value: f32) -> Self {
+        Self(AtomicU32::new(value.to_bits()))
+    }
+
+    #[inline]
+    fn load(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    #[inline]
+    fn store(&self, value: f32) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// 混音总线，音频线程只读取原子变量
+#[derive(Debug)]
+pub(crate) struct Bus {
+    name: String,
+    volume: AtomicF32,
+    muted: AtomicBool,
+    ducked: AtomicBool,
+    // 正在播放的音频源数量
+    active: AtomicUsize,
+}
+
+impl Bus {
+    #[inline]
+    fn new(name: String, ducked: bool) -> Arc<Self> {
+        Arc::new(Self {
+            name,
+            volume: AtomicF32::new(1.0),
+            muted: AtomicBool::new(false),
+            ducked: AtomicBool::new(ducked),
+            active: AtomicUsize::new(0),
+        })
+    }
+
+    #[inline]
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    fn gain(&self) -> f32 {
+        if self.muted.load(Ordering::Relaxed) {
+            0.0
+        } else {
+            self.volume.load()
+        }
+    }
+
+    #[inline]
+    fn info(&self) -> BusInfo {
+        BusInfo {
+            name: self.name.clone(),
+            volume: self.volume.load(),
+            muted: self.muted.load(Ordering::Relaxed),
+            ducked: self.ducked.load(Ordering::Relaxed),
+            active: self.active.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// 一个接到总线上的音频源是否计入了总线的`active`
+#[derive(Debug)]
+struct Activity {
+    bus: Arc<Bus>,
+    track: Arc<Track>,
+    active: AtomicBool,
+}
+
+impl Activity {
+    fn activate(&self) {
+        if self.active.load(Ordering::SeqCst) || self.track.paused.load(Ordering::SeqCst) {
+            return;
+        }
+
+        if self
+            .active
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            self.bus.active.fetch_add(1, Ordering::SeqCst);
+            // 和暂停同时发生时由这里或者暂停的一方撤销
+            if self.track.paused.load(Ordering::SeqCst) {
+                self.deactivate();
+            }
+        }
+    }
+
+    #[inline]
+    fn deactivate(&self) {
+        if self.active.swap(false, Ordering::SeqCst) {
+            self.bus.active.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// 一个音频的所有音频源在总线上的状态。暂停时音频源不会被读取，
+/// 所以要在这里撤销它们的`active`，不然暂停的voice总线会一直压低其它总线
+#[derive(Debug, Default)]
+pub(crate) struct Track {
+    paused: AtomicBool,
+    activities: Mutex<Vec<Weak<Activity>>>,
+}
+
+impl Track {
+    pub(crate) fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+        if paused {
+            let activities = self.activities.lock().unwrap_or_else(|e| e.into_inner());
+            for activity in activities.iter().filter_map(Weak::upgrade) {
+                activity.deactivate();
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct BusInfo {
+    name: String,
+    volume: f32,
+    muted: bool,
+    ducked: bool,
+    active: usize,
+}
+
+/// 压低设置：voice总线播放时其它设置了ducked的总线的音量降到`gain`，
+/// 音量在`attack`毫秒内降下去，voice总线停止后在`release`毫秒内恢复
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Ducking {
+    pub(crate) enabled: bool,
+    pub(crate) gain: f32,
+    pub(crate) attack: u32,
+    pub(crate) release: u32,
+}
+
+impl Default for Ducking {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            gain: 0.3,
+            attack: 200,
+            release: 800,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    master: AtomicF32,
+    voice: Arc<Bus>,
+    ducking_enabled: AtomicBool,
+    ducking_gain: AtomicF32,
+    attack: AtomicU32,
+    release: AtomicU32,
+}
+
+#[derive(Debug)]
+pub(crate) struct Mixer {
+    buses: Mutex<HashMap<String, Arc<Bus>>>,
+    shared: Arc<Shared>,
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        let voice = Bus::new(VOICE_BUS.to_string(), false);
+        let ducking = Ducking::default();
+        let mut buses = HashMap::new();
+        buses.insert(VOICE_BUS.to_string(), Arc::clone(&voice));
+        buses.insert(SFX_BUS.to_string(), Bus::new(SFX_BUS.to_string(), false));
+        buses.insert(MUSIC_BUS.to_string(), Bus::new(MUSIC_BUS.to_string(), true));
+
+        Self {
+            buses: Mutex::new(buses),
+            shared: Arc::new(Shared {
+                master: AtomicF32::new(1.0),
+                voice,
+                ducking_enabled: AtomicBool::new(ducking.enabled),
+                ducking_gain: AtomicF32::new(ducking.gain),
+                attack: AtomicU32::new(ducking.attack),
+                release: AtomicU32::new(ducking.release),
+            }),
+        }
+    }
+}
+
+impl Mixer {
+    /// 返回指定名字的总线，不存在时返回错误
+    pub(crate) fn bus(&self, name: &str) -> Result<Arc<Bus>> {
+        let buses = self.buses.lock().unwrap_or_else(|e| e.into_inner());
+
+        buses
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Error::BusNotFound(name.to_string()))
+    }
+
+    pub(crate) fn buses(&self) -> Vec<BusInfo> {
+        let buses = self.buses.lock().unwrap_or_else(|e| e.into_inner());
+        let mut infos: Vec<_> = buses.values().map(|bus| bus.info()).collect();
+        infos.sort_by(|a, b| a.name.cmp(&b.name));
+
+        infos
+    }
+
+    #[inline]
+    pub(crate) fn set_bus_volume(&self, name: &str, volume: f32) -> Result<()> {
+        check_volume(volume)?;
+        self.bus(name)?.volume.store(volume);
+
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn set_bus_muted(&self, name: &str, muted: bool) -> Result<()> {
+        self.bus(name)?.muted.store(muted, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn set_bus_ducked(&self, name: &str, ducked: bool) -> Result<()> {
+        self.bus(name)?.ducked.store(ducked, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn master_volume(&self) -> f32 {
+        self.shared.master.load()
+    }
+
+    #[inline]
+    pub(crate) fn set_master_volume(&self, volume: f32) -> Result<()> {
+        check_volume(volume)?;
+        self.shared.master.store(volume);
+
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn ducking(&self) -> Ducking {
+        Ducking {
+            enabled: self.shared.ducking_enabled.load(Ordering::Relaxed),
+            gain: self.shared.ducking_gain.load(),
+            attack: self.shared.attack.load(Ordering::Relaxed),
+            release: self.shared.release.load(Ordering::Relaxed),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn set_ducking(&self, ducking: Ducking) -> Result<()> {
+        if !DUCKING_GAIN_RANGE.contains(&ducking.gain) {
+            return Err(Error::InvalidDucking(ducking.gain));
+        }
+        self.shared
+            .ducking_enabled
+            .store(ducking.enabled, Ordering::Relaxed);
+        self.shared.ducking_gain.store(ducking.gain);
+        self.shared.attack.store(ducking.attack, Ordering::Relaxed);
+        self.shared.release.store(ducking.release, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    /// 把音频源接到总线上，`track`为音频源所属的音频
+    pub(crate) fn mix<S>(&self, source: S, bus: Arc<Bus>, track: &Arc<Track>) -> Mixed<S> {
+        let activity = Arc::new(Activity {
+            bus,
+            track: Arc::clone(track),
+            active: AtomicBool::new(false),
+        });
+        {
+            let mut activities = track.activities.lock().unwrap_or_else(|e| e.into_inner());
+            activities.retain(|activity| activity.strong_count() > 0);
+            activities.push(Arc::downgrade(&activity));
+        }
+
+        Mixed {
+            inner: source,
+            activity,
+            shared: Arc::clone(&self.shared),
+            duck: 1.0,
+        }
+    }
+}
+
+/// 按总线音量、主音量和压低设置调整采样
+pub(crate) struct Mixed<S> {
+    inner: S,
+    activity: Arc<Activity>,
+    shared: Arc<Shared>,
+    duck: f32,
+}
+
+impl<S: Source> Mixed<S>
+where
+    S::Item: Sample,
+{
+    fn update_duck(&mut self) {
+        let shared = &self.shared;
+        let target = if self.activity.bus.ducked.load(Ordering::Relaxed)
+            && shared.ducking_enabled.load(Ordering::Relaxed)
+            && shared.voice.active.load(Ordering::Relaxed) > 0
+        {
+            shared.ducking_gain.load()
+        } else {
+            1.0
+        };
+        if self.duck == target {
+            return;
+        }
+
+        let time = if target < self.duck {
+            shared.attack.load(Ordering::Relaxed)
+        } else {
+            shared.release.load(Ordering::Relaxed)
+        };
+        let samples = time as f32 / 1000.0
+            * self.inner.sample_rate() as f32
+            * self.inner.channels() as f32;
+        if samples < 1.0 {
+            self.duck = target;
+        } else {
+            // 线性变化，从1变到0需要`samples`个采样
+            let step = 1.0 / samples;
+            self.duck = if target < self.duck {
+                (self.duck - step).max(target)
+            } else {
+                (self.duck + step).min(target)
+            };
+        }
+    }
+}
+
+impl<S: Source> Iterator for Mixed<S>
+where
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let sample = match self.inner.next() {
+            Some(sample) => sample,
+            None => {
+                self.activity.deactivate();
+                return None;
+            }
+        };
+
+        self.activity.activate();
+        self.update_duck();
+
+        Some(sample.amplify(self.shared.master.load() * self.activity.bus.gain() * self.duck))
+    }
+}
+
+impl<S: Source> Source for Mixed<S>
+where
+    S::Item: Sample,
+{
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+impl<S> Drop for Mixed<S> {
+    #[inline]
+    fn drop(&mut self) {
+        self.activity.deactivate();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rodio::buffer::SamplesBuffer;
+
+    use super::*;
+
+    #[inline]
+    fn source() -> SamplesBuffer<f32> {
+        SamplesBuffer::new(1, 1000, vec![1.0; 1000])
+    }
+
+    #[inline]
+    fn active(mixer: &Mixer, name: &str) -> usize {
+        mixer.bus(name).unwrap().active.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn test_mixer_bus() {
+        let mixer = Mixer::default();
+        assert!(matches!(mixer.bus("musci"), Err(Error::BusNotFound(_))));
+        assert!(mixer.set_bus_volume("musci", 0.5).is_err());
+        assert_eq!(mixer.buses().len(), 3);
+
+        mixer.set_bus_volume(MUSIC_BUS, 0.5).unwrap();
+        assert_eq!(mixer.bus(MUSIC_BUS).unwrap().volume.load(), 0.5);
+
+        // 超出范围的音量不会被保存
+        for volume in [f32::NAN, -0.5, 100.0] {
+            assert!(matches!(
+                mixer.set_bus_volume(MUSIC_BUS, volume),
+                Err(Error::InvalidVolume(_))
+            ));
+            assert!(matches!(
+                mixer.set_master_volume(volume),
+                Err(Error::InvalidVolume(_))
+            ));
+        }
+        assert_eq!(mixer.bus(MUSIC_BUS).unwrap().volume.load(), 0.5);
+        assert_eq!(mixer.master_volume(), 1.0);
+        assert!(mixer
+            .set_ducking(Ducking {
+                gain: f32::NAN,
+                ..Default::default()
+            })
+            .is_err());
+        assert_eq!(mixer.ducking().gain, Ducking::default().gain);
+    }
+
+    #[test]
+    fn test_mixer_pause() {
+        let mixer = Mixer::default();
+        let track = Arc::default();
+        let mut voice = mixer.mix(source(), mixer.bus(VOICE_BUS).unwrap(), &track);
+        assert_eq!(active(&mixer, VOICE_BUS), 0);
+        voice.next();
+        assert_eq!(active(&mixer, VOICE_BUS), 1);
+
+        track.set_paused(true);
+        assert_eq!(active(&mixer, VOICE_BUS), 0);
+        // 暂停生效前可能还会读取几个采样
+        voice.next();
+        assert_eq!(active(&mixer, VOICE_BUS), 0);
+
+        track.set_paused(false);
+        voice.next();
+        assert_eq!(active(&mixer, VOICE_BUS), 1);
+        drop(voice);
+        assert_eq!(active(&mixer, VOICE_BUS), 0);
+    }
+
+    #[test]
+    fn test_mixer_ducking() {
+        let mixer = Mixer::default();
+        mixer
+            .set_ducking(Ducking {
+                enabled: true,
+                gain: 0.25,
+                attack: 0,
+                release: 0,
+            })
+            .unwrap();
+        let voice_track = Arc::default();
+        let mut voice = mixer.mix(source(), mixer.bus(VOICE_BUS).unwrap(), &voice_track);
+        let mut music = mixer.mix(source(), mixer.bus(MUSIC_BUS).unwrap(), &Arc::default());
+        assert_eq!(music.next(), Some(1.0));
+
+        voice.next();
+        assert_eq!(music.next(), Some(0.25));
+
+        // 暂停的voice总线不再压低其它总线
+        voice_track.set_paused(true);
+        assert_eq!(music.next(), Some(1.0));
+
+        voice_track.set_paused(false);
+        assert_eq!(voice.by_ref().count(), 999);
+        assert_eq!(music.next(), Some(1.0));
+    }
+}
@@ -0,0 +1,601 @@
+use std::{
+    collections::HashMap,
+    fs::{create_dir_all, File},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, sync_channel, Receiver, SyncSender, TryRecvError},
+        Arc, Weak,
+    },
+    thread::{sleep, spawn},
+    time::{Duration, Instant},
+};
+
+use rodio::{
+    cpal::{
+        self,
+        traits::{HostTrait, StreamTrait},
+        BuildStreamError, FromSample, SampleFormat, SizedSample, Stream, StreamConfig,
+        SupportedStreamConfig,
+    },
+    dynamic_mixer::{self, DynamicMixer, DynamicMixerController},
+    Device, DeviceTrait, Sink, StreamError,
+};
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::{Error, Result};
+
+/// 不输出声音的设备名，没有声卡时默认设备也会用它
+pub(crate) const NULL_OUTPUT: &str = "null";
+
+/// 以这个前缀开头的设备名把声音写进应用缓存目录的WAV文件，前缀后面是文件名。
+/// 没有声音的时候不写入，文件达到WAV格式4GiB的上限后不再写入
+pub(crate) const FILE_OUTPUT_PREFIX: &str = "file:";
+
+const RENDER_CHANNELS: u16 = 2;
+
+const RENDER_SAMPLE_RATE: u32 = 44100;
+
+// 每次渲染10毫秒
+const RENDER_FRAMES: u32 = RENDER_SAMPLE_RATE / 100;
+
+const RENDER_SAMPLES: usize = (RENDER_FRAMES * RENDER_CHANNELS as u32) as usize;
+
+// 每渲染这么多次更新一次WAV文件头，程序意外退出时文件也能用
+const HEADER_UPDATE_INTERVAL: u32 = 100;
+
+// WAV文件头的长度，RIFF块的长度不包括开头的8个字节
+const WAV_HEADER_LEN: u32 = 44;
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct OutputDevice {
+    name: String,
+    is_default: bool,
+}
+
+#[inline]
+fn default_device_name() -> Option<String> {
+    cpal::default_host()
+        .default_output_device()
+        .and_then(|device| device.name().ok())
+}
+
+/// 列出声卡设备，最后是不输出声音的设备
+pub(crate) fn list_devices() -> Result<Vec<OutputDevice>> {
+    let default = default_device_name();
+    let mut devices: Vec<_> = cpal::default_host()
+        .output_devices()?
+        .filter_map(|device| device.name().ok())
+        .map(|name| OutputDevice {
+            is_default: default.as_deref() == Some(name.as_str()),
+            name,
+        })
+        .collect();
+    devices.push(OutputDevice {
+        name: NULL_OUTPUT.to_string(),
+        is_default: default.is_none(),
+    });
+
+    Ok(devices)
+}
+
+fn find_device(name: Option<&str>) -> Result<Device> {
+    let host = cpal::default_host();
+    match name {
+        Some(name) => host
+            .output_devices()?
+            .find(|device| device.name().map(|n| n == name).unwrap_or(false))
+            .ok_or_else(|| Error::OutputDeviceNotFound(name.to_string())),
+        None => host.default_output_device().ok_or(Error::NoDefaultOutputDevice),
+    }
+}
+
+type Controller = Arc<DynamicMixerController<f32>>;
+
+fn build_stream<T>(
+    device: &Device,
+    config: &StreamConfig,
+    mut mixer: DynamicMixer<f32>,
+    failed: Arc<AtomicBool>,
+) -> std::result::Result<Stream, BuildStreamError>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    device.build_output_stream::<T, _, _>(
+        config,
+        move |data, _| {
+            data.iter_mut()
+                .for_each(|d| *d = mixer.next().map(T::from_sample).unwrap_or(T::EQUILIBRIUM))
+        },
+        // 设备被拔出等播放错误，之后添加音频源时再换到新的输出
+        move |_| failed.store(true, Ordering::Relaxed),
+        None,
+    )
+}
+
+fn build_stream_with_config(
+    device: &Device,
+    config: &SupportedStreamConfig,
+    failed: &Arc<AtomicBool>,
+) -> std::result::Result<(Stream, Controller), BuildStreamError> {
+    let (controller, mixer) = dynamic_mixer::mixer(config.channels(), config.sample_rate().0);
+    let failed = Arc::clone(failed);
+    let stream_config = config.config();
+    let stream = match config.sample_format() {
+        SampleFormat::F32 => build_stream::<f32>(device, &stream_config, mixer, failed),
+        SampleFormat::F64 => build_stream::<f64>(device, &stream_config, mixer, failed),
+        SampleFormat::I8 => build_stream::<i8>(device, &stream_config, mixer, failed),
+        SampleFormat::I16 => build_stream::<i16>(device, &stream_config, mixer, failed),
+        SampleFormat::I32 => build_stream::<i32>(device, &stream_config, mixer, failed),
+        SampleFormat::I64 => build_stream::<i64>(device, &stream_config, mixer, failed),
+        SampleFormat::U8 => build_stream::<u8>(device, &stream_config, mixer, failed),
+        SampleFormat::U16 => build_stream::<u16>(device, &stream_config, mixer, failed),
+        SampleFormat::U32 => build_stream::<u32>(device, &stream_config, mixer, failed),
+        SampleFormat::U64 => build_stream::<u64>(device, &stream_config, mixer, failed),
+        _ => Err(BuildStreamError::StreamConfigNotSupported),
+    }?;
+
+    Ok((stream, controller))
+}
+
+/// 和rodio的`OutputStream`一样打开设备，不过自己处理播放错误，默认配置不行时尝试设备支持的其它配置
+fn open_stream(device: &Device, failed: &Arc<AtomicBool>) -> Result<(Stream, Controller)> {
+    let config = device
+        .default_output_config()
+        .map_err(StreamError::DefaultStreamConfigError)?;
+    let (stream, controller) = build_stream_with_config(device, &config, failed).or_else(|e| {
+        device
+            .supported_output_configs()
+            .map_err(StreamError::SupportedStreamConfigsError)?
+            .find_map(|range| {
+                build_stream_with_config(device, &range.with_max_sample_rate(), failed).ok()
+            })
+            .ok_or(StreamError::BuildStreamError(e))
+    })?;
+    stream.play().map_err(StreamError::PlayStreamError)?;
+
+    Ok((stream, controller))
+}
+
+/// 16位PCM的WAV文件
+struct WavWriter {
+    file: BufWriter<File>,
+    data_len: u32,
+    // RIFF块的长度是32位的，数据不能超过这个长度，按整帧对齐
+    max_data_len: u32,
+}
+
+impl WavWriter {
+    fn create(path: &Path, channels: u16, sample_rate: u32) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let block_align = channels * 2;
+        file.write_all(b"RIFF")?;
+        file.write_all(&36u32.to_le_bytes())?;
+        file.write_all(b"WAVEfmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?;
+        file.write_all(&channels.to_le_bytes())?;
+        file.write_all(&sample_rate.to_le_bytes())?;
+        file.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&16u16.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+        // 马上写入文件头，还没有声音时文件也能用
+        file.flush()?;
+
+        Ok(Self {
+            file,
+            data_len: 0,
+            max_data_len: (u32::MAX - (WAV_HEADER_LEN - 8)) / block_align as u32
+                * block_align as u32,
+        })
+    }
+
+    /// 写入一块采样，文件满了时不写入并返回`false`
+    fn write(&mut self, samples: &[f32]) -> io::Result<bool> {
+        let len = match self.data_len.checked_add(samples.len() as u32 * 2) {
+            Some(len) if len <= self.max_data_len => len,
+            _ => return Ok(false),
+        };
+        for sample in samples {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_len = len;
+
+        Ok(true)
+    }
+
+    fn update_header(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file
+            .write_all(&(self.data_len + (WAV_HEADER_LEN - 8)).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.data_len.to_le_bytes())?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+}
+
+// 按实际时间的速度从混音器取出声音，写进WAV文件或者丢掉，直到`Output`被drop
+fn render(mut mixer: DynamicMixer<f32>, mut writer: Option<WavWriter>, stop: Receiver<()>) {
+    let start = Instant::now();
+    let mut frames = 0u64;
+    let mut chunks = 0u32;
+    let mut chunk = Vec::with_capacity(RENDER_SAMPLES);
+    while let Err(TryRecvError::Empty) = stop.try_recv() {
+        chunk.clear();
+        chunk.extend((0..RENDER_SAMPLES).map(|_| mixer.next().unwrap_or(0.0)));
+        // 整块都是静音时不写入，空闲时文件不会一直变大
+        if let Some(w) = &mut writer {
+            if chunk.iter().any(|&sample| sample != 0.0) {
+                match w.write(&chunk) {
+                    Ok(true) => {}
+                    // 文件满了，写好文件头后不再写入
+                    Ok(false) => {
+                        let _ = w.update_header();
+                        writer = None;
+                    }
+                    Err(_) => writer = None,
+                }
+            }
+        }
+
+        chunks += 1;
+        if chunks == HEADER_UPDATE_INTERVAL {
+            chunks = 0;
+            if let Some(w) = &mut writer {
+                if w.update_header().is_err() {
+                    writer = None;
+                }
+            }
+        }
+
+        frames += RENDER_FRAMES as u64;
+        let next = start + Duration::from_secs_f64(frames as f64 / RENDER_SAMPLE_RATE as f64);
+        let now = Instant::now();
+        if next > now {
+            sleep(next - now);
+        }
+    }
+
+    if let Some(mut w) = writer {
+        let _ = w.update_header();
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    Device,
+    /// 不是声卡的输出，声音混合后由单独的线程取出
+    Virtual,
+}
+
+/// 打开的输出设备，`Stream`不能在线程间传递，所以放在单独的线程里，drop时线程退出并关闭设备
+pub(crate) struct Output {
+    name: String,
+    backend: Backend,
+    controller: Controller,
+    // 播放出错后设为`true`
+    failed: Arc<AtomicBool>,
+    _stop: SyncSender<()>,
+}
+
+impl Output {
+    fn open(device: Option<String>) -> Result<Self> {
+        let (result_sender, result_receiver) = channel();
+        let (stop_sender, stop_receiver) = sync_channel::<()>(0);
+        let failed = Arc::new(AtomicBool::new(false));
+        let stream_failed = Arc::clone(&failed);
+
+        spawn(move || {
+            let result = find_device(device.as_deref()).and_then(|device| {
+                let name = device.name()?;
+                let (stream, controller) = open_stream(&device, &stream_failed)?;
+
+                Ok((name, stream, controller))
+            });
+
+            match result {
+                Ok((name, stream, controller)) => {
+                    let _ = result_sender.send(Ok((name, controller)));
+                    // 等到Output被drop
+                    let _ = stop_receiver.recv();
+                    drop(stream);
+                }
+                Err(e) => {
+                    let _ = result_sender.send(Err(e));
+                }
+            }
+        });
+
+        let (name, controller) = result_receiver
+            .recv()
+            .map_err(|_| Error::OutputThreadExited)??;
+
+        Ok(Self {
+            name,
+            backend: Backend::Device,
+            controller,
+            failed,
+            _stop: stop_sender,
+        })
+    }
+
+    /// 不输出声音或者写进WAV文件的输出，播放的速度和声卡一样
+    fn open_virtual(name: String, path: Option<&Path>) -> Result<Self> {
+        let writer = match path {
+            Some(path) => Some(WavWriter::create(
+                path,
+                RENDER_CHANNELS,
+                RENDER_SAMPLE_RATE,
+            )?),
+            None => None,
+        };
+        let (controller, mixer) = dynamic_mixer::mixer(RENDER_CHANNELS, RENDER_SAMPLE_RATE);
+        let (stop_sender, stop_receiver) = sync_channel::<()>(0);
+        spawn(move || render(mixer, writer, stop_receiver));
+
+        Ok(Self {
+            name,
+            backend: Backend::Virtual,
+            controller,
+            failed: Arc::default(),
+            _stop: stop_sender,
+        })
+    }
+
+    #[inline]
+    fn null() -> Result<Self> {
+        Self::open_virtual(NULL_OUTPUT.to_string(), None)
+    }
+
+    #[inline]
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// 新建播放到这个输出的`Sink`
+    #[inline]
+    pub(crate) fn new_sink(&self) -> Sink {
+        let (sink, queue) = Sink::new_idle();
+        self.controller.add(queue);
+
+        sink
+    }
+
+    /// 播放时是否出过错
+    #[inline]
+    pub(crate) fn has_failed(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    /// 输出设备是否还能用，选择默认设备时默认设备变了也算不能用，
+    /// 没有声卡时用的null输出在有了默认设备后也算不能用
+    fn is_available(&self, device: Option<&str>) -> bool {
+        if self.has_failed() {
+            return false;
+        }
+
+        match (self.backend, device) {
+            (Backend::Virtual, Some(_)) => true,
+            (Backend::Virtual, None) => default_device_name().is_none(),
+            (Backend::Device, Some(_)) => cpal::default_host()
+                .output_devices()
+                .map(|mut devices| {
+                    devices.any(|d| d.name().map(|n| n == self.name).unwrap_or(false))
+                })
+                .unwrap_or(false),
+            (Backend::Device, None) => default_device_name().as_deref() == Some(self.name.as_str()),
+        }
+    }
+}
+
+/// 已经打开的输出设备，同一个设备由所有使用它的音频共享
+#[derive(Default)]
+pub(crate) struct OutputManager {
+    outputs: Mutex<HashMap<Option<String>, Weak<Output>>>,
+    // WAV文件输出的目录
+    render_dir: Option<PathBuf>,
+}
+
+impl OutputManager {
+    #[inline]
+    pub(crate) fn new(render_dir: Option<PathBuf>) -> Self {
+        Self {
+            outputs: Mutex::default(),
+            render_dir,
+        }
+    }
+
+    fn open_file(render_dir: Option<&Path>, file_name: &str) -> Result<Output> {
+        let invalid = || Error::InvalidOutputFile(file_name.to_string());
+        // 只能是文件名，不能包含路径
+        if Path::new(file_name).file_name().and_then(|n| n.to_str()) != Some(file_name) {
+            return Err(invalid());
+        }
+        let dir = render_dir.ok_or_else(invalid)?;
+        create_dir_all(dir)?;
+        let mut path = dir.join(file_name);
+        if path.extension().is_none() {
+            path.set_extension("wav");
+        }
+
+        Output::open_virtual(path.to_string_lossy().into_owned(), Some(&path))
+    }
+
+    // 检查已经打开的输出或者打开新的输出，会列出设备和打开设备，需要在阻塞的线程里调用
+    fn get_blocking(
+        render_dir: Option<&Path>,
+        device: Option<&str>,
+        opened: Option<Arc<Output>>,
+    ) -> Result<Arc<Output>> {
+        if let Some(output) = opened {
+            if output.is_available(device) {
+                return Ok(output);
+            }
+        }
+
+        let output = match device {
+            Some(NULL_OUTPUT) => Output::null()?,
+            Some(name) if name.starts_with(FILE_OUTPUT_PREFIX) => {
+                Self::open_file(render_dir, &name[FILE_OUTPUT_PREFIX.len()..])?
+            }
+            Some(name) => Output::open(Some(name.to_string()))?,
+            None => match Output::open(None) {
+                Ok(output) => output,
+                Err(_) => Output::null()?,
+            },
+        };
+
+        Ok(Arc::new(output))
+    }
+
+    /// 返回指定设备的输出，`None`为默认设备，没有声卡时默认设备为null输出
+    pub(crate) async fn get(&self, device: Option<&str>) -> Result<Arc<Output>> {
+        let mut map = self.outputs.lock().await;
+        let key = device.map(String::from);
+        let opened = map.get(&key).and_then(Weak::upgrade);
+        let render_dir = self.render_dir.clone();
+        let device = key.clone();
+        let output = tauri::async_runtime::spawn_blocking(move || {
+            Self::get_blocking(render_dir.as_deref(), device.as_deref(), opened)
+        })
+        .await??;
+        map.insert(key, Arc::downgrade(&output));
+        map.retain(|_, output| output.strong_count() > 0);
+
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{read, remove_dir_all},
+        sync::atomic::AtomicUsize,
+    };
+
+    use rodio::{buffer::SamplesBuffer, source::Done};
+
+    use super::*;
+
+    #[inline]
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("neotool-audio-{name}-{}", std::process::id()))
+    }
+
+    #[inline]
+    fn u16_at(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([data[offset], data[offset + 1]])
+    }
+
+    #[inline]
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_render_wav() {
+        let dir = temp_dir("render");
+        create_dir_all(&dir).unwrap();
+        let path = dir.join("render.wav");
+        let writer = WavWriter::create(&path, RENDER_CHANNELS, RENDER_SAMPLE_RATE).unwrap();
+        let (controller, mixer) = dynamic_mixer::mixer(RENDER_CHANNELS, RENDER_SAMPLE_RATE);
+
+        // 100毫秒的立体声，前后都是静音
+        let frames = RENDER_SAMPLE_RATE as usize / 10;
+        let samples = vec![0.5f32; frames * RENDER_CHANNELS as usize];
+        let remaining = Arc::new(AtomicUsize::new(1));
+        controller.add(Done::new(
+            SamplesBuffer::new(RENDER_CHANNELS, RENDER_SAMPLE_RATE, samples),
+            Arc::clone(&remaining),
+        ));
+        let (stop_sender, stop_receiver) = sync_channel(0);
+        let handle = spawn(move || render(mixer, Some(writer), stop_receiver));
+        while remaining.load(Ordering::Relaxed) > 0 {
+            sleep(Duration::from_millis(10));
+        }
+        drop(stop_sender);
+        handle.join().unwrap();
+
+        let data = read(&path).unwrap();
+        remove_dir_all(&dir).unwrap();
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32_at(&data, 4) as usize, data.len() - 8);
+        assert_eq!(&data[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(&data, 16), 16);
+        assert_eq!(u16_at(&data, 20), 1);
+        assert_eq!(u16_at(&data, 22), RENDER_CHANNELS);
+        assert_eq!(u32_at(&data, 24), RENDER_SAMPLE_RATE);
+        assert_eq!(u32_at(&data, 28), RENDER_SAMPLE_RATE * 4);
+        assert_eq!(u16_at(&data, 32), 4);
+        assert_eq!(u16_at(&data, 34), 16);
+        assert_eq!(&data[36..40], b"data");
+        let data_len = u32_at(&data, 40) as usize;
+        assert_eq!(data_len, data.len() - 44);
+        assert_eq!(data_len % 4, 0);
+
+        // 渲染是整块进行的，所有声音都写进了文件，前后的静音没有写入
+        let samples: Vec<i16> = data[44..]
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        let expected = (0.5 * i16::MAX as f32) as i16;
+        let count = samples.iter().filter(|&&s| s == expected).count();
+        assert_eq!(count, frames * RENDER_CHANNELS as usize);
+        assert!(samples.iter().all(|&s| s == 0 || s == expected));
+        assert!(samples.len() < count + 2 * RENDER_SAMPLES);
+    }
+
+    #[test]
+    fn test_wav_limit() {
+        let dir = temp_dir("limit");
+        create_dir_all(&dir).unwrap();
+        let path = dir.join("limit.wav");
+        let mut writer = WavWriter::create(&path, RENDER_CHANNELS, RENDER_SAMPLE_RATE).unwrap();
+        assert_eq!(writer.max_data_len % 4, 0);
+        assert!(writer.max_data_len as u64 + WAV_HEADER_LEN as u64 - 8 <= u32::MAX as u64);
+
+        writer.max_data_len = 12;
+        assert!(writer.write(&[0.5; 4]).unwrap());
+        // 超出上限的一块不会写入一部分
+        assert!(!writer.write(&[0.5; 4]).unwrap());
+        assert!(writer.write(&[0.5; 2]).unwrap());
+        assert!(!writer.write(&[0.5; 2]).unwrap());
+        writer.update_header().unwrap();
+        drop(writer);
+
+        let data = read(&path).unwrap();
+        remove_dir_all(&dir).unwrap();
+        assert_eq!(data.len(), 44 + 12);
+        assert_eq!(u32_at(&data, 4), 36 + 12);
+        assert_eq!(u32_at(&data, 40), 12);
+    }
+
+    #[test]
+    fn test_open_file() {
+        let dir = temp_dir("open");
+        for name in ["", "../render", "a/render"] {
+            assert!(matches!(
+                OutputManager::open_file(Some(&dir), name),
+                Err(Error::InvalidOutputFile(_))
+            ));
+        }
+        assert!(matches!(
+            OutputManager::open_file(None, "render"),
+            Err(Error::InvalidOutputFile(_))
+        ));
+
+        let output = OutputManager::open_file(Some(&dir), "render").unwrap();
+        assert!(output.name().ends_with("render.wav"));
+        let data = read(dir.join("render.wav")).unwrap();
+        assert_eq!(&data[0..4], b"RIFF");
+        drop(output);
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,633 @@
+use std::{
+    collections::{hash_map::DefaultHasher, VecDeque},
+    hash::{Hash, Hasher},
+    mem,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use acfunlive_neotool_audio::{AudioData, AudioSource, AudioSourceId, AudioSourceManager};
+use rodio::Source;
+use serde::{Deserialize, Serialize};
+
+pub(crate) type QueuedSource = Box<dyn Source<Item = f32> + Send>;
+
+// 没有长度的音频源每隔这么多采样检查一次声道数和采样率，和rodio自带的队列一样
+const THRESHOLD: usize = 512;
+
+// 用来去重的最近加入的音频源数量
+const RECENT_LIMIT: usize = 64;
+
+/// 加入队列的选项
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct QueueOptions {
+    /// 优先级，越大越先播放，相同优先级的按加入顺序播放
+    pub(crate) priority: i32,
+    /// 打断正在播放的音频源并马上播放
+    pub(crate) interrupt: bool,
+    /// 等待播放的音频源已经有这么多时丢弃新的音频源
+    pub(crate) max_queue_len: Option<usize>,
+    /// 这段时间内加入过数据相同的音频源时丢弃新的音频源，单位为毫秒，音频流不去重
+    pub(crate) dedup_window: Option<u64>,
+    /// 是否做响度标准化，不设置时按全局设置，音频流不做标准化
+    pub(crate) normalize: Option<bool>,
+}
+
+/// 音频源没有加入队列的原因
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum DropReason {
+    QueueFull,
+    Duplicate,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct QueueItemInfo {
+    audio_source_id: AudioSourceId,
+    priority: i32,
+    playing: bool,
+    /// 单位为秒，不知道时长时为`None`
+    duration: Option<f64>,
+}
+
+/// 队列里的音频源在注册表里的引用，音频源离开队列时从注册表里删除
+pub(crate) struct SourceRef {
+    audio_source_id: AudioSourceId,
+    manager: AudioSourceManager,
+}
+
+impl SourceRef {
+    #[inline]
+    pub(crate) fn new(manager: AudioSourceManager, audio_source_id: AudioSourceId) -> Self {
+        Self {
+            audio_source_id,
+            manager,
+        }
+    }
+}
+
+impl Drop for SourceRef {
+    fn drop(&mut self) {
+        let manager = self.manager.clone();
+        let audio_source_id = self.audio_source_id;
+        // 可能在音频输出的线程里drop，不能在这里等待注册表的锁
+        tauri::async_runtime::spawn(async move {
+            manager.remove(audio_source_id).await;
+        });
+    }
+}
+
+/// 要加入队列的音频源
+pub(crate) struct QueueEntry {
+    pub(crate) audio_source_id: AudioSourceId,
+    pub(crate) source: QueuedSource,
+    pub(crate) duration: Option<Duration>,
+    /// 原始数据留在注册表里，跳转时取出来重新解码
+    pub(crate) data: SourceRef,
+    /// 响度标准化的增益
+    pub(crate) gain: f32,
+}
+
+/// 音频源数据的指纹，用来去重
+pub(crate) fn fingerprint(source: &AudioSource) -> Option<u64> {
+    match &source.data {
+        AudioData::Complete(data) => {
+            let mut hasher = DefaultHasher::new();
+            source.format.hash(&mut hasher);
+            data.hash(&mut hasher);
+
+            Some(hasher.finish())
+        }
+        AudioData::Stream(_) => None,
+    }
+}
+
+struct Item {
+    entry: QueueEntry,
+    priority: i32,
+}
+
+impl Item {
+    #[inline]
+    fn info(&self) -> QueueItemInfo {
+        QueueItemInfo {
+            audio_source_id: self.entry.audio_source_id,
+            priority: self.priority,
+            playing: false,
+            duration: self.entry.duration.map(|d| d.as_secs_f64()),
+        }
+    }
+
+    // 拆成正在播放的音频源的信息和要播放的音频源
+    #[inline]
+    fn into_current(self) -> (Current, QueuedSource) {
+        (
+            Current {
+                audio_source_id: self.entry.audio_source_id,
+                priority: self.priority,
+                duration: self.entry.duration,
+                data: Some(self.entry.data),
+                gain: self.entry.gain,
+            },
+            self.entry.source,
+        )
+    }
+}
+
+struct Current {
+    audio_source_id: AudioSourceId,
+    priority: i32,
+    duration: Option<Duration>,
+    // 跳转后交给跳转后的音频源，为`None`
+    data: Option<SourceRef>,
+    gain: f32,
+}
+
+#[derive(Default)]
+struct Inner {
+    pending: VecDeque<Item>,
+    current: Option<Current>,
+    recent: VecDeque<(u64, Instant)>,
+    // 每次换掉播放队列的`QueueSource`都加一，旧的`QueueSource`不再取音频源
+    generation: u64,
+    running: bool,
+}
+
+/// 音频的播放队列，`Sink`里只放一个从这里按优先级取音频源的`QueueSource`
+#[derive(Default)]
+pub(crate) struct PlayQueue {
+    inner: Mutex<Inner>,
+    // 跳过正在播放的音频源
+    skip: AtomicBool,
+}
+
+impl PlayQueue {
+    #[inline]
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// 检查音频源能不能加入队列，只检查不记录，加入队列后才记下它的指纹
+    pub(crate) fn admit(
+        &self,
+        fingerprint: Option<u64>,
+        options: &QueueOptions,
+    ) -> Result<(), DropReason> {
+        let inner = self.lock();
+        if let Some(max) = options.max_queue_len {
+            if inner.pending.len() >= max {
+                return Err(DropReason::QueueFull);
+            }
+        }
+
+        if let (Some(fingerprint), Some(window)) = (fingerprint, options.dedup_window) {
+            let now = Instant::now();
+            let window = Duration::from_millis(window);
+            if inner
+                .recent
+                .iter()
+                .any(|(f, time)| *f == fingerprint && now.duration_since(*time) < window)
+            {
+                return Err(DropReason::Duplicate);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 加入队列并记下音频源的指纹，队列没在播放时返回需要放进`Sink`的`QueueSource`
+    pub(crate) fn push(
+        self: &Arc<Self>,
+        entry: QueueEntry,
+        fingerprint: Option<u64>,
+        options: &QueueOptions,
+    ) -> Option<QueueSource> {
+        let mut inner = self.lock();
+        let item = Item {
+            entry,
+            priority: options.priority,
+        };
+        if options.interrupt {
+            inner.pending.push_front(item);
+            if inner.current.is_some() {
+                self.skip.store(true, Ordering::Release);
+            }
+        } else {
+            let index = inner
+                .pending
+                .iter()
+                .position(|item| item.priority < options.priority)
+                .unwrap_or(inner.pending.len());
+            inner.pending.insert(index, item);
+        }
+
+        if let Some(fingerprint) = fingerprint {
+            inner.recent.push_back((fingerprint, Instant::now()));
+            if inner.recent.len() > RECENT_LIMIT {
+                inner.recent.pop_front();
+            }
+        }
+
+        self.start(&mut inner)
+    }
+
+    fn start(self: &Arc<Self>, inner: &mut Inner) -> Option<QueueSource> {
+        if inner.running {
+            return None;
+        }
+        // 先取出第一个音频源，`Sink`一开始就能拿到正确的声道数和采样率
+        let (current, source) = inner.pending.pop_front()?.into_current();
+        inner.running = true;
+        inner.current = Some(current);
+        self.skip.store(false, Ordering::Release);
+
+        Some(QueueSource {
+            queue: Arc::clone(self),
+            generation: inner.generation,
+            current: Some(source),
+        })
+    }
+
+    /// 换了`Sink`后调用，等待播放的音频源会继续播放
+    pub(crate) fn restart(self: &Arc<Self>) -> Option<QueueSource> {
+        let mut inner = self.lock();
+        inner.generation += 1;
+        inner.current = None;
+        inner.running = false;
+
+        self.start(&mut inner)
+    }
+
+    /// 清空队列
+    pub(crate) fn clear(&self) {
+        let pending = {
+            let mut inner = self.lock();
+            inner.generation += 1;
+            inner.current = None;
+            inner.running = false;
+            mem::take(&mut inner.pending)
+        };
+        // 在锁外面drop，drop时会发送事件
+        drop(pending);
+    }
+
+    #[inline]
+    pub(crate) fn is_empty(&self) -> bool {
+        let inner = self.lock();
+
+        inner.current.is_none() && inner.pending.is_empty()
+    }
+
+    /// 正在播放的音频源排在最前面
+    pub(crate) fn list(&self) -> Vec<QueueItemInfo> {
+        let inner = self.lock();
+
+        inner
+            .current
+            .iter()
+            .map(|current| QueueItemInfo {
+                audio_source_id: current.audio_source_id,
+                priority: current.priority,
+                playing: true,
+                duration: current.duration.map(|d| d.as_secs_f64()),
+            })
+            .chain(inner.pending.iter().map(Item::info))
+            .collect()
+    }
+
+    /// 正在播放的音频源的ID和时长
+    #[inline]
+    pub(crate) fn current(&self) -> Option<(AudioSourceId, Option<Duration>)> {
+        self.lock()
+            .current
+            .as_ref()
+            .map(|current| (current.audio_source_id, current.duration))
+    }
+
+    /// 正在播放和等待播放的音频源的总时长，有音频源不知道时长时返回`None`
+    pub(crate) fn total_duration(&self) -> Option<Duration> {
+        let inner = self.lock();
+
+        inner
+            .current
+            .iter()
+            .map(|current| current.duration)
+            .chain(inner.pending.iter().map(|item| item.entry.duration))
+            .sum()
+    }
+
+    /// 正在播放的音频源的ID、增益和时长，跳转时用来重新解码
+    #[inline]
+    pub(crate) fn current_entry(&self) -> Option<(AudioSourceId, f32, Option<Duration>)> {
+        self.lock()
+            .current
+            .as_ref()
+            .map(|current| (current.audio_source_id, current.gain, current.duration))
+    }
+
+    /// 用跳转后的音频源换掉正在播放的音频源，正在播放的已经不是这个音频源时返回`false`
+    pub(crate) fn replace_current(
+        &self,
+        audio_source_id: AudioSourceId,
+        source: QueuedSource,
+    ) -> bool {
+        let mut inner = self.lock();
+        let (priority, duration, data, gain) = match &mut inner.current {
+            Some(current) if current.audio_source_id == audio_source_id => (
+                current.priority,
+                current.duration,
+                current.data.take(),
+                current.gain,
+            ),
+            _ => return false,
+        };
+        match data {
+            Some(data) => inner.pending.push_front(Item {
+                entry: QueueEntry {
+                    audio_source_id,
+                    source,
+                    duration,
+                    data,
+                    gain,
+                },
+                priority,
+            }),
+            // 上一次跳转后的音频源还没开始播放，直接换掉它
+            None => match inner.pending.front_mut() {
+                Some(item) if item.entry.audio_source_id == audio_source_id => {
+                    item.entry.source = source;
+                }
+                _ => return false,
+            },
+        }
+        self.skip.store(true, Ordering::Release);
+
+        true
+    }
+
+    /// 跳到下一个音频源，没有正在播放的音频源时返回`false`
+    #[inline]
+    pub(crate) fn skip(&self) -> bool {
+        let inner = self.lock();
+        if inner.current.is_some() {
+            self.skip.store(true, Ordering::Release);
+
+            true
+        } else {
+            false
+        }
+    }
+
+    /// 移除队列里的音频源，正在播放的会被跳过
+    pub(crate) fn remove(&self, audio_source_id: AudioSourceId) -> bool {
+        let item = {
+            let mut inner = self.lock();
+            if inner
+                .current
+                .as_ref()
+                .map(|current| current.audio_source_id)
+                == Some(audio_source_id)
+            {
+                self.skip.store(true, Ordering::Release);
+                return true;
+            }
+            match inner
+                .pending
+                .iter()
+                .position(|item| item.entry.audio_source_id == audio_source_id)
+            {
+                Some(index) => inner.pending.remove(index),
+                None => return false,
+            }
+        };
+        drop(item);
+
+        true
+    }
+
+    /// 把等待播放的音频源移到`index`，0为下一个播放
+    pub(crate) fn move_to(&self, audio_source_id: AudioSourceId, index: usize) -> bool {
+        let mut inner = self.lock();
+        match inner
+            .pending
+            .iter()
+            .position(|item| item.entry.audio_source_id == audio_source_id)
+        {
+            Some(from) => {
+                if let Some(item) = inner.pending.remove(from) {
+                    let index = index.min(inner.pending.len());
+                    inner.pending.insert(index, item);
+                }
+
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// 放进`Sink`里的音频源，依次播放队列里的音频源，队列空了就结束
+pub(crate) struct QueueSource {
+    queue: Arc<PlayQueue>,
+    generation: u64,
+    current: Option<QueuedSource>,
+}
+
+impl QueueSource {
+    fn next_source(&mut self) -> bool {
+        let mut inner = self.queue.lock();
+        if inner.generation != self.generation {
+            return false;
+        }
+        // 取出的就是要马上播放的音频源，不用再跳过
+        self.queue.skip.store(false, Ordering::Release);
+        match inner.pending.pop_front() {
+            Some(item) => {
+                let (current, source) = item.into_current();
+                inner.current = Some(current);
+                self.current = Some(source);
+
+                true
+            }
+            None => {
+                inner.current = None;
+                inner.running = false;
+
+                false
+            }
+        }
+    }
+}
+
+impl Iterator for QueueSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.queue.skip.swap(false, Ordering::AcqRel) {
+                self.current = None;
+            }
+            if let Some(current) = &mut self.current {
+                if let Some(sample) = current.next() {
+                    return Some(sample);
+                }
+                self.current = None;
+            }
+            if !self.next_source() {
+                return None;
+            }
+        }
+    }
+}
+
+impl Source for QueueSource {
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        match self.current.as_ref().and_then(|c| c.current_frame_len()) {
+            Some(len) if len > 0 => Some(len),
+            _ => Some(THRESHOLD),
+        }
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.current.as_ref().map(|c| c.channels()).unwrap_or(1)
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.current
+            .as_ref()
+            .map(|c| c.sample_rate())
+            .unwrap_or(44100)
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+impl Drop for QueueSource {
+    fn drop(&mut self) {
+        let mut inner = self.queue.lock();
+        if inner.generation == self.generation {
+            inner.current = None;
+            inner.running = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use acfunlive_neotool_audio::{AudioFormat, AudioOrigin};
+    use rodio::buffer::SamplesBuffer;
+
+    use super::*;
+
+    #[inline]
+    fn samples(len: usize) -> QueuedSource {
+        Box::new(SamplesBuffer::new(1, 8000, vec![0.0f32; len]))
+    }
+
+    fn entry(audio_source_id: AudioSourceId) -> QueueEntry {
+        QueueEntry {
+            audio_source_id,
+            source: samples(8),
+            duration: None,
+            data: SourceRef::new(AudioSourceManager::default(), audio_source_id),
+            gain: 1.0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queue_admit() {
+        let queue = Arc::new(PlayQueue::default());
+        let options = QueueOptions {
+            max_queue_len: Some(1),
+            dedup_window: Some(60000),
+            ..Default::default()
+        };
+
+        // 检查时不记录指纹，没加入队列的音频源不影响之后的音频源
+        assert_eq!(queue.admit(Some(1), &options), Ok(()));
+        assert_eq!(queue.admit(Some(1), &options), Ok(()));
+
+        // 第一个音频源马上开始播放，不算等待播放的音频源
+        let source = queue.push(entry(1), Some(1), &options);
+        assert!(source.is_some());
+        assert_eq!(queue.admit(Some(1), &options), Err(DropReason::Duplicate));
+        let no_dedup = QueueOptions {
+            dedup_window: None,
+            ..options
+        };
+        assert_eq!(queue.admit(Some(1), &no_dedup), Ok(()));
+
+        assert_eq!(queue.admit(Some(2), &options), Ok(()));
+        assert!(queue.push(entry(2), Some(2), &options).is_none());
+        assert_eq!(queue.admit(Some(3), &options), Err(DropReason::QueueFull));
+        assert_eq!(queue.admit(None, &QueueOptions::default()), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_queue_order() {
+        let queue = Arc::new(PlayQueue::default());
+        let source = queue.push(entry(1), None, &QueueOptions::default());
+        for (id, priority) in [(2, 0), (3, 1), (4, 0)] {
+            let options = QueueOptions {
+                priority,
+                ..Default::default()
+            };
+            assert!(queue.push(entry(id), None, &options).is_none());
+        }
+        let ids: Vec<AudioSourceId> = queue.list().iter().map(|i| i.audio_source_id).collect();
+        assert_eq!(ids, [1, 3, 2, 4]);
+
+        assert!(queue.move_to(4, 0));
+        assert!(queue.remove(2));
+        assert!(!queue.remove(5));
+        let ids: Vec<AudioSourceId> = queue.list().iter().map(|i| i.audio_source_id).collect();
+        assert_eq!(ids, [1, 4, 3]);
+
+        // 依次播放完所有音频源
+        assert_eq!(source.unwrap().count(), 8 * 3);
+        assert!(queue.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_queue_source_ref() {
+        let manager = AudioSourceManager::default();
+        let data = AudioSource::new(vec![1, 2, 3], AudioFormat::Wav, AudioOrigin::Generated);
+        let id = manager.add(data.clone()).await;
+        let queue = Arc::new(PlayQueue::default());
+        let entry = QueueEntry {
+            audio_source_id: id,
+            source: samples(8),
+            duration: None,
+            data: SourceRef::new(manager.clone(), id),
+            gain: 0.5,
+        };
+        let mut source = queue.push(entry, None, &QueueOptions::default()).unwrap();
+        assert_eq!(queue.current_entry(), Some((id, 0.5, None)));
+
+        // 跳转两次，第二次换掉还没开始播放的音频源，原始数据一直留在注册表里
+        assert!(queue.replace_current(id, samples(4)));
+        assert!(queue.replace_current(id, samples(2)));
+        assert!(!queue.replace_current(id + 1, samples(2)));
+        assert_eq!(queue.list().len(), 2);
+        assert_eq!(source.next(), Some(0.0));
+        assert_eq!(queue.list().len(), 1);
+        tokio::task::yield_now().await;
+        assert_eq!(manager.peek(id).await, Some(data));
+
+        // 播放完后从注册表里删除
+        assert_eq!(source.count(), 1);
+        tokio::task::yield_now().await;
+        assert_eq!(manager.peek(id).await, None);
+    }
+}
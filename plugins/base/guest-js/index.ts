@@ -1,16 +1,147 @@
-import { invoke } from '@tauri-apps/api/tauri';
+import { listen, type UnlistenFn } from '@tauri-apps/api/event';
+import { invoke, transformCallback } from '@tauri-apps/api/tauri';
+
+// 应用目录里neotool.app.sig的签名状态，key是base64的ed25519公钥
+export type SignatureStatus =
+  | { status: 'unsigned' }
+  | { status: 'trusted'; key: string }
+  | { status: 'untrusted'; key: string }
+  | { status: 'invalid'; message: string };
 
 export type AppConfig = {
+  manifestVersion: number;
   id: string;
   name: string;
+  // version和minNeotoolVersion都是semver
+  version?: string;
+  minNeotoolVersion?: string;
+  author?: string;
+  homepage?: string;
+  icon?: string;
+  // 例如keyboard.simulate、secrets:<service>、fs:<绝对路径>，fs:*为任意路径，
+  // fs:$APP/<相对路径>为应用目录下的路径
+  permissions: string[];
   path: string;
   description?: string;
   entry: string;
   css?: string;
+  signature: SignatureStatus;
+};
+
+// 应用目录没有被加载的原因，line和column从1开始
+export type AppProblem =
+  | { type: 'missingManifest' }
+  | { type: 'io'; message: string }
+  | { type: 'invalidJson'; message: string; line: number; column: number }
+  | { type: 'missingField'; field: string }
+  | { type: 'invalidField'; field: string; message: string }
+  | { type: 'fileNotFound'; field: string; file: string }
+  | { type: 'unsupportedManifestVersion'; version: number }
+  | { type: 'incompatibleHost'; required: string; current: string }
+  // path是已经加载的id相同的应用目录
+  | { type: 'duplicateId'; id: string; path: string }
+  | { type: 'notTrusted'; signature: SignatureStatus };
+
+export type AppDiagnostic = {
+  path: string;
+  problem: AppProblem;
 };
 
-export async function loadAppsConfig(appsDir: string): Promise<AppConfig[]> {
-  return await invoke('plugin:acfunlive-neotool-base|load_apps_config', { appsDir });
+export type AppsDiscovery = {
+  apps: AppConfig[];
+  diagnostics: AppDiagnostic[];
+};
+
+// neotool在加载应用之前获取token，界面重新加载之前只能获取一次
+export async function neotoolToken(): Promise<string> {
+  return await invoke('plugin:acfunlive-neotool-base|neotool_token');
+}
+
+// neotool加载应用时给应用发放token，应用用它调用需要权限的命令，之前发放的token会失效
+export async function appToken(token: string, appId: string): Promise<string> {
+  return await invoke('plugin:acfunlive-neotool-base|app_token', { token, appId });
+}
+
+// 只有neotool可以调用，没有设置文件时返回null，应用不能读取和修改neotool的设置文件
+export async function loadNeotoolConfig(token: string): Promise<unknown> {
+  return await invoke('plugin:acfunlive-neotool-base|load_neotool_config', { token });
+}
+
+export async function saveNeotoolConfig(token: string, config: unknown): Promise<void> {
+  await invoke('plugin:acfunlive-neotool-base|save_neotool_config', { token, config });
+}
+
+// 只有neotool可以调用，一个应用出错不影响其它应用，出错的应用目录在diagnostics里，
+// 应用目录在启动时从neotool的设置文件的appsDir读取，修改后要重启才生效，
+// 验证签名用的trustedKeys和requireSigned从neotool的设置文件读取
+export async function loadAppsConfig(token: string): Promise<AppsDiscovery> {
+  return await invoke('plugin:acfunlive-neotool-base|load_apps_config', { token });
+}
+
+const appEvent = 'acfunlive-neotool-base:app';
+
+// appChanged为应用目录里的文件或者应用的配置发生变化
+export type AppEvent =
+  | { type: 'appAdded'; config: AppConfig }
+  | { type: 'appChanged'; config: AppConfig }
+  | { type: 'appRemoved'; id: string; path: string };
+
+// 只有neotool可以调用，监听应用目录，应用的文件变化时发送AppEvent，之前的监听会被停止，开发应用时使用
+export async function watchApps(token: string): Promise<void> {
+  await invoke('plugin:acfunlive-neotool-base|watch_apps', { token });
+}
+
+export async function unwatchApps(token: string): Promise<void> {
+  await invoke('plugin:acfunlive-neotool-base|unwatch_apps', { token });
+}
+
+export async function listenAppEvents(handler: (event: AppEvent) => void): Promise<UnlistenFn> {
+  return await listen<AppEvent>(appEvent, (event) => handler(event.payload));
+}
+
+// 只有neotool可以调用，从zip或tar.gz安装应用，已经安装时升级，应用目录里的data目录会保留，
+// pkg可以是本地路径或者http(s)地址，从地址下载时必须给出sha256
+export async function installApp(token: string, pkg: string, sha256?: string): Promise<AppConfig> {
+  return await invoke('plugin:acfunlive-neotool-base|install_app', { token, package: pkg, sha256 });
+}
+
+export async function uninstallApp(token: string, appId: string): Promise<void> {
+  await invoke('plugin:acfunlive-neotool-base|uninstall_app', { token, appId });
+}
+
+export type IndexVersion = {
+  version: string;
+  // 索引里的相对路径会被改为绝对路径或者绝对地址，远程索引里不是http(s)地址的版本会被忽略
+  url: string;
+  sha256: string;
+  minNeotoolVersion?: string;
+};
+
+export type IndexApp = {
+  id: string;
+  name?: string;
+  description?: string;
+  versions: IndexVersion[];
+};
+
+export type AppIndex = {
+  apps: IndexApp[];
+};
+
+export type AppUpdate = {
+  id: string;
+  current?: string;
+  latest: IndexVersion;
+};
+
+// 只有neotool可以调用，index可以是http(s)地址、file://地址或者本地路径
+export async function loadAppIndex(token: string, index: string): Promise<AppIndex> {
+  return await invoke('plugin:acfunlive-neotool-base|load_app_index', { token, index });
+}
+
+// 只有neotool可以调用，返回有新版本的已经安装的应用，不兼容当前neotool的版本会被忽略
+export async function checkAppUpdates(token: string, index: string): Promise<AppUpdate[]> {
+  return await invoke('plugin:acfunlive-neotool-base|check_app_updates', { token, index });
 }
 
 export async function canonicalize(path: string): Promise<string> {
@@ -21,8 +152,47 @@ export async function hashFileSha256(path: string): Promise<string> {
   return await invoke('plugin:acfunlive-neotool-base|hash_file_sha256', { path });
 }
 
-export async function symlinkDir(source: string, destination: string): Promise<void> {
-  return await invoke('plugin:acfunlive-neotool-base|symlink_dir', { source, destination });
+// 需要destination的fs权限，source和destination都要在应用目录或者应用数据目录里，
+// destination是真实的文件或目录时只有force为true才会被删除
+export async function symlinkDir(
+  token: string,
+  source: string,
+  destination: string,
+  force = false
+): Promise<void> {
+  return await invoke('plugin:acfunlive-neotool-base|symlink_dir', {
+    token,
+    source,
+    destination,
+    force
+  });
+}
+
+export type PermissionStatus = {
+  permission: string;
+  description: string;
+  // undefined为用户还没做出选择
+  granted?: boolean;
+};
+
+// 只有neotool可以调用，token是neotoolToken返回的token
+export async function appPermissions(token: string, appId: string): Promise<PermissionStatus[]> {
+  return await invoke('plugin:acfunlive-neotool-base|get_app_permissions', { token, appId });
+}
+
+// granted为undefined时下次使用权限会重新请求用户同意
+export async function setAppPermission(
+  token: string,
+  appId: string,
+  permission: string,
+  granted?: boolean
+): Promise<void> {
+  await invoke('plugin:acfunlive-neotool-base|set_app_permission', {
+    token,
+    appId,
+    permission,
+    granted
+  });
 }
 
 type SecretKey = {
@@ -31,12 +201,39 @@ type SecretKey = {
   target?: string;
 };
 
+// 系统密钥环不可用时使用加密文件，需要先用unlockSecrets解锁
+export type SecretsStatus = { backend: 'keyring' } | { backend: 'file'; locked: boolean };
+
+export type SecretName = {
+  service: string;
+  user: string;
+};
+
+export async function secretsStatus(): Promise<SecretsStatus> {
+  return await invoke('plugin:acfunlive-neotool-base|secrets_status');
+}
+
+// 只有neotool可以调用，没有加密文件时用这个密码新建
+export async function unlockSecrets(token: string, passphrase: string): Promise<void> {
+  await invoke('plugin:acfunlive-neotool-base|unlock_secrets', { token, passphrase });
+}
+
+// 只能列出token对应的应用自己保存的密钥
+export async function listSecretKeys(token: string): Promise<SecretName[]> {
+  return await invoke('plugin:acfunlive-neotool-base|list_secret_keys', { token });
+}
+
+// 需要secrets:<service>权限，token是neotool加载应用时传入的token，
+// 密钥按应用id分开保存，不同应用不能读取对方的密钥，
+// 旧版本没有按应用分开保存的密钥在第一次读取时移到读取的应用下
 export class SecretKeyEntry {
+  readonly token: string;
   readonly service: string;
   readonly user: string;
   readonly target?: string;
 
-  constructor(service: string, user: string, target?: string) {
+  constructor(token: string, service: string, user: string, target?: string) {
+    this.token = token;
     this.service = service;
     this.user = user;
     this.target = target;
@@ -51,14 +248,91 @@ export class SecretKeyEntry {
   }
 
   async get_data(): Promise<string | undefined> {
-    return await invoke('plugin:acfunlive-neotool-base|get_secret_key', { key: this.toType() });
+    return await invoke('plugin:acfunlive-neotool-base|get_secret_key', {
+      token: this.token,
+      key: this.toType()
+    });
   }
 
   async set_data(content: string): Promise<void> {
-    await invoke('plugin:acfunlive-neotool-base|set_secret_key', { key: this.toType(), content });
+    await invoke('plugin:acfunlive-neotool-base|set_secret_key', {
+      token: this.token,
+      key: this.toType(),
+      content
+    });
   }
 
   async delete_data(): Promise<void> {
-    await invoke('plugin:acfunlive-neotool-base|delete_secret_key', { key: this.toType() });
+    await invoke('plugin:acfunlive-neotool-base|delete_secret_key', {
+      token: this.token,
+      key: this.toType()
+    });
+  }
+}
+
+// 数据被删除时value为null
+export type StoreChange = {
+  key: string;
+  value: unknown;
+};
+
+// 按应用id分开保存的数据，值可以是任意JSON，token是neotool加载应用时传入的token，
+// 应用重新加载后之前的监听会被停止
+export class AppStore {
+  readonly token: string;
+
+  constructor(token: string) {
+    this.token = token;
+  }
+
+  async get<T>(key: string): Promise<T | undefined> {
+    const value: T | null = await invoke('plugin:acfunlive-neotool-base|store_get', {
+      token: this.token,
+      key
+    });
+
+    return value ?? undefined;
+  }
+
+  async set(key: string, value: unknown): Promise<void> {
+    await invoke('plugin:acfunlive-neotool-base|store_set', { token: this.token, key, value });
+  }
+
+  // 返回数据是否存在
+  async delete(key: string): Promise<boolean> {
+    return await invoke('plugin:acfunlive-neotool-base|store_delete', { token: this.token, key });
+  }
+
+  async list(): Promise<string[]> {
+    return await invoke('plugin:acfunlive-neotool-base|store_list', { token: this.token });
+  }
+
+  // key为undefined时监听所有的数据，返回的函数用来停止监听
+  async watch(
+    handler: (change: StoreChange) => void,
+    key?: string
+  ): Promise<() => Promise<void>> {
+    const id: number = await invoke('plugin:acfunlive-neotool-base|store_watch', {
+      token: this.token,
+      key,
+      callback: transformCallback(handler)
+    });
+
+    return async () => {
+      await invoke('plugin:acfunlive-neotool-base|store_unwatch', { token: this.token, id });
+    };
+  }
+
+  async export(): Promise<Record<string, unknown>> {
+    return await invoke('plugin:acfunlive-neotool-base|store_export', { token: this.token });
+  }
+
+  // replace为true时先删除所有的数据
+  async import(data: Record<string, unknown>, replace = false): Promise<void> {
+    await invoke('plugin:acfunlive-neotool-base|store_import', {
+      token: this.token,
+      data,
+      replace
+    });
   }
 }
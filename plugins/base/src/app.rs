@@ -0,0 +1,667 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Component, Path},
+};
+
+use acfunlive_neotool_permission::{Permission, PermissionManager};
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::fs::{metadata, read_dir, read_to_string};
+
+use crate::{
+    package::DATA_DIR,
+    signature::{self, SignaturePolicy, SignatureStatus},
+    Result,
+};
+
+pub(crate) const CONFIG_FILE: &str = "neotool.app.json";
+
+// 必须有而且不能为空的字段
+const REQUIRED_FIELDS: [&str; 3] = ["id", "name", "entry"];
+
+/// 支持的最新的配置格式版本
+pub(crate) const MANIFEST_VERSION: u32 = 1;
+
+const MAX_ID_LEN: usize = 64;
+
+#[inline]
+fn default_manifest_version() -> u32 {
+    MANIFEST_VERSION
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct AppConfig {
+    /// 配置格式的版本，没有时为1
+    #[serde(default = "default_manifest_version")]
+    pub(crate) manifest_version: u32,
+    pub(crate) id: String,
+    pub(crate) name: String,
+    /// 应用的版本，必须是semver
+    pub(crate) version: Option<String>,
+    /// 需要的最低的neotool版本，必须是semver
+    pub(crate) min_neotool_version: Option<String>,
+    pub(crate) author: Option<String>,
+    pub(crate) homepage: Option<String>,
+    /// 图标文件，相对于应用目录
+    pub(crate) icon: Option<String>,
+    /// 应用需要的权限，使用时会请求用户同意
+    #[serde(default)]
+    pub(crate) permissions: Vec<String>,
+    #[serde(default)]
+    pub(crate) path: String,
+    pub(crate) description: Option<String>,
+    pub(crate) entry: String,
+    pub(crate) css: Option<String>,
+    /// 应用的签名状态，不从配置读取
+    #[serde(default, skip_deserializing)]
+    pub(crate) signature: SignatureStatus,
+}
+
+/// 应用目录没有被加载的原因
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub(crate) enum Problem {
+    /// 没有`neotool.app.json`
+    MissingManifest,
+    /// 读取文件或目录出错
+    Io { message: String },
+    /// JSON格式错误或者字段类型错误，行和列从1开始
+    InvalidJson {
+        message: String,
+        line: usize,
+        column: usize,
+    },
+    /// 缺少必须的字段或者字段为空
+    MissingField { field: String },
+    /// 字段的值不符合要求
+    InvalidField { field: String, message: String },
+    /// `entry`、`css`或`icon`指定的文件不存在
+    FileNotFound { field: String, file: String },
+    /// 配置格式的版本比支持的新
+    UnsupportedManifestVersion { version: u32 },
+    /// 需要更新的neotool
+    IncompatibleHost { required: String, current: String },
+    /// 和另一个应用目录的应用id相同，`path`是那个应用目录
+    DuplicateId { id: String, path: String },
+    /// 要求签名时应用没有被信任的公钥签名
+    NotTrusted { signature: SignatureStatus },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingManifest => write!(f, "missing {}", CONFIG_FILE),
+            Self::Io { message } => write!(f, "{}", message),
+            Self::InvalidJson { message, .. } => write!(f, "invalid {}: {}", CONFIG_FILE, message),
+            Self::MissingField { field } => write!(f, "missing field {}", field),
+            Self::InvalidField { field, message } => write!(f, "invalid {}: {}", field, message),
+            Self::FileNotFound { field, file } => write!(f, "{} file not found: {}", field, file),
+            Self::UnsupportedManifestVersion { version } => {
+                write!(f, "unsupported manifest version {}", version)
+            }
+            Self::IncompatibleHost { required, current } => write!(
+                f,
+                "requires neotool {}, current version is {}",
+                required, current
+            ),
+            Self::DuplicateId { id, path } => {
+                write!(f, "app id {} is already used by {}", id, path)
+            }
+            Self::NotTrusted { signature } => match signature {
+                SignatureStatus::Unsigned => write!(f, "app is not signed"),
+                SignatureStatus::Untrusted { key } => {
+                    write!(f, "app is signed by untrusted key {}", key)
+                }
+                SignatureStatus::Invalid { message } => {
+                    write!(f, "invalid signature: {}", message)
+                }
+                SignatureStatus::Trusted { key } => write!(f, "app is signed by {}", key),
+            },
+        }
+    }
+}
+
+impl Problem {
+    #[inline]
+    fn invalid_field(field: &str, message: impl ToString) -> Self {
+        Self::InvalidField {
+            field: field.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<std::io::Error> for Problem {
+    #[inline]
+    fn from(e: std::io::Error) -> Self {
+        Self::Io {
+            message: e.to_string(),
+        }
+    }
+}
+
+impl From<serde_json::Error> for Problem {
+    #[inline]
+    fn from(e: serde_json::Error) -> Self {
+        Self::InvalidJson {
+            message: e.to_string(),
+            line: e.line(),
+            column: e.column(),
+        }
+    }
+}
+
+/// 一个应用目录的问题
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct AppDiagnostic {
+    path: String,
+    problem: Problem,
+}
+
+/// 加载的应用和没有加载的应用目录的问题
+#[derive(Clone, Debug, Default, Serialize)]
+pub(crate) struct AppsDiscovery {
+    pub(crate) apps: Vec<AppConfig>,
+    diagnostics: Vec<AppDiagnostic>,
+}
+
+/// 文件必须在应用目录里而且不能在`data`目录里，这些地方之外的文件没有被签名
+async fn check_file(dir: &Path, field: &str, file: &str) -> std::result::Result<(), Problem> {
+    let path = Path::new(file);
+    if !path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(Problem::invalid_field(
+            field,
+            format!("not a relative path inside the app directory: {}", file),
+        ));
+    }
+    let first = path.components().find(|c| !matches!(c, Component::CurDir));
+    if matches!(first, Some(Component::Normal(first)) if first == DATA_DIR) {
+        return Err(Problem::invalid_field(
+            field,
+            format!("file in the data directory: {}", file),
+        ));
+    }
+
+    let not_found = || Problem::FileNotFound {
+        field: field.to_string(),
+        file: file.to_string(),
+    };
+    let dir = tokio::fs::canonicalize(dir).await?;
+    let real = tokio::fs::canonicalize(dir.join(path))
+        .await
+        .map_err(|_| not_found())?;
+    // 符号链接可以指向应用目录外面或者`data`目录里
+    if !real.starts_with(&dir) || real.starts_with(dir.join(DATA_DIR)) {
+        return Err(Problem::invalid_field(
+            field,
+            format!("resolves outside the app directory: {}", file),
+        ));
+    }
+    match metadata(&real).await {
+        Ok(data) if data.is_file() => Ok(()),
+        _ => Err(not_found()),
+    }
+}
+
+/// 应用id只能包含ASCII字母、数字、`_`、`-`和`.`，不能以`.`开头
+pub(crate) fn check_id(id: &str) -> std::result::Result<(), Problem> {
+    if id.len() > MAX_ID_LEN {
+        return Err(Problem::invalid_field(
+            "id",
+            format!("longer than {} characters", MAX_ID_LEN),
+        ));
+    }
+    if id.starts_with('.')
+        || !id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        return Err(Problem::invalid_field("id", format!("invalid id: {}", id)));
+    }
+
+    Ok(())
+}
+
+#[inline]
+fn parse_version(field: &str, version: &str) -> std::result::Result<Version, Problem> {
+    Version::parse(version).map_err(|e| Problem::invalid_field(field, e))
+}
+
+/// 检查版本、兼容性和其它字段
+async fn check_manifest(
+    dir: &Path,
+    config: &AppConfig,
+    host_version: &Version,
+) -> std::result::Result<(), Problem> {
+    if config.manifest_version > MANIFEST_VERSION {
+        return Err(Problem::UnsupportedManifestVersion {
+            version: config.manifest_version,
+        });
+    }
+    check_id(&config.id)?;
+    if let Some(version) = &config.version {
+        parse_version("version", version)?;
+    }
+    if let Some(required) = &config.min_neotool_version {
+        if &parse_version("minNeotoolVersion", required)? > host_version {
+            return Err(Problem::IncompatibleHost {
+                required: required.clone(),
+                current: host_version.to_string(),
+            });
+        }
+    }
+    if let Some(homepage) = &config.homepage {
+        if !homepage.starts_with("https://") && !homepage.starts_with("http://") {
+            return Err(Problem::invalid_field(
+                "homepage",
+                "not a http or https URL",
+            ));
+        }
+    }
+    if let Some(icon) = &config.icon {
+        check_file(dir, "icon", icon).await?;
+    }
+    for permission in &config.permissions {
+        Permission::parse_for_app(permission, dir)
+            .map_err(|e| Problem::invalid_field("permissions", e))?;
+    }
+
+    Ok(())
+}
+
+/// 读取并检查应用目录里的配置和签名
+pub(crate) async fn load_app(
+    dir: &Path,
+    host_version: &Version,
+    policy: &SignaturePolicy,
+) -> std::result::Result<AppConfig, Problem> {
+    let path = dir.join(CONFIG_FILE);
+    let data = match read_to_string(&path).await {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(Problem::MissingManifest),
+        Err(e) => return Err(e.into()),
+    };
+
+    // 先检查JSON格式和必须的字段，再检查字段的类型
+    if let Value::Object(map) = serde_json::from_str(&data)? {
+        for field in REQUIRED_FIELDS {
+            let missing = match map.get(field) {
+                None | Some(Value::Null) => true,
+                Some(Value::String(s)) => s.is_empty(),
+                Some(_) => false,
+            };
+            if missing {
+                return Err(Problem::MissingField {
+                    field: field.to_string(),
+                });
+            }
+        }
+    }
+    let mut config: AppConfig = serde_json::from_str(&data)?;
+    if config.css.as_deref() == Some("") {
+        return Err(Problem::MissingField {
+            field: "css".to_string(),
+        });
+    }
+
+    check_manifest(dir, &config, host_version).await?;
+    check_file(dir, "entry", &config.entry).await?;
+    if let Some(css) = &config.css {
+        check_file(dir, "css", css).await?;
+    }
+
+    config.signature = {
+        let (dir, policy) = (dir.to_path_buf(), policy.clone());
+        tauri::async_runtime::spawn_blocking(move || signature::verify(&dir, &policy))
+            .await
+            .map_err(|e| Problem::Io {
+                message: e.to_string(),
+            })?
+    };
+    if policy.require_signed && !matches!(config.signature, SignatureStatus::Trusted { .. }) {
+        return Err(Problem::NotTrusted {
+            signature: config.signature,
+        });
+    }
+
+    config.path = tokio::fs::canonicalize(dir)
+        .await?
+        .to_string_lossy()
+        .into_owned();
+
+    Ok(config)
+}
+
+/// 登记应用申请的权限，`fs:$APP/<path>`展开成应用目录下的路径
+pub(crate) fn declare_permissions(config: &AppConfig) {
+    // 和`symlink_dir`检查权限时的路径一样去掉Windows的`\\?\`前缀
+    let dir = dunce::simplified(Path::new(&config.path));
+    PermissionManager::global().declare(
+        &config.id,
+        dir.to_path_buf(),
+        config
+            .permissions
+            .iter()
+            .filter_map(|p| Permission::parse_for_app(p, dir).ok())
+            .collect(),
+    );
+}
+
+/// 加载应用目录下所有的应用，一个应用出错不影响其它应用。
+/// 应用id重复时按目录的路径排序，只加载第一个。加载的应用申请的权限会被登记。
+pub(crate) async fn discover_apps(
+    apps_dir: &str,
+    host_version: &Version,
+    policy: &SignaturePolicy,
+) -> Result<AppsDiscovery> {
+    let mut read_dir = read_dir(apps_dir).await?;
+    let mut discovery = AppsDiscovery::default();
+    loop {
+        let entry = match read_dir.next_entry().await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => break,
+            // 读取目录出错时已经读到的应用仍然会被加载
+            Err(e) => {
+                discovery.diagnostics.push(AppDiagnostic {
+                    path: apps_dir.to_string(),
+                    problem: e.into(),
+                });
+                break;
+            }
+        };
+        // 以`.`开头的是安装应用时的临时目录
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        let result = match entry.file_type().await {
+            Ok(file_type) if file_type.is_dir() => load_app(&path, host_version, policy).await,
+            Ok(_) => continue,
+            Err(e) => Err(e.into()),
+        };
+        match result {
+            Ok(config) => discovery.apps.push(config),
+            Err(problem) => discovery.diagnostics.push(AppDiagnostic {
+                path: path.to_string_lossy().into_owned(),
+                problem,
+            }),
+        }
+    }
+
+    discovery.apps.sort_by(|a, b| a.path.cmp(&b.path));
+    let mut ids: HashMap<String, String> = HashMap::new();
+    let mut apps = Vec::with_capacity(discovery.apps.len());
+    for config in discovery.apps {
+        match ids.get(&config.id) {
+            Some(path) => discovery.diagnostics.push(AppDiagnostic {
+                problem: Problem::DuplicateId {
+                    id: config.id.clone(),
+                    path: path.clone(),
+                },
+                path: config.path,
+            }),
+            None => {
+                ids.insert(config.id.clone(), config.path.clone());
+                apps.push(config);
+            }
+        }
+    }
+    discovery.apps = apps;
+
+    for config in &discovery.apps {
+        declare_permissions(config);
+    }
+
+    Ok(discovery)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("neotool-app-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_app(dir: &Path, manifest: &str) {
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(dir.join(CONFIG_FILE), manifest).unwrap();
+        std::fs::write(dir.join("index.js"), "").unwrap();
+    }
+
+    fn manifest(id: &str, extra: &str) -> String {
+        format!(r#"{{"id": "{id}", "name": "test", "entry": "index.js"{extra}}}"#)
+    }
+
+    fn config(extra: &str) -> AppConfig {
+        serde_json::from_str(&manifest("test", extra)).unwrap()
+    }
+
+    #[test]
+    fn test_check_id() {
+        assert!(check_id("acfunlive-neotool.test_app-1").is_ok());
+        assert!(check_id(&"a".repeat(MAX_ID_LEN)).is_ok());
+        assert!(check_id(&"a".repeat(MAX_ID_LEN + 1)).is_err());
+        assert!(check_id(".test").is_err());
+        for id in ["test/app", "test\\app", "test app", "测试"] {
+            assert!(
+                matches!(check_id(id), Err(Problem::InvalidField { .. })),
+                "{}",
+                id
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_manifest() {
+        let dir = temp_dir("manifest");
+        let host_version = Version::new(0, 2, 0);
+        let check = |extra: &'static str| {
+            let dir = dir.clone();
+            let host_version = host_version.clone();
+            async move { check_manifest(&dir, &config(extra), &host_version).await }
+        };
+
+        assert!(check("").await.is_ok());
+        assert!(check(r#", "version": "1.0.0-beta.1""#).await.is_ok());
+        assert!(matches!(
+            check(r#", "version": "1.0""#).await,
+            Err(Problem::InvalidField { field, .. }) if field == "version"
+        ));
+        assert!(matches!(
+            check(r#", "manifestVersion": 2"#).await,
+            Err(Problem::UnsupportedManifestVersion { version: 2 })
+        ));
+
+        assert!(check(r#", "minNeotoolVersion": "0.2.0""#).await.is_ok());
+        assert!(check(r#", "minNeotoolVersion": "0.1.5""#).await.is_ok());
+        assert!(matches!(
+            check(r#", "minNeotoolVersion": "0.2.1""#).await,
+            Err(Problem::IncompatibleHost { required, current })
+                if required == "0.2.1" && current == "0.2.0"
+        ));
+        assert!(matches!(
+            check(r#", "minNeotoolVersion": "latest""#).await,
+            Err(Problem::InvalidField { field, .. }) if field == "minNeotoolVersion"
+        ));
+
+        assert!(check(r#", "homepage": "https://www.acfun.cn""#)
+            .await
+            .is_ok());
+        assert!(check(r#", "homepage": "http://localhost:8080""#)
+            .await
+            .is_ok());
+        for homepage in ["javascript:alert(1)", "file:///etc/passwd", "www.acfun.cn"] {
+            let extra = format!(r#", "homepage": "{homepage}""#);
+            assert!(matches!(
+                check_manifest(&dir, &config(&extra), &host_version).await,
+                Err(Problem::InvalidField { field, .. }) if field == "homepage"
+            ));
+        }
+
+        assert!(matches!(
+            check(r#", "icon": "icon.png""#).await,
+            Err(Problem::FileNotFound { field, .. }) if field == "icon"
+        ));
+        std::fs::write(dir.join("icon.png"), "").unwrap();
+        assert!(check(r#", "icon": "icon.png""#).await.is_ok());
+        assert!(check(r#", "icon": "./icon.png""#).await.is_ok());
+
+        // 应用目录外和`data`目录里的文件没有被签名
+        std::fs::create_dir_all(dir.join(DATA_DIR)).unwrap();
+        std::fs::write(dir.join(DATA_DIR).join("icon.png"), "").unwrap();
+        let outside = dir.with_extension("outside.png");
+        std::fs::write(&outside, "").unwrap();
+        let absolute = format!(
+            r#", "icon": {}"#,
+            serde_json::to_string(&outside.to_string_lossy()).unwrap()
+        );
+        let relative = format!(
+            r#", "icon": "../{}""#,
+            outside.file_name().unwrap().to_string_lossy()
+        );
+        for extra in [
+            absolute.as_str(),
+            relative.as_str(),
+            r#", "icon": "data/icon.png""#,
+            r#", "icon": "./data/icon.png""#,
+        ] {
+            assert!(
+                matches!(
+                    check_manifest(&dir, &config(extra), &host_version).await,
+                    Err(Problem::InvalidField { field, .. }) if field == "icon"
+                ),
+                "{}",
+                extra
+            );
+        }
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&outside, dir.join("link.png")).unwrap();
+            std::os::unix::fs::symlink(dir.join(DATA_DIR), dir.join("assets")).unwrap();
+            for extra in [r#", "icon": "link.png""#, r#", "icon": "assets/icon.png""#] {
+                assert!(matches!(
+                    check_manifest(&dir, &config(extra), &host_version).await,
+                    Err(Problem::InvalidField { field, .. }) if field == "icon"
+                ));
+            }
+        }
+        std::fs::remove_file(&outside).unwrap();
+
+        check(r#", "permissions": ["fs:$APP/web"]"#).await.unwrap();
+        for extra in [
+            r#", "permissions": ["unknown"]"#,
+            r#", "permissions": ["fs:$APP/../web"]"#,
+        ] {
+            assert!(matches!(
+                check(extra).await,
+                Err(Problem::InvalidField { field, .. }) if field == "permissions"
+            ));
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_discover_apps() {
+        let dir = temp_dir("discover");
+        let host_version = Version::new(0, 2, 0);
+        let policy = SignaturePolicy::default();
+
+        write_app(&dir.join("a"), &manifest("test.app", ""));
+        write_app(&dir.join("b"), &manifest("test.app", ""));
+        write_app(
+            &dir.join("c"),
+            &manifest("test.other", r#", "permissions": ["fs:$APP/web"]"#),
+        );
+        write_app(&dir.join("invalid-json"), "{");
+        write_app(
+            &dir.join("missing-field"),
+            r#"{"id": "test.missing", "name": ""}"#,
+        );
+        write_app(
+            &dir.join("missing-entry"),
+            r#"{"id": "test.entry", "name": "test", "entry": "main.js"}"#,
+        );
+        std::fs::create_dir(dir.join("empty")).unwrap();
+        // 安装时的临时目录和文件会被忽略
+        write_app(&dir.join(".staging"), &manifest("test.staging", ""));
+        std::fs::write(dir.join("readme.txt"), "").unwrap();
+
+        let discovery = discover_apps(&dir.to_string_lossy(), &host_version, &policy)
+            .await
+            .unwrap();
+        let apps: Vec<_> = discovery
+            .apps
+            .iter()
+            .map(|c| (c.id.as_str(), Path::new(&c.path).file_name().unwrap()))
+            .collect();
+        assert_eq!(
+            apps,
+            [("test.app", "a".as_ref()), ("test.other", "c".as_ref())]
+        );
+        // `fs:$APP`按应用目录展开后登记
+        let declared = PermissionManager::global()
+            .permissions("test.other")
+            .unwrap();
+        assert_eq!(
+            declared[0].permission,
+            format!(
+                "fs:{}",
+                Path::new(&discovery.apps[1].path).join("web").display()
+            )
+        );
+
+        let problems: HashMap<_, _> = discovery
+            .diagnostics
+            .iter()
+            .map(|d| {
+                (
+                    Path::new(&d.path)
+                        .file_name()
+                        .unwrap()
+                        .to_string_lossy()
+                        .into_owned(),
+                    &d.problem,
+                )
+            })
+            .collect();
+        assert_eq!(problems.len(), 5);
+        assert!(matches!(
+            problems["b"],
+            Problem::DuplicateId { id, path } if id == "test.app" && path.ends_with('a')
+        ));
+        assert!(matches!(
+            problems["invalid-json"],
+            Problem::InvalidJson { line: 1, .. }
+        ));
+        assert!(matches!(
+            problems["missing-field"],
+            Problem::MissingField { field } if field == "name"
+        ));
+        assert!(matches!(
+            problems["missing-entry"],
+            Problem::FileNotFound { field, .. } if field == "entry"
+        ));
+        assert!(matches!(problems["empty"], Problem::MissingManifest));
+
+        assert!(
+            discover_apps(&dir.join("none").to_string_lossy(), &host_version, &policy)
+                .await
+                .is_err()
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
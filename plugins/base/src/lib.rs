@@ -1,14 +1,54 @@
+mod app;
+mod link;
+mod package;
+mod registry;
+mod secret;
+mod signature;
+mod store;
+mod watch;
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use acfunlive_neotool_permission::{Permission, PermissionManager, PermissionStatus};
 use serde::{ser::Serializer, Deserialize, Serialize};
+use serde_json::Value;
 use tauri::{
+    api::{
+        dialog::blocking::ask,
+        ipc::{format_callback, CallbackFn},
+    },
     command,
     plugin::{Builder, TauriPlugin},
-    Runtime,
+    AppHandle, Manager, Runtime, State, Window,
 };
-use tokio::fs::{read_dir, read_to_string, remove_dir_all, remove_file, symlink_metadata};
+
+use app::{discover_apps, AppConfig, AppsDiscovery, Problem};
+use link::LinkScope;
+use registry::{AppIndex, AppUpdate};
+use secret::{SecretKey, SecretManager, SecretName, SecretsStatus};
+use signature::SignaturePolicy;
+use store::{Store, StoreManager, WatchId};
+use watch::AppsWatcher;
 
 type Result<T> = std::result::Result<T, Error>;
 
-const CONFIG_FILE: &str = "neotool.app.json";
+// 保存用户对应用权限的选择的文件
+const PERMISSIONS_FILE: &str = "permissions.json";
+
+// neotool界面所在的窗口
+const HOST_WINDOW: &str = "main";
+
+// 保存应用数据的目录
+const STORE_DIR: &str = "store";
+
+// neotool界面的设置文件
+const NEOTOOL_CONFIG_FILE: &str = "neotool.conf.json";
+
+// 设置里没有应用目录时使用的目录，和界面一样相对于当前目录
+const DEFAULT_APPS_DIR: &str = "apps";
 
 #[allow(clippy::enum_variant_names)]
 #[derive(Debug, thiserror::Error)]
@@ -19,6 +59,55 @@ enum Error {
     SerdeJsonError(#[from] serde_json::Error),
     #[error(transparent)]
     KeyringError(#[from] keyring::Error),
+    #[error(transparent)]
+    PermissionError(#[from] acfunlive_neotool_permission::Error),
+    #[error("invalid path: {0}")]
+    InvalidPath(String),
+    #[error(transparent)]
+    TauriError(#[from] tauri::Error),
+    #[error(transparent)]
+    ZipError(#[from] zip::result::ZipError),
+    #[error("unsupported package: {0}")]
+    UnsupportedPackage(String),
+    #[error("unsafe entry in package: {0}")]
+    UnsafeEntry(String),
+    #[error("package is larger than {0} bytes after extraction")]
+    PackageTooLarge(u64),
+    #[error("invalid app package: {0}")]
+    InvalidPackage(Problem),
+    #[error("SHA-256 mismatch, expected {expected}, got {actual}")]
+    HashMismatch { expected: String, actual: String },
+    #[error("app not found: {0}")]
+    AppNotFound(String),
+    #[error("app directory already exists: {0}")]
+    AppDirExists(String),
+    #[error(transparent)]
+    TauriApiError(#[from] tauri::api::Error),
+    #[error("HTTP status {0}")]
+    HttpStatus(u16),
+    #[error("invalid URL: {0}")]
+    InvalidUrl(String),
+    #[error("SHA-256 is required to install from {0}")]
+    MissingHash(String),
+    #[error("invalid app id: {0}")]
+    InvalidAppId(String),
+    #[error("encrypted secrets file is locked")]
+    SecretsLocked,
+    #[error("wrong passphrase")]
+    WrongPassphrase,
+    #[error("corrupted secrets file: {0}")]
+    CorruptedSecrets(String),
+    #[error(transparent)]
+    NotifyError(#[from] notify::Error),
+    #[error("path is outside the allowed directories: {0}")]
+    PathNotAllowed(String),
+    #[error("destination is not a symlink, use force to replace it: {0}")]
+    DestinationExists(String),
+    #[error("signed app contains a symlink: {0}")]
+    SymlinkInApp(String),
+    #[cfg(windows)]
+    #[error("no privilege to create symlink and failed to create junction: {0}")]
+    JunctionFailed(std::io::Error),
 }
 
 impl Serialize for Error {
@@ -31,43 +120,255 @@ impl Serialize for Error {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-struct AppConfig {
-    id: String,
-    name: String,
-    #[serde(default)]
-    path: String,
-    description: Option<String>,
-    entry: String,
-    css: Option<String>,
-}
-
-#[command]
-async fn load_apps_config(apps_dir: String) -> Result<Vec<AppConfig>> {
-    let mut read_dir = read_dir(&apps_dir).await?;
-    let mut configs = Vec::new();
-    while let Ok(Some(entry)) = read_dir.next_entry().await {
-        if entry.file_type().await?.is_dir() {
-            let mut path = entry.path();
-            let dir = tokio::fs::canonicalize(&path)
-                .await?
-                .to_string_lossy()
-                .into_owned();
-            path.push(CONFIG_FILE);
-            let data = read_to_string(&path).await?;
-            let mut config: AppConfig = serde_json::from_str(&data)?;
-            if !config.id.is_empty()
-                && !config.name.is_empty()
-                && !config.entry.is_empty()
-                && !config.css.as_deref().unwrap_or(" ").is_empty()
-            {
-                config.path = dir;
-                configs.push(config);
-            }
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NeotoolConfig {
+    apps_dir: Option<String>,
+    #[serde(flatten)]
+    policy: SignaturePolicy,
+}
+
+/// 从neotool界面的设置读取应用目录，启动后不再改变
+fn configured_apps_dir(config_dir: Option<&Path>) -> PathBuf {
+    let config: NeotoolConfig = config_dir
+        .and_then(|dir| std::fs::read_to_string(dir.join(NEOTOOL_CONFIG_FILE)).ok())
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default();
+    let apps_dir = PathBuf::from(
+        config
+            .apps_dir
+            .filter(|dir| !dir.is_empty())
+            .unwrap_or_else(|| DEFAULT_APPS_DIR.to_string()),
+    );
+    if apps_dir.is_relative() {
+        if let Ok(current_dir) = std::env::current_dir() {
+            return current_dir.join(apps_dir);
         }
     }
 
-    Ok(configs)
+    apps_dir
+}
+
+// 启动时设置的应用目录，不使用界面传入的目录
+#[inline]
+fn apps_dir() -> PathBuf {
+    PermissionManager::global()
+        .apps_dir()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_APPS_DIR))
+}
+
+/// 从neotool界面的设置读取验证签名用的设置，每次使用时读取。
+/// 设置文件损坏时返回错误，不能因为读不到`requireSigned`而加载没有签名的应用。
+async fn signature_policy(config_dir: Option<&Path>) -> Result<SignaturePolicy> {
+    let path = match config_dir {
+        Some(dir) => dir.join(NEOTOOL_CONFIG_FILE),
+        None => return Ok(SignaturePolicy::default()),
+    };
+    match tokio::fs::read(path).await {
+        Ok(data) => Ok(serde_json::from_slice::<NeotoolConfig>(&data)?.policy),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(SignaturePolicy::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// 验证签名用的设置，加上应用数据目录
+async fn app_signature_policy<R: Runtime>(app: &AppHandle<R>) -> Result<SignaturePolicy> {
+    let resolver = app.path_resolver();
+    let mut policy = signature_policy(resolver.app_config_dir().as_deref()).await?;
+    policy.data_dir = resolver.app_data_dir();
+
+    Ok(policy)
+}
+
+// 只有neotool界面可以调用的命令要检查窗口和token
+#[inline]
+fn check_host<R: Runtime>(window: &Window<R>, token: &str) -> Result<()> {
+    if window.label() != HOST_WINDOW {
+        return Err(acfunlive_neotool_permission::Error::NotHost.into());
+    }
+
+    Ok(PermissionManager::global().check_host(token)?)
+}
+
+/// neotool界面在加载应用之前获取token，界面重新加载之前只能获取一次
+#[command]
+#[inline]
+fn neotool_token<R: Runtime>(window: Window<R>) -> Result<String> {
+    if window.label() != HOST_WINDOW {
+        return Err(acfunlive_neotool_permission::Error::NotHost.into());
+    }
+
+    Ok(PermissionManager::global().issue_host_token()?)
+}
+
+/// 加载应用时给应用发放token，应用用它调用需要权限的命令
+#[command]
+#[inline]
+fn app_token<R: Runtime>(
+    window: Window<R>,
+    store: State<'_, StoreManager>,
+    token: String,
+    app_id: String,
+) -> Result<String> {
+    check_host(&window, &token)?;
+    let token = PermissionManager::global().issue_token(&app_id)?;
+    // 之前的token失效了，用它监听的数据也不再通知
+    store.unwatch_app(&app_id);
+
+    Ok(token)
+}
+
+// 设置文件在fs的scope之外，应用不能读取和修改
+fn neotool_config_file<R: Runtime>(app: &AppHandle<R>) -> Result<PathBuf> {
+    app.path_resolver()
+        .app_config_dir()
+        .map(|dir| dir.join(NEOTOOL_CONFIG_FILE))
+        .ok_or_else(|| Error::InvalidPath(NEOTOOL_CONFIG_FILE.to_string()))
+}
+
+/// 读取neotool界面的设置，没有设置文件时返回`null`
+#[command]
+#[inline]
+async fn load_neotool_config<R: Runtime>(
+    app: AppHandle<R>,
+    window: Window<R>,
+    token: String,
+) -> Result<Value> {
+    check_host(&window, &token)?;
+    match tokio::fs::read(neotool_config_file(&app)?).await {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Value::Null),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// 先写临时文件再改名，避免写到一半时设置文件损坏
+#[command]
+#[inline]
+async fn save_neotool_config<R: Runtime>(
+    app: AppHandle<R>,
+    window: Window<R>,
+    token: String,
+    config: Value,
+) -> Result<()> {
+    check_host(&window, &token)?;
+    let path = neotool_config_file(&app)?;
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    tokio::fs::write(&tmp, serde_json::to_vec_pretty(&config)?).await?;
+    tokio::fs::rename(tmp, path).await?;
+
+    Ok(())
+}
+
+#[command]
+#[inline]
+async fn load_apps_config<R: Runtime>(
+    app: AppHandle<R>,
+    window: Window<R>,
+    token: String,
+) -> Result<AppsDiscovery> {
+    check_host(&window, &token)?;
+    let policy = app_signature_policy(&app).await?;
+    discover_apps(
+        &apps_dir().to_string_lossy(),
+        &app.package_info().version,
+        &policy,
+    )
+    .await
+}
+
+/// 监听应用目录，应用的文件变化时发送事件，开发应用时使用
+#[command]
+#[inline]
+async fn watch_apps<R: Runtime>(
+    app: AppHandle<R>,
+    window: Window<R>,
+    watcher: State<'_, AppsWatcher>,
+    token: String,
+) -> Result<()> {
+    check_host(&window, &token)?;
+    let policy = app_signature_policy(&app).await?;
+    watcher.watch(app, &apps_dir(), policy).await
+}
+
+#[command]
+#[inline]
+fn unwatch_apps<R: Runtime>(
+    window: Window<R>,
+    watcher: State<'_, AppsWatcher>,
+    token: String,
+) -> Result<()> {
+    check_host(&window, &token)?;
+    watcher.unwatch();
+
+    Ok(())
+}
+
+/// 从zip或tar.gz安装或升级应用，`package`是http(s)地址时先下载
+#[command]
+#[inline]
+async fn install_app<R: Runtime>(
+    app: AppHandle<R>,
+    window: Window<R>,
+    token: String,
+    package: String,
+    sha256: Option<String>,
+) -> Result<AppConfig> {
+    check_host(&window, &token)?;
+    let (apps_dir, host_version) = (&apps_dir(), &app.package_info().version);
+    let policy = app_signature_policy(&app).await?;
+    if registry::is_remote(&package) {
+        package::install_url(apps_dir, &package, sha256.as_deref(), host_version, &policy).await
+    } else {
+        package::install(
+            apps_dir,
+            Path::new(&package),
+            sha256.as_deref(),
+            host_version,
+            &policy,
+        )
+        .await
+    }
+}
+
+#[command]
+#[inline]
+async fn uninstall_app<R: Runtime>(window: Window<R>, token: String, app_id: String) -> Result<()> {
+    check_host(&window, &token)?;
+    package::uninstall(&apps_dir(), &app_id).await
+}
+
+/// 读取应用索引
+#[command]
+#[inline]
+async fn load_app_index<R: Runtime>(
+    window: Window<R>,
+    token: String,
+    index: String,
+) -> Result<AppIndex> {
+    check_host(&window, &token)?;
+    registry::load_index(&index).await
+}
+
+/// 比较应用目录里的应用和应用索引，返回可以更新的应用
+#[command]
+#[inline]
+async fn check_app_updates<R: Runtime>(
+    app: AppHandle<R>,
+    window: Window<R>,
+    token: String,
+    index: String,
+) -> Result<Vec<AppUpdate>> {
+    check_host(&window, &token)?;
+    let version = &app.package_info().version;
+    let policy = app_signature_policy(&app).await?;
+    let discovery = discover_apps(&apps_dir().to_string_lossy(), version, &policy).await?;
+    let index = registry::load_index(&index).await?;
+
+    Ok(registry::check_updates(&discovery.apps, &index, version))
 }
 
 #[command]
@@ -84,69 +385,223 @@ async fn hash_file_sha256(path: String) -> Result<String> {
     Ok(sha256::try_async_digest(path).await?)
 }
 
+/// `destination`是真实的文件或目录时，只有`force`为`true`才会被删除
 #[command]
 #[inline]
-async fn symlink_dir(source: String, destination: String) -> Result<()> {
-    if let Ok(data) = symlink_metadata(&destination).await {
-        let src = tokio::fs::canonicalize(&source).await?;
-        let dst = tokio::fs::canonicalize(&destination).await?;
-        if src == dst {
-            return Ok(());
-        }
+async fn symlink_dir(
+    scope: State<'_, LinkScope>,
+    token: String,
+    source: String,
+    destination: String,
+    force: Option<bool>,
+) -> Result<()> {
+    let path = link::resolve_parent(Path::new(&destination)).await?;
+    PermissionManager::global()
+        .authorize(&token, &Permission::fs(path))
+        .await?;
 
-        if data.is_dir() {
-            remove_dir_all(&destination).await?;
-        } else {
-            remove_file(&destination).await?;
-        }
-    }
+    link::symlink_dir(
+        &scope,
+        Path::new(&source),
+        Path::new(&destination),
+        force.unwrap_or_default(),
+    )
+    .await
+}
 
-    #[cfg(unix)]
-    tokio::fs::symlink(source, destination).await?;
+#[command]
+#[inline]
+async fn get_secret_key(
+    manager: State<'_, SecretManager>,
+    token: String,
+    key: SecretKey,
+) -> Result<Option<String>> {
+    let app_id = key.authorize(&token).await?;
+    manager.get(&app_id, &key).await
+}
 
-    #[cfg(windows)]
-    tokio::fs::symlink_dir(source, destination).await?;
+#[command]
+#[inline]
+async fn set_secret_key(
+    manager: State<'_, SecretManager>,
+    token: String,
+    key: SecretKey,
+    content: String,
+) -> Result<()> {
+    let app_id = key.authorize(&token).await?;
+    manager.set(&app_id, &key, &content).await
+}
 
-    Ok(())
+#[command]
+#[inline]
+async fn delete_secret_key(
+    manager: State<'_, SecretManager>,
+    token: String,
+    key: SecretKey,
+) -> Result<()> {
+    let app_id = key.authorize(&token).await?;
+    manager.delete(&app_id, &key).await
 }
 
-#[derive(Clone, Debug, Deserialize)]
-struct SecretKey {
-    service: String,
-    user: String,
-    target: Option<String>,
+/// 应用自己保存的密钥的名字
+#[command]
+#[inline]
+async fn list_secret_keys(
+    manager: State<'_, SecretManager>,
+    token: String,
+) -> Result<Vec<SecretName>> {
+    let app_id = PermissionManager::global().app_id(&token)?;
+    manager.list(&app_id).await
 }
 
-impl SecretKey {
-    #[inline]
-    fn entry(&self) -> Result<keyring::Entry> {
-        Ok(match &self.target {
-            Some(target) => keyring::Entry::new_with_target(target, &self.service, &self.user)?,
-            None => keyring::Entry::new(&self.service, &self.user)?,
+#[command]
+#[inline]
+async fn secrets_status(manager: State<'_, SecretManager>) -> Result<SecretsStatus> {
+    manager.status().await
+}
+
+/// 系统密钥环不可用时用密码解锁加密文件，只有neotool界面可以调用
+#[command]
+#[inline]
+async fn unlock_secrets<R: Runtime>(
+    window: Window<R>,
+    manager: State<'_, SecretManager>,
+    token: String,
+    passphrase: String,
+) -> Result<()> {
+    check_host(&window, &token)?;
+    manager.unlock(passphrase).await
+}
+
+#[command]
+#[inline]
+fn get_app_permissions<R: Runtime>(
+    window: Window<R>,
+    token: String,
+    app_id: String,
+) -> Result<Vec<PermissionStatus>> {
+    check_host(&window, &token)?;
+
+    Ok(PermissionManager::global().permissions(&app_id)?)
+}
+
+#[command]
+#[inline]
+fn set_app_permission<R: Runtime>(
+    window: Window<R>,
+    token: String,
+    app_id: String,
+    permission: String,
+    granted: Option<bool>,
+) -> Result<()> {
+    check_host(&window, &token)?;
+
+    Ok(PermissionManager::global().set_granted(&app_id, &permission, granted)?)
+}
+
+/// 数据按token对应的应用分开保存
+#[command]
+#[inline]
+async fn store_get(
+    manager: State<'_, StoreManager>,
+    token: String,
+    key: String,
+) -> Result<Option<Value>> {
+    let app_id = PermissionManager::global().app_id(&token)?;
+    manager
+        .read(&app_id, |store| store.get(&key).cloned())
+        .await
+}
+
+#[command]
+#[inline]
+async fn store_set(
+    manager: State<'_, StoreManager>,
+    token: String,
+    key: String,
+    value: Value,
+) -> Result<()> {
+    let app_id = PermissionManager::global().app_id(&token)?;
+    manager
+        .update(&app_id, |store| {
+            store.insert(key, value);
         })
-    }
+        .await
 }
 
+/// 返回数据是否存在
 #[command]
 #[inline]
-fn get_secret_key(key: SecretKey) -> Result<Option<String>> {
-    match key.entry()?.get_password() {
-        Ok(s) => Ok(Some(s)),
-        Err(keyring::Error::NoEntry) => Ok(None),
-        Err(e) => Err(Error::from(e)),
-    }
+async fn store_delete(
+    manager: State<'_, StoreManager>,
+    token: String,
+    key: String,
+) -> Result<bool> {
+    let app_id = PermissionManager::global().app_id(&token)?;
+    manager
+        .update(&app_id, |store| store.remove(&key).is_some())
+        .await
 }
 
 #[command]
 #[inline]
-fn set_secret_key(key: SecretKey, content: String) -> Result<()> {
-    Ok(key.entry()?.set_password(&content)?)
+async fn store_list(manager: State<'_, StoreManager>, token: String) -> Result<Vec<String>> {
+    let app_id = PermissionManager::global().app_id(&token)?;
+    manager
+        .read(&app_id, |store| store.keys().cloned().collect())
+        .await
+}
+
+#[command]
+fn store_watch<R: Runtime>(
+    window: Window<R>,
+    manager: State<'_, StoreManager>,
+    token: String,
+    key: Option<String>,
+    callback: CallbackFn,
+) -> Result<WatchId> {
+    let app_id = PermissionManager::global().app_id(&token)?;
+    manager.watch(&app_id, key, move |change| {
+        if let Ok(js) = format_callback(callback, change) {
+            let _ = window.eval(&js);
+        }
+    })
 }
 
 #[command]
 #[inline]
-fn delete_secret_key(key: SecretKey) -> Result<()> {
-    Ok(key.entry()?.delete_password()?)
+fn store_unwatch(manager: State<'_, StoreManager>, token: String, id: WatchId) -> Result<()> {
+    let app_id = PermissionManager::global().app_id(&token)?;
+    manager.unwatch(&app_id, id);
+
+    Ok(())
+}
+
+#[command]
+#[inline]
+async fn store_export(manager: State<'_, StoreManager>, token: String) -> Result<Store> {
+    let app_id = PermissionManager::global().app_id(&token)?;
+    manager.read(&app_id, |store| store.clone()).await
+}
+
+/// `replace`为`true`时先删除所有的数据
+#[command]
+#[inline]
+async fn store_import(
+    manager: State<'_, StoreManager>,
+    token: String,
+    data: Store,
+    replace: bool,
+) -> Result<()> {
+    let app_id = PermissionManager::global().app_id(&token)?;
+    manager
+        .update(&app_id, |store| {
+            if replace {
+                store.clear();
+            }
+            store.extend(data);
+        })
+        .await
 }
 
 /// Initializes the plugin.
@@ -154,13 +609,158 @@ fn delete_secret_key(key: SecretKey) -> Result<()> {
 pub fn init<R: Runtime>() -> TauriPlugin<R> {
     Builder::new("acfunlive-neotool-base")
         .invoke_handler(tauri::generate_handler![
+            neotool_token,
+            app_token,
+            load_neotool_config,
+            save_neotool_config,
             load_apps_config,
+            watch_apps,
+            unwatch_apps,
+            install_app,
+            uninstall_app,
+            load_app_index,
+            check_app_updates,
             canonicalize,
             hash_file_sha256,
             symlink_dir,
             get_secret_key,
             set_secret_key,
-            delete_secret_key
+            delete_secret_key,
+            list_secret_keys,
+            secrets_status,
+            unlock_secrets,
+            get_app_permissions,
+            set_app_permission,
+            store_get,
+            store_set,
+            store_delete,
+            store_list,
+            store_watch,
+            store_unwatch,
+            store_export,
+            store_import
         ])
+        .setup(|app| {
+            let config_dir = app.path_resolver().app_config_dir();
+            app.manage(StoreManager::new(
+                config_dir.as_ref().map(|dir| dir.join(STORE_DIR)),
+            ));
+            app.manage(SecretManager::new(config_dir.clone()));
+            app.manage(AppsWatcher::default());
+            let apps_dir = configured_apps_dir(config_dir.as_deref());
+            let scope = LinkScope::default();
+            if let Some(dir) = app.path_resolver().app_data_dir() {
+                scope.allow(dir);
+            }
+            // 应用可以在应用目录里创建符号链接
+            scope.allow(apps_dir.clone());
+            app.manage(scope);
+
+            let manager = PermissionManager::global();
+            manager.set_apps_dir(apps_dir);
+            if let Some(dir) = config_dir {
+                // 读取失败时不保存用户的选择
+                if std::fs::create_dir_all(&dir).is_ok() {
+                    let _ = manager.set_storage(dir.join(PERMISSIONS_FILE));
+                }
+            }
+            let app = app.app_handle();
+            manager.set_prompt(Arc::new(move |app_id: &str, permission: &Permission| {
+                ask(
+                    app.get_window("main").as_ref(),
+                    "权限申请",
+                    format!(
+                        "应用{}申请{}（{}），是否允许？",
+                        app_id,
+                        permission.description(),
+                        permission
+                    ),
+                )
+            }));
+
+            Ok(())
+        })
+        .on_page_load(|window, _| {
+            // 界面重新加载后之前的应用都不存在了
+            if window.label() == HOST_WINDOW {
+                PermissionManager::global().revoke_all();
+                window.state::<StoreManager>().unwatch_all();
+            }
+        })
         .build()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_configured_apps_dir() {
+        let current_dir = std::env::current_dir().unwrap();
+        assert_eq!(
+            configured_apps_dir(None),
+            current_dir.join(DEFAULT_APPS_DIR)
+        );
+
+        let config_dir =
+            std::env::temp_dir().join(format!("neotool-config-{}", std::process::id()));
+        std::fs::create_dir_all(&config_dir).unwrap();
+        let config_file = config_dir.join(NEOTOOL_CONFIG_FILE);
+
+        std::fs::write(&config_file, r#"{"appsDir": "./my-apps", "appData": {}}"#).unwrap();
+        assert_eq!(
+            configured_apps_dir(Some(&config_dir)),
+            current_dir.join("./my-apps")
+        );
+
+        let absolute = std::env::temp_dir().join("neotool-apps");
+        std::fs::write(
+            &config_file,
+            serde_json::to_vec(&serde_json::json!({ "appsDir": absolute })).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(configured_apps_dir(Some(&config_dir)), absolute);
+
+        // 设置文件损坏时使用默认的目录
+        std::fs::write(&config_file, "{").unwrap();
+        assert_eq!(
+            configured_apps_dir(Some(&config_dir)),
+            current_dir.join(DEFAULT_APPS_DIR)
+        );
+
+        std::fs::remove_dir_all(&config_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_signature_policy() {
+        let policy = signature_policy(None).await.unwrap();
+        assert!(policy.trusted_keys.is_empty());
+        assert!(!policy.require_signed);
+
+        let config_dir =
+            std::env::temp_dir().join(format!("neotool-policy-{}", std::process::id()));
+        std::fs::create_dir_all(&config_dir).unwrap();
+        let config_file = config_dir.join(NEOTOOL_CONFIG_FILE);
+        assert!(
+            !signature_policy(Some(&config_dir))
+                .await
+                .unwrap()
+                .require_signed
+        );
+
+        std::fs::write(
+            &config_file,
+            r#"{"appsDir": "./apps", "trustedKeys": ["key"], "requireSigned": true}"#,
+        )
+        .unwrap();
+        let policy = signature_policy(Some(&config_dir)).await.unwrap();
+        assert_eq!(policy.trusted_keys, ["key"]);
+        assert!(policy.require_signed);
+
+        // 设置文件损坏时不能当成不要求签名
+        std::fs::write(&config_file, r#"{"requireSigned": true"#).unwrap();
+        assert!(signature_policy(Some(&config_dir)).await.is_err());
+
+        std::fs::remove_dir_all(&config_dir).unwrap();
+    }
+}
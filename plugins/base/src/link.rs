@@ -0,0 +1,216 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use tokio::fs::{canonicalize, remove_dir_all, remove_file, symlink_metadata};
+
+use crate::{Error, Result};
+
+// 没有创建符号链接的权限时Windows返回的错误码
+#[cfg(windows)]
+const ERROR_PRIVILEGE_NOT_HELD: i32 = 1314;
+
+/// 允许创建符号链接的目录，例如应用目录和应用数据目录
+#[derive(Default)]
+pub(crate) struct LinkScope(Mutex<Vec<PathBuf>>);
+
+#[inline]
+async fn normalize(path: &Path) -> Result<PathBuf> {
+    Ok(dunce::simplified(&canonicalize(path).await?).to_path_buf())
+}
+
+/// 路径本身可能是符号链接，所以只对父目录做规范化
+pub(crate) async fn resolve_parent(path: &Path) -> Result<PathBuf> {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => Ok(normalize(parent).await?.join(name)),
+        _ => Err(Error::InvalidPath(path.to_string_lossy().into_owned())),
+    }
+}
+
+impl LinkScope {
+    #[inline]
+    pub(crate) fn allow(&self, root: impl Into<PathBuf>) {
+        let root = root.into();
+        let mut roots = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if !roots.contains(&root) {
+            roots.push(root);
+        }
+    }
+
+    // 路径必须在允许的目录里面，不能是允许的目录本身
+    async fn check(&self, path: &Path) -> Result<()> {
+        let roots = self.0.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        for root in roots {
+            // 还不存在的目录里面不会有要检查的路径
+            if let Ok(root) = normalize(&root).await {
+                if path != root && path.starts_with(&root) {
+                    return Ok(());
+                }
+            }
+        }
+
+        Err(Error::PathNotAllowed(path.to_string_lossy().into_owned()))
+    }
+}
+
+// Windows上目录的符号链接和junction要用`remove_dir`删除
+async fn remove_link(path: &Path) -> Result<()> {
+    #[cfg(windows)]
+    if tokio::fs::remove_dir(path).await.is_ok() {
+        return Ok(());
+    }
+    remove_file(path).await?;
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[inline]
+async fn create_link(source: &Path, destination: &Path) -> Result<()> {
+    Ok(tokio::fs::symlink(source, destination).await?)
+}
+
+#[cfg(windows)]
+async fn create_link(source: &Path, destination: &Path) -> Result<()> {
+    match tokio::fs::symlink_dir(source, destination).await {
+        Ok(()) => Ok(()),
+        // 没有开启开发者模式也不是管理员时改用junction
+        Err(e) if e.raw_os_error() == Some(ERROR_PRIVILEGE_NOT_HELD) => {
+            let (source, destination) = (source.to_path_buf(), destination.to_path_buf());
+            tauri::async_runtime::spawn_blocking(move || junction::create(source, destination))
+                .await?
+                .map_err(Error::JunctionFailed)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// 创建指向`source`的目录链接`destination`，两者都要在允许的目录里。
+/// `destination`是指向别处的链接时会被替换，是真实的文件或目录时只有`force`为`true`才会被删除。
+pub(crate) async fn symlink_dir(
+    scope: &LinkScope,
+    source: &Path,
+    destination: &Path,
+    force: bool,
+) -> Result<()> {
+    let source = normalize(source).await?;
+    scope.check(&source).await?;
+    let destination = resolve_parent(destination).await?;
+    scope.check(&destination).await?;
+    // 删除`destination`时会删除`source`
+    if source.starts_with(&destination) {
+        return Err(Error::InvalidPath(
+            destination.to_string_lossy().into_owned(),
+        ));
+    }
+
+    if let Ok(data) = symlink_metadata(&destination).await {
+        if !data.file_type().is_symlink() {
+            if !force {
+                return Err(Error::DestinationExists(
+                    destination.to_string_lossy().into_owned(),
+                ));
+            }
+            if data.is_dir() {
+                remove_dir_all(&destination).await?;
+            } else {
+                remove_file(&destination).await?;
+            }
+        } else if matches!(normalize(&destination).await, Ok(target) if target == source) {
+            return Ok(());
+        } else {
+            remove_link(&destination).await?;
+        }
+    }
+
+    create_link(&source, &destination).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_symlink_dir() {
+        use std::os::unix::fs::{symlink, MetadataExt};
+
+        let dir = std::env::temp_dir().join(format!("neotool-link-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (app, data, outside) = (dir.join("app"), dir.join("data"), dir.join("outside"));
+        let source = data.join("sticker");
+        fs::create_dir_all(&app).unwrap();
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(source.join("a.png"), "a").unwrap();
+        let scope = LinkScope::default();
+        scope.allow(&app);
+        scope.allow(&data);
+        let link = app.join("sticker");
+
+        // 源和链接都要在允许的目录里面，不能是允许的目录本身
+        for (source, destination) in [
+            (&outside, &link),
+            (&source, &outside.join("sticker")),
+            (&data, &link),
+        ] {
+            assert!(matches!(
+                symlink_dir(&scope, source, destination, true).await,
+                Err(Error::PathNotAllowed(_))
+            ));
+        }
+        assert!(fs::symlink_metadata(&link).is_err());
+
+        // 真实的目录没有`force`时保留
+        fs::create_dir(&link).unwrap();
+        fs::write(link.join("user.txt"), "user").unwrap();
+        assert!(matches!(
+            symlink_dir(&scope, &source, &link, false).await,
+            Err(Error::DestinationExists(_))
+        ));
+        assert_eq!(fs::read_to_string(link.join("user.txt")).unwrap(), "user");
+
+        symlink_dir(&scope, &source, &link, true).await.unwrap();
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read_to_string(link.join("a.png")).unwrap(), "a");
+        assert!(!source.join("user.txt").exists());
+
+        // 已经指向`source`时不重新创建
+        let inode = fs::symlink_metadata(&link).unwrap().ino();
+        symlink_dir(&scope, &source, &link, false).await.unwrap();
+        assert_eq!(fs::symlink_metadata(&link).unwrap().ino(), inode);
+        assert_eq!(
+            fs::read_link(&link).unwrap(),
+            fs::canonicalize(&source).unwrap()
+        );
+
+        // 指向别处或者已经失效的链接不需要`force`就会被替换
+        for stale in [outside.clone(), data.join("removed")] {
+            fs::remove_file(&link).unwrap();
+            symlink(&stale, &link).unwrap();
+            symlink_dir(&scope, &source, &link, false).await.unwrap();
+            assert_eq!(
+                fs::read_link(&link).unwrap(),
+                fs::canonicalize(&source).unwrap()
+            );
+        }
+        assert!(outside.exists());
+
+        // 删除`destination`会删除`source`
+        let nested = app.join("nested");
+        fs::create_dir_all(nested.join("inner")).unwrap();
+        assert!(matches!(
+            symlink_dir(&scope, &nested.join("inner"), &nested, true).await,
+            Err(Error::InvalidPath(_))
+        ));
+        assert!(nested.join("inner").is_dir());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,637 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use acfunlive_neotool_permission::PermissionManager;
+use flate2::read::GzDecoder;
+use semver::Version;
+use serde_json::Value;
+use tar::{Archive, EntryType};
+use tokio::fs::{read_dir, read_to_string, remove_dir_all, remove_file, write};
+use zip::ZipArchive;
+
+use crate::{
+    app::{declare_permissions, load_app, AppConfig, Problem, CONFIG_FILE},
+    hash_file_sha256, registry,
+    signature::SignaturePolicy,
+    Error, Result,
+};
+
+/// 应用目录里保存用户数据的子目录，升级时会保留
+pub(crate) const DATA_DIR: &str = "data";
+
+// 解压和升级时用的临时目录，以`.`开头的目录不会被当成应用
+const STAGING_PREFIX: &str = ".install-";
+
+const BACKUP_PREFIX: &str = ".backup-";
+
+const DOWNLOAD_PREFIX: &str = ".download-";
+
+// unix权限里文件类型的部分
+const MODE_TYPE_MASK: u32 = 0o170000;
+
+const MODE_SYMLINK: u32 = 0o120000;
+
+// 解压后所有文件的大小之和的上限，防止压缩炸弹
+const MAX_UNPACKED_SIZE: u64 = 512 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug)]
+enum PackageKind {
+    Zip,
+    TarGz,
+}
+
+impl PackageKind {
+    fn from_path(path: &Path) -> Result<Self> {
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if name.ends_with(".zip") {
+            Ok(Self::Zip)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Ok(Self::TarGz)
+        } else {
+            Err(Error::UnsupportedPackage(path.display().to_string()))
+        }
+    }
+
+    #[inline]
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Zip => "zip",
+            Self::TarGz => "tar.gz",
+        }
+    }
+}
+
+#[inline]
+fn temp_dir(apps_dir: &Path, prefix: &str) -> PathBuf {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+
+    apps_dir.join(format!("{}{}-{}", prefix, std::process::id(), nanos))
+}
+
+/// 解压zip，不允许路径超出`dest`和符号链接，解压的大小超过`limit`时返回错误
+fn extract_zip(package: &Path, dest: &Path, limit: u64) -> Result<()> {
+    let mut archive = ZipArchive::new(File::open(package)?)?;
+    let mut total = 0;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let path = match file.enclosed_name() {
+            Some(name) => dest.join(name),
+            None => return Err(Error::UnsafeEntry(file.name().to_string())),
+        };
+        if matches!(file.unix_mode(), Some(mode) if mode & MODE_TYPE_MASK == MODE_SYMLINK) {
+            return Err(Error::UnsafeEntry(file.name().to_string()));
+        }
+
+        if file.is_dir() {
+            fs::create_dir_all(&path)?;
+        } else {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            // 不相信zip里记录的大小，按实际解压的大小计算
+            let mut reader = file.by_ref().take(limit - total + 1);
+            total += io::copy(&mut reader, &mut File::create(&path)?)?;
+            if total > limit {
+                return Err(Error::PackageTooLarge(limit));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// 解压tar.gz，只解压普通文件和目录，解压的大小超过`limit`时返回错误
+fn extract_tar_gz(package: &Path, dest: &Path, limit: u64) -> Result<()> {
+    let mut archive = Archive::new(GzDecoder::new(File::open(package)?));
+    let mut total = 0u64;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?;
+        let name = path.to_string_lossy().into_owned();
+        // `unpack_in`会去掉绝对路径开头的`/`，和zip一样不允许绝对路径
+        if path
+            .components()
+            .any(|c| matches!(c, Component::Prefix(_) | Component::RootDir))
+        {
+            return Err(Error::UnsafeEntry(name));
+        }
+        match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Directory => {}
+            EntryType::XGlobalHeader | EntryType::XHeader => continue,
+            _ => return Err(Error::UnsafeEntry(name)),
+        }
+        // tar里记录的大小就是解压的大小
+        total = total.saturating_add(entry.size());
+        if total > limit {
+            return Err(Error::PackageTooLarge(limit));
+        }
+        // 路径超出`dest`时返回`false`
+        if !entry.unpack_in(dest)? {
+            return Err(Error::UnsafeEntry(name));
+        }
+    }
+
+    Ok(())
+}
+
+/// 应用可以在压缩包的根目录，也可以在压缩包里唯一的目录里
+fn package_root(staging: &Path) -> Result<PathBuf> {
+    if staging.join(CONFIG_FILE).is_file() {
+        return Ok(staging.to_path_buf());
+    }
+
+    let dirs: Vec<PathBuf> = fs::read_dir(staging)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_dir())
+        .collect();
+    match dirs.as_slice() {
+        [dir] if dir.join(CONFIG_FILE).is_file() => Ok(dir.clone()),
+        _ => Err(Error::InvalidPackage(Problem::MissingManifest)),
+    }
+}
+
+// 把旧版本的`data`目录移到新版本，替换新版本自带的
+fn keep_data(backup: &Path, target: &Path) -> Result<()> {
+    let data = backup.join(DATA_DIR);
+    if data.is_dir() {
+        let new_data = target.join(DATA_DIR);
+        if new_data.exists() {
+            fs::remove_dir_all(&new_data)?;
+        }
+        fs::rename(data, new_data)?;
+    }
+
+    Ok(())
+}
+
+/// 把解压好的应用移到`target`，`target`已经存在时为升级，出错时恢复旧版本
+fn replace_app(root: &Path, target: &Path, backup: &Path) -> Result<()> {
+    let upgrade = target.exists();
+    if upgrade {
+        fs::rename(target, backup)?;
+    }
+    if let Err(e) = fs::rename(root, target) {
+        if upgrade {
+            let _ = fs::rename(backup, target);
+        }
+        return Err(e.into());
+    }
+
+    if upgrade {
+        if let Err(e) = keep_data(backup, target) {
+            let _ = fs::remove_dir_all(target);
+            let _ = fs::rename(backup, target);
+            return Err(e);
+        }
+        let _ = fs::remove_dir_all(backup);
+    }
+
+    Ok(())
+}
+
+/// 找到已经安装的应用的目录，只比较配置里的id，不检查配置的其它部分
+pub(crate) async fn find_app(apps_dir: &Path, app_id: &str) -> Result<Option<PathBuf>> {
+    let mut read_dir = read_dir(apps_dir).await?;
+    while let Ok(Some(entry)) = read_dir.next_entry().await {
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        if let Ok(data) = read_to_string(path.join(CONFIG_FILE)).await {
+            if let Ok(value) = serde_json::from_str::<Value>(&data) {
+                if value.get("id").and_then(Value::as_str) == Some(app_id) {
+                    return Ok(Some(path));
+                }
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+async fn install_from(
+    apps_dir: &Path,
+    package: &Path,
+    staging: &Path,
+    host_version: &Version,
+    policy: &SignaturePolicy,
+) -> Result<AppConfig> {
+    let kind = PackageKind::from_path(package)?;
+    {
+        let (package, staging) = (package.to_path_buf(), staging.to_path_buf());
+        tauri::async_runtime::spawn_blocking(move || {
+            fs::create_dir_all(&staging)?;
+            match kind {
+                PackageKind::Zip => extract_zip(&package, &staging, MAX_UNPACKED_SIZE),
+                PackageKind::TarGz => extract_tar_gz(&package, &staging, MAX_UNPACKED_SIZE),
+            }
+        })
+        .await??;
+    }
+
+    let root = package_root(staging)?;
+    let config = load_app(&root, host_version, policy)
+        .await
+        .map_err(Error::InvalidPackage)?;
+    let target = match find_app(apps_dir, &config.id).await? {
+        Some(dir) => dir,
+        None => {
+            let dir = apps_dir.join(&config.id);
+            if dir.exists() {
+                return Err(Error::AppDirExists(dir.display().to_string()));
+            }
+            dir
+        }
+    };
+
+    {
+        let target = target.clone();
+        let backup = temp_dir(apps_dir, BACKUP_PREFIX);
+        tauri::async_runtime::spawn_blocking(move || replace_app(&root, &target, &backup))
+            .await??;
+    }
+
+    let config = load_app(&target, host_version, policy)
+        .await
+        .map_err(Error::InvalidPackage)?;
+    declare_permissions(&config);
+
+    Ok(config)
+}
+
+/// 从zip或tar.gz安装应用，已经安装时升级并保留应用目录里的`data`目录。
+/// 给出`sha256`时先检查压缩包的SHA-256，要求签名时不安装没有被信任的公钥签名的应用。
+pub(crate) async fn install(
+    apps_dir: &Path,
+    package: &Path,
+    sha256: Option<&str>,
+    host_version: &Version,
+    policy: &SignaturePolicy,
+) -> Result<AppConfig> {
+    if let Some(expected) = sha256 {
+        let actual = hash_file_sha256(package.to_string_lossy().into_owned()).await?;
+        if !actual.eq_ignore_ascii_case(expected) {
+            return Err(Error::HashMismatch {
+                expected: expected.to_string(),
+                actual,
+            });
+        }
+    }
+
+    // 解压失败时旧版本不受影响，只需要删除临时目录
+    let staging = temp_dir(apps_dir, STAGING_PREFIX);
+    let result = install_from(apps_dir, package, &staging, host_version, policy).await;
+    let _ = remove_dir_all(&staging).await;
+
+    result
+}
+
+/// 下载压缩包后安装，必须给出`sha256`，下载的文件在安装后删除
+pub(crate) async fn install_url(
+    apps_dir: &Path,
+    url: &str,
+    sha256: Option<&str>,
+    host_version: &Version,
+    policy: &SignaturePolicy,
+) -> Result<AppConfig> {
+    let sha256 = sha256.ok_or_else(|| Error::MissingHash(url.to_string()))?;
+    // 按地址里的文件名判断格式，不包括查询参数
+    let name = registry::parse_url(url)?
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .unwrap_or_default()
+        .to_string();
+    let kind = PackageKind::from_path(Path::new(&name))?;
+
+    let data = registry::fetch(url).await?;
+    let package = temp_dir(apps_dir, DOWNLOAD_PREFIX).with_extension(kind.extension());
+    write(&package, data).await?;
+    let result = install(apps_dir, &package, Some(sha256), host_version, policy).await;
+    let _ = remove_file(&package).await;
+
+    result
+}
+
+/// 删除应用目录，应用保存在其它地方的数据不会被删除
+pub(crate) async fn uninstall(apps_dir: &Path, app_id: &str) -> Result<()> {
+    let dir = find_app(apps_dir, app_id)
+        .await?
+        .ok_or_else(|| Error::AppNotFound(app_id.to_string()))?;
+    remove_dir_all(dir).await?;
+    PermissionManager::global().undeclare(app_id);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+    use tar::{Builder, Header};
+    use zip::{write::FileOptions, ZipWriter};
+
+    use super::*;
+
+    const MANIFEST: &str = r#"{"id": "test.package", "name": "test", "entry": "index.js"}"#;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("neotool-package-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // 清空解压的目录
+    fn reset(dest: &Path) {
+        let _ = fs::remove_dir_all(dest);
+        fs::create_dir_all(dest).unwrap();
+    }
+
+    fn zip_package(path: &Path, files: &[(&str, &[u8])], symlink: Option<&str>) {
+        let mut writer = ZipWriter::new(File::create(path).unwrap());
+        for (name, data) in files {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        if let Some(name) = symlink {
+            writer
+                .add_symlink(name, "../..", FileOptions::default())
+                .unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    fn tar_gz_package(path: &Path, entries: &[(&str, EntryType, &[u8])]) {
+        let encoder = GzEncoder::new(File::create(path).unwrap(), Compression::default());
+        let mut builder = Builder::new(encoder);
+        for (name, kind, data) in entries {
+            let mut header = Header::new_gnu();
+            // `set_path`不允许`..`和绝对路径，直接写入名字
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_entry_type(*kind);
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            if kind.is_symlink() {
+                header.set_link_name("../..").unwrap();
+            }
+            header.set_cksum();
+            builder.append(&header, *data).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+    }
+
+    #[test]
+    fn test_extract_zip() {
+        let dir = temp_dir("zip");
+        let package = dir.join("app.zip");
+        let dest = dir.join("dest");
+        reset(&dest);
+
+        zip_package(
+            &package,
+            &[
+                ("app/neotool.app.json", MANIFEST.as_bytes()),
+                ("app/index.js", b""),
+            ],
+            None,
+        );
+        extract_zip(&package, &dest, MAX_UNPACKED_SIZE).unwrap();
+        assert_eq!(package_root(&dest).unwrap(), dest.join("app"));
+        assert!(dest.join("app/index.js").is_file());
+
+        for name in ["../evil.js", "app/../../evil.js", "/evil.js"] {
+            reset(&dest);
+            zip_package(&package, &[(name, b"")], None);
+            assert!(
+                matches!(
+                    extract_zip(&package, &dest, MAX_UNPACKED_SIZE),
+                    Err(Error::UnsafeEntry(_))
+                ),
+                "{}",
+                name
+            );
+        }
+        assert!(!dir.join("evil.js").exists());
+
+        zip_package(&package, &[("index.js", b"")], Some("link"));
+        assert!(matches!(
+            extract_zip(&package, &dest, MAX_UNPACKED_SIZE),
+            Err(Error::UnsafeEntry(name)) if name == "link"
+        ));
+
+        // 压缩率很高的文件按解压后的大小计算
+        let data = vec![0; 4096];
+        zip_package(&package, &[("a.bin", &data), ("b.bin", &data)], None);
+        reset(&dest);
+        extract_zip(&package, &dest, 8192).unwrap();
+        assert!(matches!(
+            extract_zip(&package, &dest, 8191),
+            Err(Error::PackageTooLarge(8191))
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_extract_tar_gz() {
+        let dir = temp_dir("tar");
+        let package = dir.join("app.tar.gz");
+        let dest = dir.join("dest");
+        reset(&dest);
+
+        tar_gz_package(
+            &package,
+            &[
+                ("neotool.app.json", EntryType::Regular, MANIFEST.as_bytes()),
+                ("data/", EntryType::Directory, b""),
+                ("index.js", EntryType::Regular, b""),
+            ],
+        );
+        extract_tar_gz(&package, &dest, MAX_UNPACKED_SIZE).unwrap();
+        assert_eq!(package_root(&dest).unwrap(), dest);
+        assert!(dest.join("data").is_dir());
+
+        for (name, kind) in [
+            ("../evil.js", EntryType::Regular),
+            ("app/../../evil.js", EntryType::Regular),
+            ("/evil.js", EntryType::Regular),
+            ("link", EntryType::Symlink),
+            ("hardlink", EntryType::Link),
+        ] {
+            reset(&dest);
+            tar_gz_package(&package, &[(name, kind, b"")]);
+            assert!(
+                matches!(
+                    extract_tar_gz(&package, &dest, MAX_UNPACKED_SIZE),
+                    Err(Error::UnsafeEntry(_))
+                ),
+                "{}",
+                name
+            );
+        }
+        assert!(!dir.join("evil.js").exists());
+        assert!(!dest.join("evil.js").exists());
+
+        let data = vec![0; 4096];
+        tar_gz_package(
+            &package,
+            &[
+                ("a.bin", EntryType::Regular, &data),
+                ("b.bin", EntryType::Regular, &data),
+            ],
+        );
+        reset(&dest);
+        extract_tar_gz(&package, &dest, 8192).unwrap();
+        assert!(matches!(
+            extract_tar_gz(&package, &dest, 8191),
+            Err(Error::PackageTooLarge(8191))
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_replace_app() {
+        let dir = temp_dir("replace");
+        let (root, target, backup) = (dir.join("new"), dir.join("app"), dir.join("backup"));
+        fs::create_dir_all(target.join(DATA_DIR)).unwrap();
+        fs::write(target.join("index.js"), "old").unwrap();
+        fs::write(target.join(DATA_DIR).join("user.json"), "user").unwrap();
+
+        // 新版本不存在时恢复旧版本
+        assert!(replace_app(&root, &target, &backup).is_err());
+        assert_eq!(fs::read_to_string(target.join("index.js")).unwrap(), "old");
+        assert!(!backup.exists());
+
+        // 保留`data`目录失败时恢复旧版本
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("index.js"), "new").unwrap();
+        fs::write(root.join(DATA_DIR), "not a directory").unwrap();
+        assert!(replace_app(&root, &target, &backup).is_err());
+        assert_eq!(fs::read_to_string(target.join("index.js")).unwrap(), "old");
+        assert!(!backup.exists());
+
+        // 新版本自带的`data`目录被旧版本的替换
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("index.js"), "new").unwrap();
+        fs::create_dir_all(root.join(DATA_DIR)).unwrap();
+        fs::write(root.join(DATA_DIR).join("default.json"), "default").unwrap();
+        replace_app(&root, &target, &backup).unwrap();
+        assert_eq!(fs::read_to_string(target.join("index.js")).unwrap(), "new");
+        assert_eq!(
+            fs::read_to_string(target.join(DATA_DIR).join("user.json")).unwrap(),
+            "user"
+        );
+        assert!(!target.join(DATA_DIR).join("default.json").exists());
+        assert!(!root.exists());
+        assert!(!backup.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_install() {
+        let dir = temp_dir("install");
+        let apps_dir = dir.join("apps");
+        fs::create_dir_all(&apps_dir).unwrap();
+        let host_version = Version::new(0, 2, 0);
+        let policy = SignaturePolicy::default();
+
+        let v1 = dir.join("v1.tar.gz");
+        tar_gz_package(
+            &v1,
+            &[
+                ("neotool.app.json", EntryType::Regular, MANIFEST.as_bytes()),
+                ("index.js", EntryType::Regular, b"v1"),
+            ],
+        );
+        let config = install(&apps_dir, &v1, None, &host_version, &policy)
+            .await
+            .unwrap();
+        assert_eq!(config.id, "test.package");
+        let target = apps_dir.join("test.package");
+        fs::create_dir_all(target.join(DATA_DIR)).unwrap();
+        fs::write(target.join(DATA_DIR).join("user.json"), "user").unwrap();
+
+        let v2 = dir.join("v2.zip");
+        zip_package(
+            &v2,
+            &[
+                ("app/neotool.app.json", MANIFEST.as_bytes()),
+                ("app/index.js", b"v2"),
+                ("app/data/user.json", b"default"),
+            ],
+            None,
+        );
+        assert!(matches!(
+            install(&apps_dir, &v2, Some("00"), &host_version, &policy).await,
+            Err(Error::HashMismatch { .. })
+        ));
+        let sha256 = hash_file_sha256(v2.to_string_lossy().into_owned())
+            .await
+            .unwrap();
+        install(&apps_dir, &v2, Some(&sha256), &host_version, &policy)
+            .await
+            .unwrap();
+        assert_eq!(fs::read_to_string(target.join("index.js")).unwrap(), "v2");
+        assert_eq!(
+            fs::read_to_string(target.join(DATA_DIR).join("user.json")).unwrap(),
+            "user"
+        );
+
+        // 安装失败时不影响已经安装的版本，临时目录都被删除
+        let invalid = dir.join("invalid.zip");
+        zip_package(&invalid, &[("app/index.js", b"v3")], None);
+        assert!(matches!(
+            install(&apps_dir, &invalid, None, &host_version, &policy).await,
+            Err(Error::InvalidPackage(Problem::MissingManifest))
+        ));
+        assert_eq!(fs::read_to_string(target.join("index.js")).unwrap(), "v2");
+        let names: Vec<_> = fs::read_dir(&apps_dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["test.package"]);
+
+        // 下载之前检查SHA-256和格式
+        assert!(matches!(
+            install_url(
+                &apps_dir,
+                "https://example.com/app.zip",
+                None,
+                &host_version,
+                &policy
+            )
+            .await,
+            Err(Error::MissingHash(_))
+        ));
+        assert!(matches!(
+            install_url(
+                &apps_dir,
+                "https://example.com/app.rar?version=1.0.0.zip",
+                Some(&sha256),
+                &host_version,
+                &policy
+            )
+            .await,
+            Err(Error::UnsupportedPackage(_))
+        ));
+
+        uninstall(&apps_dir, "test.package").await.unwrap();
+        assert!(!target.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,333 @@
+use std::path::{Path, PathBuf};
+
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use tauri::{
+    api::http::{ClientBuilder, HttpRequestBuilder},
+    Url,
+};
+use tokio::fs::read;
+
+use crate::{app::AppConfig, Error, Result};
+
+const FILE_SCHEME: &str = "file://";
+
+/// 应用索引里一个应用的一个版本
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct IndexVersion {
+    /// 必须是semver，不是的版本会被忽略
+    pub(crate) version: String,
+    /// 压缩包的下载地址，本地索引里的相对路径相对于索引文件所在的目录，
+    /// 远程索引里的相对地址相对于索引的地址
+    pub(crate) url: String,
+    pub(crate) sha256: String,
+    pub(crate) min_neotool_version: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct IndexApp {
+    pub(crate) id: String,
+    pub(crate) name: Option<String>,
+    pub(crate) description: Option<String>,
+    #[serde(default)]
+    pub(crate) versions: Vec<IndexVersion>,
+}
+
+/// 应用索引
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct AppIndex {
+    #[serde(default)]
+    pub(crate) apps: Vec<IndexApp>,
+}
+
+/// 已经安装的应用可以更新到的版本
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct AppUpdate {
+    pub(crate) id: String,
+    /// 已经安装的版本，配置里没有版本时为`None`
+    pub(crate) current: Option<String>,
+    pub(crate) latest: IndexVersion,
+}
+
+#[inline]
+pub(crate) fn is_remote(source: &str) -> bool {
+    source.starts_with("https://") || source.starts_with("http://")
+}
+
+#[inline]
+pub(crate) fn parse_url(url: &str) -> Result<Url> {
+    Url::parse(url).map_err(|e| Error::InvalidUrl(format!("{}: {}", url, e)))
+}
+
+pub(crate) async fn fetch(url: &str) -> Result<Vec<u8>> {
+    let client = ClientBuilder::new().build()?;
+    let response = client.send(HttpRequestBuilder::new("GET", url)?).await?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(Error::HttpStatus(status.as_u16()));
+    }
+
+    Ok(response.bytes().await?.data)
+}
+
+// 本地索引里的相对路径改为绝对路径，这样可以直接用来安装
+fn resolve_urls(index: &mut AppIndex, dir: &Path) {
+    for version in index
+        .apps
+        .iter_mut()
+        .flat_map(|app| app.versions.iter_mut())
+    {
+        if is_remote(&version.url) {
+            continue;
+        }
+        let path = version
+            .url
+            .strip_prefix(FILE_SCHEME)
+            .unwrap_or(&version.url);
+        if Path::new(path).is_relative() {
+            version.url = dir.join(path).to_string_lossy().into_owned();
+        } else {
+            version.url = path.to_string();
+        }
+    }
+}
+
+// 远程索引里的相对地址改为绝对地址，远程索引不能指向本地文件，不是http(s)地址的版本会被忽略
+fn resolve_remote_urls(index: &mut AppIndex, base: &Url) {
+    for app in &mut index.apps {
+        app.versions = std::mem::take(&mut app.versions)
+            .into_iter()
+            .filter_map(|mut version| {
+                let url = base.join(&version.url).ok()?;
+                is_remote(url.as_str()).then(|| {
+                    version.url = url.into();
+                    version
+                })
+            })
+            .collect();
+    }
+}
+
+/// 读取应用索引，`source`可以是http(s)地址、`file://`地址或者本地路径
+pub(crate) async fn load_index(source: &str) -> Result<AppIndex> {
+    if is_remote(source) {
+        let base = parse_url(source)?;
+        let mut index: AppIndex = serde_json::from_slice(&fetch(source).await?)?;
+        resolve_remote_urls(&mut index, &base);
+        return Ok(index);
+    }
+
+    let path = PathBuf::from(source.strip_prefix(FILE_SCHEME).unwrap_or(source));
+    let mut index: AppIndex = serde_json::from_slice(&read(&path).await?)?;
+    let path = tokio::fs::canonicalize(path).await?;
+    if let Some(dir) = path.parent() {
+        resolve_urls(&mut index, dunce::simplified(dir));
+    }
+
+    Ok(index)
+}
+
+/// 应用可以安装的最新版本，不兼容当前neotool的版本会被忽略
+fn latest_version<'a>(
+    app: &'a IndexApp,
+    host_version: &Version,
+) -> Option<(Version, &'a IndexVersion)> {
+    app.versions
+        .iter()
+        .filter(|v| match v.min_neotool_version.as_deref() {
+            Some(required) => matches!(Version::parse(required), Ok(r) if &r <= host_version),
+            None => true,
+        })
+        .filter_map(|v| Version::parse(&v.version).ok().map(|version| (version, v)))
+        .max_by(|a, b| a.0.cmp(&b.0))
+}
+
+/// 比较已经安装的应用和应用索引，返回可以更新的应用
+pub(crate) fn check_updates(
+    installed: &[AppConfig],
+    index: &AppIndex,
+    host_version: &Version,
+) -> Vec<AppUpdate> {
+    installed
+        .iter()
+        .filter_map(|config| {
+            let app = index.apps.iter().find(|app| app.id == config.id)?;
+            let (latest, entry) = latest_version(app, host_version)?;
+            // 没有版本时总是可以更新
+            let newer = match config.version.as_deref().map(Version::parse) {
+                Some(Ok(current)) => latest > current,
+                _ => true,
+            };
+
+            newer.then(|| AppUpdate {
+                id: config.id.clone(),
+                current: config.version.clone(),
+                latest: entry.clone(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(version: &str, url: &str, min_neotool_version: Option<&str>) -> IndexVersion {
+        IndexVersion {
+            version: version.to_string(),
+            url: url.to_string(),
+            sha256: String::new(),
+            min_neotool_version: min_neotool_version.map(str::to_string),
+        }
+    }
+
+    fn index(id: &str, versions: Vec<IndexVersion>) -> AppIndex {
+        AppIndex {
+            apps: vec![IndexApp {
+                id: id.to_string(),
+                name: None,
+                description: None,
+                versions,
+            }],
+        }
+    }
+
+    fn installed(id: &str, version: Option<&str>) -> AppConfig {
+        let mut config = serde_json::json!({ "id": id, "name": "test", "entry": "index.js" });
+        if let Some(version) = version {
+            config["version"] = version.into();
+        }
+        serde_json::from_value(config).unwrap()
+    }
+
+    fn urls(index: &AppIndex) -> Vec<&str> {
+        index.apps[0]
+            .versions
+            .iter()
+            .map(|v| v.url.as_str())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_load_index() {
+        let dir = std::env::temp_dir().join(format!("neotool-index-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dunce::simplified(&std::fs::canonicalize(&dir).unwrap()).to_path_buf();
+        let absolute = dir.join("absolute.zip").to_string_lossy().into_owned();
+        let file = dir.join("index.json");
+        let index = index(
+            "test.app",
+            vec![
+                version("1.0.0", "pkgs/app-1.0.0.zip", None),
+                version("1.1.0", &absolute, None),
+                version("1.2.0", &format!("file://{}", absolute), None),
+                version("1.3.0", "https://example.com/app-1.3.0.zip", None),
+            ],
+        );
+        std::fs::write(&file, serde_json::to_vec(&index).unwrap()).unwrap();
+
+        let expected = [
+            dir.join("pkgs/app-1.0.0.zip")
+                .to_string_lossy()
+                .into_owned(),
+            absolute.clone(),
+            absolute.clone(),
+            "https://example.com/app-1.3.0.zip".to_string(),
+        ];
+        let index = load_index(&file.to_string_lossy()).await.unwrap();
+        assert_eq!(urls(&index), expected);
+        let index = load_index(&format!("file://{}", file.to_string_lossy()))
+            .await
+            .unwrap();
+        assert_eq!(urls(&index), expected);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_remote_urls() {
+        let mut index = index(
+            "test.app",
+            vec![
+                version("1.0.0", "pkgs/app-1.0.0.zip", None),
+                version("1.1.0", "/app-1.1.0.zip", None),
+                version("1.2.0", "https://cdn.example.com/app-1.2.0.zip", None),
+                version("1.3.0", "file:///etc/app-1.3.0.zip", None),
+            ],
+        );
+        resolve_remote_urls(
+            &mut index,
+            &Url::parse("https://example.com/neotool/index.json").unwrap(),
+        );
+        assert_eq!(
+            urls(&index),
+            [
+                "https://example.com/neotool/pkgs/app-1.0.0.zip",
+                "https://example.com/app-1.1.0.zip",
+                "https://cdn.example.com/app-1.2.0.zip",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_latest_version() {
+        let host_version = Version::new(0, 2, 0);
+        let index = index(
+            "test.app",
+            vec![
+                version("1.0.0", "1.0.0.zip", None),
+                version("1.10.0", "1.10.0.zip", Some("0.2.0")),
+                version("1.9.0", "1.9.0.zip", None),
+                version("2.0.0", "2.0.0.zip", Some("0.3.0")),
+                version("3.0", "3.0.zip", None),
+                version("4.0.0", "4.0.0.zip", Some("latest")),
+            ],
+        );
+        let (latest, entry) = latest_version(&index.apps[0], &host_version).unwrap();
+        assert_eq!(latest, Version::new(1, 10, 0));
+        assert_eq!(entry.url, "1.10.0.zip");
+        assert_eq!(
+            latest_version(&index.apps[0], &Version::new(0, 3, 0))
+                .unwrap()
+                .0,
+            Version::new(2, 0, 0)
+        );
+
+        let index = self::index(
+            "test.app",
+            vec![version("2.0.0", "2.0.0.zip", Some("1.0.0"))],
+        );
+        assert!(latest_version(&index.apps[0], &host_version).is_none());
+    }
+
+    #[test]
+    fn test_check_updates() {
+        let host_version = Version::new(0, 2, 0);
+        let index = index(
+            "test.app",
+            vec![
+                version("1.0.0", "1.0.0.zip", None),
+                version("1.1.0", "1.1.0.zip", None),
+            ],
+        );
+        let check = |version: Option<&str>| {
+            check_updates(&[installed("test.app", version)], &index, &host_version)
+        };
+
+        let updates = check(Some("1.0.0"));
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].id, "test.app");
+        assert_eq!(updates[0].current.as_deref(), Some("1.0.0"));
+        assert_eq!(updates[0].latest.version, "1.1.0");
+        assert!(check(Some("1.1.0")).is_empty());
+        assert!(check(Some("2.0.0")).is_empty());
+        // 没有版本或者版本不是semver时总是可以更新
+        assert_eq!(check(None).len(), 1);
+        assert_eq!(check(Some("1.0")).len(), 1);
+
+        assert!(check_updates(&[installed("test.other", None)], &index, &host_version).is_empty());
+    }
+}
@@ -0,0 +1,474 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::ErrorKind,
+    path::PathBuf,
+};
+
+use acfunlive_neotool_permission::{Permission, PermissionManager};
+use argon2::Argon2;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{create_dir_all, read, rename, write},
+    sync::{MappedMutexGuard, Mutex, MutexGuard},
+};
+
+use crate::{Error, Result};
+
+// 保存在系统密钥环里的服务名的前缀，后面是应用id和服务名
+const KEYRING_PREFIX: &str = "acfunlive-neotool";
+
+// 检查系统密钥环是否可用时读取的用户名
+const KEYRING_PROBE: &str = "probe";
+
+/// 使用系统密钥环时保存密钥名字的文件，不保存密钥的内容
+pub(crate) const SECRETS_INDEX_FILE: &str = "secrets.json";
+
+/// 系统密钥环不可用时保存密钥的加密文件
+pub(crate) const SECRETS_FILE: &str = "secrets.enc";
+
+const SALT_LEN: usize = 16;
+
+const NONCE_LEN: usize = 12;
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct SecretKey {
+    service: String,
+    user: String,
+    /// 只有系统密钥环使用
+    target: Option<String>,
+}
+
+impl SecretKey {
+    #[inline]
+    fn keyring_entry(&self, service: &str) -> Result<keyring::Entry> {
+        Ok(match &self.target {
+            Some(target) => keyring::Entry::new_with_target(target, service, &self.user)?,
+            None => keyring::Entry::new(service, &self.user)?,
+        })
+    }
+
+    // 加上应用id，不同应用的密钥不会冲突
+    #[inline]
+    fn entry(&self, app_id: &str) -> Result<keyring::Entry> {
+        self.keyring_entry(&format!("{}/{}/{}", KEYRING_PREFIX, app_id, self.service))
+    }
+
+    // 旧版本直接用服务名保存在系统密钥环里
+    #[inline]
+    fn legacy_entry(&self) -> Result<keyring::Entry> {
+        self.keyring_entry(&self.service)
+    }
+
+    /// 检查token对应的应用是否有`secrets:<service>`权限，返回应用id
+    #[inline]
+    pub(crate) async fn authorize(&self, token: &str) -> Result<String> {
+        Ok(PermissionManager::global()
+            .authorize(token, &Permission::Secrets(self.service.clone()))
+            .await?)
+    }
+}
+
+/// 密钥的名字，不包括内容
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub(crate) struct SecretName {
+    service: String,
+    user: String,
+}
+
+/// 正在使用的存储方式
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "backend", rename_all = "camelCase")]
+pub(crate) enum SecretsStatus {
+    Keyring,
+    /// 加密文件需要先用密码解锁
+    File {
+        locked: bool,
+    },
+}
+
+// 应用id到服务名到用户名
+type Names = BTreeMap<String, BTreeMap<String, BTreeSet<String>>>;
+
+// 应用id到服务名到用户名到密钥
+type Secrets = BTreeMap<String, BTreeMap<String, BTreeMap<String, String>>>;
+
+#[derive(Debug, Deserialize, Serialize)]
+struct EncryptedFile {
+    salt: String,
+    nonce: String,
+    data: String,
+}
+
+struct Unlocked {
+    key: Key,
+    salt: [u8; SALT_LEN],
+    secrets: Secrets,
+}
+
+enum Backend {
+    Keyring(Names),
+    File(Option<Unlocked>),
+}
+
+/// 按应用id分开保存密钥，优先使用系统密钥环，不可用时使用用密码加密的文件
+pub(crate) struct SecretManager {
+    // 没有时不保存密钥的名字，加密文件只保存在内存里
+    dir: Option<PathBuf>,
+    // 第一次使用时检查系统密钥环是否可用
+    backend: Mutex<Option<Backend>>,
+}
+
+#[inline]
+fn decode(data: &str) -> Result<Vec<u8>> {
+    STANDARD
+        .decode(data)
+        .map_err(|e| Error::CorruptedSecrets(e.to_string()))
+}
+
+// 在单独的线程里从密码生成密钥
+async fn derive_key(passphrase: String, salt: [u8; SALT_LEN]) -> Result<Key> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut key = Key::default();
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|e| Error::CorruptedSecrets(e.to_string()))?;
+
+        Ok(key)
+    })
+    .await?
+}
+
+impl SecretManager {
+    #[inline]
+    pub(crate) fn new(dir: Option<PathBuf>) -> Self {
+        Self {
+            dir,
+            backend: Mutex::new(None),
+        }
+    }
+
+    async fn read_file<T: serde::de::DeserializeOwned>(&self, name: &str) -> Result<Option<T>> {
+        match &self.dir {
+            Some(dir) => match read(dir.join(name)).await {
+                Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+            None => Ok(None),
+        }
+    }
+
+    // 先写临时文件再改名，避免写到一半时文件损坏
+    async fn write_file<T: Serialize>(&self, name: &str, data: &T) -> Result<()> {
+        if let Some(dir) = &self.dir {
+            create_dir_all(dir).await?;
+            let path = dir.join(name);
+            let tmp = path.with_extension("tmp");
+            write(&tmp, serde_json::to_vec_pretty(data)?).await?;
+            rename(tmp, path).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn detect(&self) -> Result<Backend> {
+        let available = match keyring::Entry::new(KEYRING_PREFIX, KEYRING_PROBE) {
+            Ok(entry) => !matches!(
+                entry.get_password(),
+                Err(keyring::Error::PlatformFailure(_)) | Err(keyring::Error::NoStorageAccess(_))
+            ),
+            Err(_) => false,
+        };
+
+        Ok(if available {
+            Backend::Keyring(
+                self.read_file(SECRETS_INDEX_FILE)
+                    .await?
+                    .unwrap_or_default(),
+            )
+        } else {
+            Backend::File(None)
+        })
+    }
+
+    async fn backend(&self) -> Result<MappedMutexGuard<'_, Backend>> {
+        let mut backend = self.backend.lock().await;
+        if backend.is_none() {
+            *backend = Some(self.detect().await?);
+        }
+
+        Ok(MutexGuard::map(backend, |backend| {
+            backend.as_mut().expect("secrets backend is detected")
+        }))
+    }
+
+    async fn save_secrets(&self, unlocked: &Unlocked) -> Result<()> {
+        let cipher = ChaCha20Poly1305::new(&unlocked.key);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let data = cipher
+            .encrypt(&nonce, serde_json::to_vec(&unlocked.secrets)?.as_slice())
+            .map_err(|e| Error::CorruptedSecrets(e.to_string()))?;
+
+        self.write_file(
+            SECRETS_FILE,
+            &EncryptedFile {
+                salt: STANDARD.encode(unlocked.salt),
+                nonce: STANDARD.encode(nonce),
+                data: STANDARD.encode(data),
+            },
+        )
+        .await
+    }
+
+    pub(crate) async fn status(&self) -> Result<SecretsStatus> {
+        Ok(match &*self.backend().await? {
+            Backend::Keyring(_) => SecretsStatus::Keyring,
+            Backend::File(unlocked) => SecretsStatus::File {
+                locked: unlocked.is_none(),
+            },
+        })
+    }
+
+    /// 用密码解锁加密文件，没有加密文件时用这个密码新建
+    pub(crate) async fn unlock(&self, passphrase: String) -> Result<()> {
+        let mut backend = self.backend().await?;
+        if !matches!(*backend, Backend::File(_)) {
+            return Ok(());
+        }
+
+        let unlocked = match self.read_file::<EncryptedFile>(SECRETS_FILE).await? {
+            Some(file) => {
+                let salt: [u8; SALT_LEN] = decode(&file.salt)?
+                    .try_into()
+                    .map_err(|_| Error::CorruptedSecrets("invalid salt".to_string()))?;
+                let nonce = decode(&file.nonce)?;
+                if nonce.len() != NONCE_LEN {
+                    return Err(Error::CorruptedSecrets("invalid nonce".to_string()));
+                }
+                let key = derive_key(passphrase, salt).await?;
+                let data = ChaCha20Poly1305::new(&key)
+                    .decrypt(Nonce::from_slice(&nonce), decode(&file.data)?.as_slice())
+                    .map_err(|_| Error::WrongPassphrase)?;
+
+                Unlocked {
+                    key,
+                    salt,
+                    secrets: serde_json::from_slice(&data)?,
+                }
+            }
+            None => {
+                let mut salt = [0; SALT_LEN];
+                OsRng.fill_bytes(&mut salt);
+                let unlocked = Unlocked {
+                    key: derive_key(passphrase, salt).await?,
+                    salt,
+                    secrets: Secrets::new(),
+                };
+                self.save_secrets(&unlocked).await?;
+
+                unlocked
+            }
+        };
+        *backend = Backend::File(Some(unlocked));
+
+        Ok(())
+    }
+
+    /// 把旧版本保存的密钥移到应用自己的服务名下，返回旧的密钥。
+    /// 旧版本的密钥不区分应用，有`secrets:<service>`权限的应用第一次读取时得到它。
+    async fn migrate_legacy(
+        &self,
+        names: &mut Names,
+        app_id: &str,
+        key: &SecretKey,
+    ) -> Result<Option<String>> {
+        let legacy = key.legacy_entry()?;
+        let content = match legacy.get_password() {
+            Ok(content) => content,
+            Err(keyring::Error::NoEntry) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        key.entry(app_id)?.set_password(&content)?;
+        if add_name(names, app_id, key) {
+            self.write_file(SECRETS_INDEX_FILE, names).await?;
+        }
+        // 保存到新的服务名后才删除旧的密钥
+        let _ = legacy.delete_password();
+
+        Ok(Some(content))
+    }
+
+    pub(crate) async fn get(&self, app_id: &str, key: &SecretKey) -> Result<Option<String>> {
+        match &mut *self.backend().await? {
+            Backend::Keyring(names) => match key.entry(app_id)?.get_password() {
+                Ok(s) => Ok(Some(s)),
+                Err(keyring::Error::NoEntry) => self.migrate_legacy(names, app_id, key).await,
+                Err(e) => Err(Error::from(e)),
+            },
+            Backend::File(Some(unlocked)) => Ok(unlocked
+                .secrets
+                .get(app_id)
+                .and_then(|s| s.get(&key.service))
+                .and_then(|s| s.get(&key.user))
+                .cloned()),
+            Backend::File(None) => Err(Error::SecretsLocked),
+        }
+    }
+
+    pub(crate) async fn set(&self, app_id: &str, key: &SecretKey, content: &str) -> Result<()> {
+        match &mut *self.backend().await? {
+            Backend::Keyring(names) => {
+                key.entry(app_id)?.set_password(content)?;
+                if add_name(names, app_id, key) {
+                    self.write_file(SECRETS_INDEX_FILE, names).await?;
+                }
+            }
+            Backend::File(Some(unlocked)) => {
+                unlocked
+                    .secrets
+                    .entry(app_id.to_string())
+                    .or_default()
+                    .entry(key.service.clone())
+                    .or_default()
+                    .insert(key.user.clone(), content.to_string());
+                self.save_secrets(unlocked).await?;
+            }
+            Backend::File(None) => return Err(Error::SecretsLocked),
+        }
+
+        Ok(())
+    }
+
+    /// 密钥不存在时不返回错误
+    pub(crate) async fn delete(&self, app_id: &str, key: &SecretKey) -> Result<()> {
+        match &mut *self.backend().await? {
+            Backend::Keyring(names) => {
+                match key.entry(app_id)?.delete_password() {
+                    Ok(()) | Err(keyring::Error::NoEntry) => {}
+                    Err(e) => return Err(e.into()),
+                }
+                if remove_name(names, app_id, key) {
+                    self.write_file(SECRETS_INDEX_FILE, names).await?;
+                }
+            }
+            Backend::File(Some(unlocked)) => {
+                if remove_name(&mut unlocked.secrets, app_id, key) {
+                    self.save_secrets(unlocked).await?;
+                }
+            }
+            Backend::File(None) => return Err(Error::SecretsLocked),
+        }
+
+        Ok(())
+    }
+
+    /// 应用保存的密钥的名字
+    pub(crate) async fn list(&self, app_id: &str) -> Result<Vec<SecretName>> {
+        match &*self.backend().await? {
+            Backend::Keyring(names) => Ok(list_names(names, app_id)),
+            Backend::File(Some(unlocked)) => Ok(list_names(&unlocked.secrets, app_id)),
+            Backend::File(None) => Err(Error::SecretsLocked),
+        }
+    }
+}
+
+// 返回是否添加了新的名字
+fn add_name(names: &mut Names, app_id: &str, key: &SecretKey) -> bool {
+    names
+        .entry(app_id.to_string())
+        .or_default()
+        .entry(key.service.clone())
+        .or_default()
+        .insert(key.user.clone())
+}
+
+// 以下被`Names`和`Secrets`共用
+fn list_names<T: Users>(
+    map: &BTreeMap<String, BTreeMap<String, T>>,
+    app_id: &str,
+) -> Vec<SecretName> {
+    map.get(app_id)
+        .into_iter()
+        .flatten()
+        .flat_map(|(service, users)| {
+            users.users().map(move |user| SecretName {
+                service: service.clone(),
+                user: user.clone(),
+            })
+        })
+        .collect()
+}
+
+// 返回是否删除了
+fn remove_name<T: Users>(
+    map: &mut BTreeMap<String, BTreeMap<String, T>>,
+    app_id: &str,
+    key: &SecretKey,
+) -> bool {
+    let services = match map.get_mut(app_id) {
+        Some(services) => services,
+        None => return false,
+    };
+    let removed = match services.get_mut(&key.service) {
+        Some(users) => {
+            let removed = users.remove_user(&key.user);
+            if users.is_empty() {
+                services.remove(&key.service);
+            }
+            removed
+        }
+        None => false,
+    };
+    if services.is_empty() {
+        map.remove(app_id);
+    }
+
+    removed
+}
+
+trait Users {
+    fn users(&self) -> Box<dyn Iterator<Item = &String> + '_>;
+
+    fn remove_user(&mut self, user: &str) -> bool;
+
+    fn is_empty(&self) -> bool;
+}
+
+impl Users for BTreeSet<String> {
+    #[inline]
+    fn users(&self) -> Box<dyn Iterator<Item = &String> + '_> {
+        Box::new(self.iter())
+    }
+
+    #[inline]
+    fn remove_user(&mut self, user: &str) -> bool {
+        self.remove(user)
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        BTreeSet::is_empty(self)
+    }
+}
+
+impl Users for BTreeMap<String, String> {
+    #[inline]
+    fn users(&self) -> Box<dyn Iterator<Item = &String> + '_> {
+        Box::new(self.keys())
+    }
+
+    #[inline]
+    fn remove_user(&mut self, user: &str) -> bool {
+        self.remove(user).is_some()
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        BTreeMap::is_empty(self)
+    }
+}
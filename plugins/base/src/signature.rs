@@ -0,0 +1,323 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{Signature, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+use crate::{package::DATA_DIR, Error, Result};
+
+/// 应用目录里的签名文件
+pub(crate) const SIGNATURE_FILE: &str = "neotool.app.sig";
+
+/// `neotool.app.sig`的内容，公钥和签名都是base64。
+/// 签名的内容是应用目录里除了`neotool.app.sig`、`data`目录和指向应用数据目录的符号链接外所有文件的列表，
+/// 每个文件一行，格式为`<SHA-256>  <相对路径>\n`，路径用`/`分隔，按路径排序，
+/// 和`sha256sum`的输出相同。
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SignatureFile {
+    public_key: String,
+    signature: String,
+}
+
+/// 验证签名用的设置
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SignaturePolicy {
+    /// 信任的发布者的公钥，base64
+    #[serde(default)]
+    pub(crate) trusted_keys: Vec<String>,
+    /// 为`true`时不加载没有被信任的公钥签名的应用
+    #[serde(default)]
+    pub(crate) require_signed: bool,
+    /// neotool的应用数据目录，应用里指向这个目录的符号链接不会被签名
+    #[serde(skip)]
+    pub(crate) data_dir: Option<PathBuf>,
+}
+
+/// 应用的签名状态
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub(crate) enum SignatureStatus {
+    /// 没有签名
+    Unsigned,
+    /// 签名正确而且公钥被信任
+    Trusted { key: String },
+    /// 签名正确但公钥没有被信任
+    Untrusted { key: String },
+    /// 签名错误或者签名文件格式错误
+    Invalid { message: String },
+}
+
+impl Default for SignatureStatus {
+    #[inline]
+    fn default() -> Self {
+        Self::Unsigned
+    }
+}
+
+impl SignatureStatus {
+    #[inline]
+    fn invalid(message: impl ToString) -> Self {
+        Self::Invalid {
+            message: message.to_string(),
+        }
+    }
+}
+
+// 应用目录里的文件，符号链接指向的内容没有被签名，所以只有`data`目录和指向应用数据目录的
+// 符号链接（比如应用把数据目录链接到`web`目录里给浏览器源使用）可以是符号链接
+fn collect_files(
+    root: &Path,
+    dir: &Path,
+    data_dir: Option<&Path>,
+    files: &mut Vec<(String, PathBuf)>,
+) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = path
+            .strip_prefix(root)
+            .unwrap_or(&path)
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            let into_data_dir = match (fs::canonicalize(&path), data_dir) {
+                (Ok(target), Some(data_dir)) => target.starts_with(data_dir),
+                _ => false,
+            };
+            if name != DATA_DIR && !into_data_dir {
+                return Err(Error::SymlinkInApp(name));
+            }
+        } else if file_type.is_dir() {
+            if name != DATA_DIR {
+                collect_files(root, &path, data_dir, files)?;
+            }
+        } else if name != SIGNATURE_FILE {
+            files.push((name, path));
+        }
+    }
+
+    Ok(())
+}
+
+/// 应用目录里的文件列表，也就是签名的内容
+fn signed_content(dir: &Path, data_dir: Option<&Path>) -> Result<String> {
+    // 应用数据目录还不存在时不会有指向它的符号链接
+    let data_dir = data_dir.and_then(|dir| fs::canonicalize(dir).ok());
+    let mut files = Vec::new();
+    collect_files(dir, dir, data_dir.as_deref(), &mut files)?;
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut content = String::new();
+    for (name, path) in files {
+        content.push_str(&sha256::try_digest(path.as_path())?);
+        content.push_str("  ");
+        content.push_str(&name);
+        content.push('\n');
+    }
+
+    Ok(content)
+}
+
+fn decode_key(key: &str) -> std::result::Result<VerifyingKey, String> {
+    let bytes: [u8; 32] = STANDARD
+        .decode(key.trim())
+        .map_err(|e| e.to_string())?
+        .try_into()
+        .map_err(|_| "public key must be 32 bytes".to_string())?;
+
+    VerifyingKey::from_bytes(&bytes).map_err(|e| e.to_string())
+}
+
+fn verify_file(
+    dir: &Path,
+    data_dir: Option<&Path>,
+    file: &SignatureFile,
+) -> std::result::Result<VerifyingKey, String> {
+    let key = decode_key(&file.public_key)?;
+    let signature = STANDARD
+        .decode(file.signature.trim())
+        .map_err(|e| e.to_string())?;
+    let signature = Signature::from_slice(&signature).map_err(|e| e.to_string())?;
+    let content = signed_content(dir, data_dir).map_err(|e| e.to_string())?;
+    key.verify_strict(content.as_bytes(), &signature)
+        .map_err(|e| e.to_string())?;
+
+    Ok(key)
+}
+
+/// 验证应用目录的签名，会读取应用目录里所有的文件
+pub(crate) fn verify(dir: &Path, policy: &SignaturePolicy) -> SignatureStatus {
+    let data = match fs::read_to_string(dir.join(SIGNATURE_FILE)) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return SignatureStatus::Unsigned,
+        Err(e) => return SignatureStatus::invalid(e),
+    };
+    let file: SignatureFile = match serde_json::from_str(&data) {
+        Ok(file) => file,
+        Err(e) => return SignatureStatus::invalid(e),
+    };
+    let key = match verify_file(dir, policy.data_dir.as_deref(), &file) {
+        Ok(key) => key,
+        Err(message) => return SignatureStatus::Invalid { message },
+    };
+
+    let encoded = STANDARD.encode(key.as_bytes());
+    // 比较解码后的公钥，忽略base64的写法不同
+    if policy
+        .trusted_keys
+        .iter()
+        .any(|trusted| decode_key(trusted).ok() == Some(key))
+    {
+        SignatureStatus::Trusted { key: encoded }
+    } else {
+        SignatureStatus::Untrusted { key: encoded }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{Signer, SigningKey};
+
+    use super::*;
+
+    fn sign(dir: &Path, data_dir: Option<&Path>, key: &SigningKey) {
+        let signature = key.sign(signed_content(dir, data_dir).unwrap().as_bytes());
+        let file = serde_json::json!({
+            "publicKey": STANDARD.encode(key.verifying_key().as_bytes()),
+            "signature": STANDARD.encode(signature.to_bytes()),
+        });
+        fs::write(dir.join(SIGNATURE_FILE), file.to_string()).unwrap();
+    }
+
+    #[test]
+    fn test_verify() {
+        let dir = std::env::temp_dir().join(format!("neotool-signature-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::create_dir_all(dir.join(DATA_DIR)).unwrap();
+        fs::write(dir.join("index.js"), "index").unwrap();
+        fs::write(dir.join("lib").join("lib.js"), "lib").unwrap();
+        fs::write(dir.join(DATA_DIR).join("user.json"), "user").unwrap();
+
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let encoded = STANDARD.encode(key.verifying_key().as_bytes());
+        let policy = SignaturePolicy {
+            trusted_keys: vec![encoded.clone()],
+            require_signed: true,
+            data_dir: None,
+        };
+        assert_eq!(verify(&dir, &policy), SignatureStatus::Unsigned);
+        assert_eq!(
+            signed_content(&dir, None).unwrap(),
+            format!(
+                "{}  index.js\n{}  lib/lib.js\n",
+                sha256::digest("index"),
+                sha256::digest("lib")
+            )
+        );
+
+        sign(&dir, None, &key);
+        assert_eq!(
+            verify(&dir, &policy),
+            SignatureStatus::Trusted {
+                key: encoded.clone()
+            }
+        );
+        assert_eq!(
+            verify(&dir, &SignaturePolicy::default()),
+            SignatureStatus::Untrusted {
+                key: encoded.clone()
+            }
+        );
+
+        // `data`目录里的文件不影响签名
+        fs::write(dir.join(DATA_DIR).join("user.json"), "changed").unwrap();
+        assert!(matches!(
+            verify(&dir, &policy),
+            SignatureStatus::Trusted { .. }
+        ));
+        fs::write(dir.join("lib").join("lib.js"), "changed").unwrap();
+        assert!(matches!(
+            verify(&dir, &policy),
+            SignatureStatus::Invalid { .. }
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink() {
+        use std::os::unix::fs::symlink;
+
+        let dir = std::env::temp_dir().join(format!("neotool-symlink-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (app, outside) = (dir.join("app"), dir.join("outside"));
+        fs::create_dir_all(&app).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(app.join("index.js"), "index").unwrap();
+        fs::write(outside.join("lib.js"), "lib").unwrap();
+
+        // 符号链接指向的文件可以在签名之后被修改
+        symlink(&outside, app.join("lib")).unwrap();
+        assert!(matches!(
+            signed_content(&app, None),
+            Err(Error::SymlinkInApp(name)) if name == "lib"
+        ));
+        fs::remove_file(app.join("lib")).unwrap();
+        symlink(outside.join("lib.js"), app.join("lib.js")).unwrap();
+        assert!(matches!(
+            signed_content(&app, None),
+            Err(Error::SymlinkInApp(name)) if name == "lib.js"
+        ));
+        fs::remove_file(app.join("lib.js")).unwrap();
+
+        // 应用可以把`data`目录链接到其它地方
+        symlink(&outside, app.join(DATA_DIR)).unwrap();
+        let key = SigningKey::from_bytes(&[1; 32]);
+        sign(&app, None, &key);
+        assert!(matches!(
+            verify(&app, &SignaturePolicy::default()),
+            SignatureStatus::Untrusted { .. }
+        ));
+
+        // 指向应用数据目录的符号链接不签名，其它目录里的符号链接仍然不允许
+        let data_dir = dir.join("app_data");
+        fs::create_dir_all(data_dir.join("sticker")).unwrap();
+        fs::create_dir_all(app.join("web")).unwrap();
+        symlink(data_dir.join("sticker"), app.join("web").join("sticker")).unwrap();
+        let policy = SignaturePolicy {
+            data_dir: Some(data_dir.clone()),
+            ..SignaturePolicy::default()
+        };
+        sign(&app, Some(&data_dir), &key);
+        assert!(matches!(
+            verify(&app, &policy),
+            SignatureStatus::Untrusted { .. }
+        ));
+        fs::write(data_dir.join("sticker").join("a.png"), "sticker").unwrap();
+        assert!(matches!(
+            verify(&app, &policy),
+            SignatureStatus::Untrusted { .. }
+        ));
+        assert!(matches!(
+            verify(&app, &SignaturePolicy::default()),
+            SignatureStatus::Invalid { .. }
+        ));
+        symlink(&outside, app.join("web").join("lib")).unwrap();
+        assert!(matches!(
+            signed_content(&app, Some(&data_dir)),
+            Err(Error::SymlinkInApp(name)) if name == "web/lib"
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
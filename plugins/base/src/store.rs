@@ -0,0 +1,290 @@
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex as StdMutex,
+    },
+};
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tokio::{
+    fs::{create_dir_all, read_to_string, rename, write},
+    sync::Mutex,
+};
+
+use crate::{app::check_id, Error, Result};
+
+/// 一个应用保存的所有数据
+pub(crate) type Store = Map<String, Value>;
+
+pub(crate) type WatchId = u32;
+
+type Callback = Box<dyn Fn(&StoreChange) + Send + Sync>;
+
+/// 数据的变化，`value`为`None`时数据被删除
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct StoreChange {
+    key: String,
+    value: Option<Value>,
+}
+
+struct Watcher {
+    app_id: String,
+    // `None`为监听所有的数据
+    key: Option<String>,
+    callback: Callback,
+}
+
+/// 按应用id分开保存的键值对，每个应用的数据保存在`<dir>/<应用id>.json`
+#[derive(Default)]
+pub(crate) struct StoreManager {
+    // 没有时只保存在内存里
+    dir: Option<PathBuf>,
+    // 同时保证同一时间只有一个写入
+    stores: Mutex<HashMap<String, Store>>,
+    watchers: StdMutex<HashMap<WatchId, Watcher>>,
+    watch_id: AtomicU32,
+}
+
+// 新旧数据的差别
+fn diff(old: &Store, new: &Store) -> Vec<StoreChange> {
+    let mut changes: Vec<StoreChange> = new
+        .iter()
+        .filter(|(key, value)| old.get(*key) != Some(*value))
+        .map(|(key, value)| StoreChange {
+            key: key.clone(),
+            value: Some(value.clone()),
+        })
+        .collect();
+    changes.extend(
+        old.keys()
+            .filter(|key| !new.contains_key(*key))
+            .map(|key| StoreChange {
+                key: key.clone(),
+                value: None,
+            }),
+    );
+
+    changes
+}
+
+impl StoreManager {
+    #[inline]
+    pub(crate) fn new(dir: Option<PathBuf>) -> Self {
+        Self {
+            dir,
+            ..Default::default()
+        }
+    }
+
+    #[inline]
+    fn path(&self, app_id: &str) -> Option<PathBuf> {
+        self.dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.json", app_id)))
+    }
+
+    async fn load(&self, app_id: &str) -> Result<Store> {
+        match self.path(app_id) {
+            Some(path) => match read_to_string(path).await {
+                Ok(data) => Ok(serde_json::from_str(&data)?),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(Store::new()),
+                Err(e) => Err(e.into()),
+            },
+            None => Ok(Store::new()),
+        }
+    }
+
+    // 先写临时文件再改名，避免写到一半时文件损坏
+    async fn save(&self, app_id: &str, store: &Store) -> Result<()> {
+        if let (Some(dir), Some(path)) = (&self.dir, self.path(app_id)) {
+            create_dir_all(dir).await?;
+            let tmp = path.with_extension("json.tmp");
+            write(&tmp, serde_json::to_vec_pretty(store)?).await?;
+            rename(tmp, path).await?;
+        }
+
+        Ok(())
+    }
+
+    /// 读取应用的数据
+    pub(crate) async fn read<T>(&self, app_id: &str, f: impl FnOnce(&Store) -> T) -> Result<T> {
+        check_id(app_id).map_err(|_| Error::InvalidAppId(app_id.to_string()))?;
+        let mut stores = self.stores.lock().await;
+        if !stores.contains_key(app_id) {
+            let store = self.load(app_id).await?;
+            stores.insert(app_id.to_string(), store);
+        }
+
+        Ok(f(&stores[app_id]))
+    }
+
+    /// 修改应用的数据，保存成功后才生效并通知监听者
+    pub(crate) async fn update<T>(
+        &self,
+        app_id: &str,
+        f: impl FnOnce(&mut Store) -> T,
+    ) -> Result<T> {
+        check_id(app_id).map_err(|_| Error::InvalidAppId(app_id.to_string()))?;
+        let mut stores = self.stores.lock().await;
+        let old = match stores.remove(app_id) {
+            Some(store) => store,
+            None => self.load(app_id).await?,
+        };
+        let mut new = old.clone();
+        let result = f(&mut new);
+
+        let changes = diff(&old, &new);
+        if changes.is_empty() {
+            stores.insert(app_id.to_string(), old);
+            return Ok(result);
+        }
+        if let Err(e) = self.save(app_id, &new).await {
+            stores.insert(app_id.to_string(), old);
+            return Err(e);
+        }
+        stores.insert(app_id.to_string(), new);
+        drop(stores);
+
+        self.notify(app_id, &changes);
+
+        Ok(result)
+    }
+
+    fn notify(&self, app_id: &str, changes: &[StoreChange]) {
+        let watchers = self.watchers.lock().unwrap_or_else(|e| e.into_inner());
+        for watcher in watchers.values().filter(|w| w.app_id == app_id) {
+            for change in changes {
+                let matched = match &watcher.key {
+                    Some(key) => *key == change.key,
+                    None => true,
+                };
+                if matched {
+                    (watcher.callback)(change);
+                }
+            }
+        }
+    }
+
+    /// 监听应用的数据的变化，`key`为`None`时监听所有的数据
+    pub(crate) fn watch(
+        &self,
+        app_id: &str,
+        key: Option<String>,
+        callback: impl Fn(&StoreChange) + Send + Sync + 'static,
+    ) -> Result<WatchId> {
+        check_id(app_id).map_err(|_| Error::InvalidAppId(app_id.to_string()))?;
+        let id = self.watch_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.watchers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(
+                id,
+                Watcher {
+                    app_id: app_id.to_string(),
+                    key,
+                    callback: Box::new(callback),
+                },
+            );
+
+        Ok(id)
+    }
+
+    /// 只能停止应用自己的监听
+    pub(crate) fn unwatch(&self, app_id: &str, id: WatchId) {
+        let mut watchers = self.watchers.lock().unwrap_or_else(|e| e.into_inner());
+        if matches!(watchers.get(&id), Some(watcher) if watcher.app_id == app_id) {
+            watchers.remove(&id);
+        }
+    }
+
+    /// 应用重新加载后之前的监听都不能再调用
+    #[inline]
+    pub(crate) fn unwatch_app(&self, app_id: &str) {
+        self.watchers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|_, watcher| watcher.app_id != app_id);
+    }
+
+    /// 界面重新加载后停止所有的监听
+    #[inline]
+    pub(crate) fn unwatch_all(&self) {
+        self.watchers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::AtomicUsize, Arc};
+
+    use super::*;
+
+    fn counter(manager: &StoreManager, app_id: &str, key: Option<&str>) -> Arc<AtomicUsize> {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counted = count.clone();
+        manager
+            .watch(app_id, key.map(str::to_string), move |_| {
+                counted.fetch_add(1, Ordering::Relaxed);
+            })
+            .unwrap();
+        count
+    }
+
+    async fn set(manager: &StoreManager, app_id: &str, key: &str, value: Value) {
+        manager
+            .update(app_id, |store| {
+                store.insert(key.to_string(), value);
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        let manager = StoreManager::new(None);
+        let all = counter(&manager, "test.a", None);
+        let key = counter(&manager, "test.a", Some("key"));
+        let other = counter(&manager, "test.b", None);
+
+        set(&manager, "test.a", "key", 1.into()).await;
+        set(&manager, "test.a", "other", 1.into()).await;
+        // 值没有变化时不通知
+        set(&manager, "test.a", "key", 1.into()).await;
+        assert_eq!(all.load(Ordering::Relaxed), 2);
+        assert_eq!(key.load(Ordering::Relaxed), 1);
+        assert_eq!(other.load(Ordering::Relaxed), 0);
+        assert_eq!(
+            manager
+                .read("test.a", |store| store.get("key").cloned())
+                .await
+                .unwrap(),
+            Some(1.into())
+        );
+
+        // 其它应用不能停止监听
+        manager.unwatch("test.b", 1);
+        set(&manager, "test.a", "key", 2.into()).await;
+        assert_eq!(all.load(Ordering::Relaxed), 3);
+        manager.unwatch("test.a", 1);
+        set(&manager, "test.a", "key", 3.into()).await;
+        assert_eq!(all.load(Ordering::Relaxed), 3);
+        assert_eq!(key.load(Ordering::Relaxed), 3);
+
+        manager.unwatch_app("test.a");
+        set(&manager, "test.a", "key", 4.into()).await;
+        set(&manager, "test.b", "key", 4.into()).await;
+        assert_eq!(key.load(Ordering::Relaxed), 3);
+        assert_eq!(other.load(Ordering::Relaxed), 1);
+
+        manager.unwatch_all();
+        set(&manager, "test.b", "key", 5.into()).await;
+        assert_eq!(other.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,370 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use acfunlive_neotool_permission::PermissionManager;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use semver::Version;
+use serde::Serialize;
+use tauri::{async_runtime::spawn, AppHandle, Manager, Runtime};
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver},
+    time::{timeout, Instant},
+};
+
+use crate::{
+    app::{declare_permissions, load_app, AppConfig, Problem},
+    package::DATA_DIR,
+    signature::SignaturePolicy,
+    Result,
+};
+
+/// 前端监听的事件名
+pub(crate) const APP_EVENT: &str = "acfunlive-neotool-base:app";
+
+/// 最后一个文件变化后等待这么久再重新加载应用
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// 文件一直在变化时，第一个文件变化后最多等待这么久就重新加载应用
+const MAX_DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// neotool界面显示错误信息的事件名
+const ERROR_EVENT: &str = "error";
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub(crate) enum AppEvent {
+    AppAdded {
+        config: AppConfig,
+    },
+    /// 应用目录里的文件或者应用的配置发生变化
+    AppChanged {
+        config: AppConfig,
+    },
+    AppRemoved {
+        id: String,
+        path: String,
+    },
+}
+
+/// 监听应用目录，应用的文件变化时重新加载应用并发送事件
+#[derive(Default)]
+pub(crate) struct AppsWatcher(Mutex<Option<RecommendedWatcher>>);
+
+// 发生变化的应用目录的名字，忽略应用的`data`目录和以`.`开头的临时目录
+fn changed_dir(apps_dir: &Path, path: &Path) -> Option<OsString> {
+    let mut components = path.strip_prefix(apps_dir).ok()?.components();
+    let dir = match components.next()? {
+        Component::Normal(dir) => dir,
+        _ => return None,
+    };
+    if dir.to_string_lossy().starts_with('.') {
+        return None;
+    }
+    if matches!(components.next(), Some(Component::Normal(sub)) if sub == DATA_DIR) {
+        return None;
+    }
+
+    Some(dir.to_os_string())
+}
+
+// 按id区分的应用，id重复时和`discover_apps`一样按目录的路径排序，只保留第一个
+fn apps_by_id(dirs: &HashMap<OsString, AppConfig>) -> HashMap<String, AppConfig> {
+    let mut configs: Vec<&AppConfig> = dirs.values().collect();
+    configs.sort_by(|a, b| a.path.cmp(&b.path));
+    let mut apps = HashMap::new();
+    for config in configs {
+        apps.entry(config.id.clone())
+            .or_insert_with(|| config.clone());
+    }
+    apps
+}
+
+// 应用目录下所有可能是应用的目录的名字
+async fn all_dirs(apps_dir: &Path) -> Result<HashSet<OsString>> {
+    let mut read_dir = tokio::fs::read_dir(apps_dir).await?;
+    let mut dirs = HashSet::new();
+    while let Some(entry) = read_dir.next_entry().await? {
+        let name = entry.file_name();
+        if !name.to_string_lossy().starts_with('.') && entry.file_type().await?.is_dir() {
+            dirs.insert(name);
+        }
+    }
+    Ok(dirs)
+}
+
+// 加载一个应用目录，目录不存在时返回`None`
+async fn load_dir(
+    apps_dir: &Path,
+    name: &OsString,
+    host_version: &Version,
+    policy: &SignaturePolicy,
+) -> std::result::Result<Option<AppConfig>, Problem> {
+    let dir = apps_dir.join(name);
+    match tokio::fs::metadata(&dir).await {
+        Ok(metadata) if metadata.is_dir() => load_app(&dir, host_version, policy).await.map(Some),
+        _ => Ok(None),
+    }
+}
+
+// 只重新加载发生变化的应用目录，返回要发送的事件和加载失败的原因
+async fn reload(
+    apps_dir: &Path,
+    host_version: &Version,
+    policy: &SignaturePolicy,
+    dirs: &mut HashMap<OsString, AppConfig>,
+    changed: &HashSet<OsString>,
+) -> (Vec<AppEvent>, Vec<String>) {
+    let old_apps = apps_by_id(dirs);
+    let mut errors = Vec::new();
+    for name in changed {
+        match load_dir(apps_dir, name, host_version, policy).await {
+            Ok(Some(config)) => {
+                dirs.insert(name.clone(), config);
+            }
+            Ok(None) => {
+                dirs.remove(name);
+            }
+            Err(problem) => {
+                dirs.remove(name);
+                errors.push(format!(
+                    "failed to load app {}: {}",
+                    apps_dir.join(name).display(),
+                    problem
+                ));
+            }
+        }
+    }
+    let new_apps = apps_by_id(dirs);
+
+    let mut events = Vec::new();
+    for (id, old) in &old_apps {
+        if !new_apps.contains_key(id) {
+            PermissionManager::global().undeclare(id);
+            events.push(AppEvent::AppRemoved {
+                id: id.clone(),
+                path: old.path.clone(),
+            });
+        }
+    }
+    for (id, config) in &new_apps {
+        let touched = changed
+            .iter()
+            .any(|name| matches!(dirs.get(name), Some(c) if c.path == config.path));
+        let event = match old_apps.get(id) {
+            None => AppEvent::AppAdded {
+                config: config.clone(),
+            },
+            Some(old) if touched || old != config => AppEvent::AppChanged {
+                config: config.clone(),
+            },
+            Some(_) => continue,
+        };
+        declare_permissions(config);
+        events.push(event);
+    }
+
+    (events, errors)
+}
+
+// 合并短时间内的多个文件变化，然后重新加载应用
+async fn run<R: Runtime>(
+    app: AppHandle<R>,
+    apps_dir: PathBuf,
+    policy: SignaturePolicy,
+    mut dirs: HashMap<OsString, AppConfig>,
+    mut receiver: UnboundedReceiver<Event>,
+) {
+    let host_version = app.package_info().version.clone();
+    while let Some(event) = receiver.recv().await {
+        let mut events = vec![event];
+        // 文件一直在变化时最多等待`MAX_DEBOUNCE`
+        let deadline = Instant::now() + MAX_DEBOUNCE;
+        loop {
+            let wait = DEBOUNCE.min(deadline.saturating_duration_since(Instant::now()));
+            match timeout(wait, receiver.recv()).await {
+                Ok(Some(event)) => events.push(event),
+                // 停止监听
+                Ok(None) => return,
+                Err(_) => break,
+            }
+        }
+
+        let mut rescan = false;
+        let mut changed = HashSet::new();
+        for event in events {
+            if matches!(event.kind, EventKind::Access(_)) {
+                continue;
+            }
+            for path in &event.paths {
+                if path == &apps_dir {
+                    rescan = true;
+                } else if let Some(dir) = changed_dir(&apps_dir, path) {
+                    changed.insert(dir);
+                }
+            }
+        }
+        // 不知道具体哪个目录变化时检查所有的应用目录
+        if rescan {
+            match all_dirs(&apps_dir).await {
+                Ok(all) => changed.extend(all.into_iter().chain(dirs.keys().cloned())),
+                Err(e) => {
+                    let _ = app.emit_all(
+                        ERROR_EVENT,
+                        format!("failed to reload apps in {}: {}", apps_dir.display(), e),
+                    );
+                }
+            }
+        }
+        if changed.is_empty() {
+            continue;
+        }
+
+        let (events, errors) = reload(&apps_dir, &host_version, &policy, &mut dirs, &changed).await;
+        for event in events {
+            let _ = app.emit_all(APP_EVENT, event);
+        }
+        for error in errors {
+            let _ = app.emit_all(ERROR_EVENT, error);
+        }
+    }
+}
+
+impl AppsWatcher {
+    /// 开始监听应用目录，之前的监听会被停止
+    pub(crate) async fn watch<R: Runtime>(
+        &self,
+        app: AppHandle<R>,
+        apps_dir: &Path,
+        policy: SignaturePolicy,
+    ) -> Result<()> {
+        // 和`AppConfig`的`path`一样规范化，这样才能比较
+        let apps_dir = tokio::fs::canonicalize(apps_dir).await?;
+        let host_version = &app.package_info().version;
+        let mut dirs = HashMap::new();
+        for name in all_dirs(&apps_dir).await? {
+            // 加载失败的应用在加载应用配置时已经报告过了
+            if let Ok(Some(config)) = load_dir(&apps_dir, &name, host_version, &policy).await {
+                dirs.insert(name, config);
+            }
+        }
+
+        let (sender, receiver) = unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+            if let Ok(event) = event {
+                let _ = sender.send(event);
+            }
+        })?;
+        watcher.watch(&apps_dir, RecursiveMode::Recursive)?;
+        // 替换后旧的监听的发送端被丢弃，旧的任务会结束
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = Some(watcher);
+        spawn(run(app, apps_dir, policy, dirs, receiver));
+
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn unwatch(&self) {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::CONFIG_FILE;
+
+    fn write_app(dir: &Path, id: &str, name: &str) {
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(
+            dir.join(CONFIG_FILE),
+            format!(r#"{{"id": "{id}", "name": "{name}", "entry": "index.js"}}"#),
+        )
+        .unwrap();
+        std::fs::write(dir.join("index.js"), "").unwrap();
+    }
+
+    #[test]
+    fn test_changed_dir() {
+        let apps_dir = Path::new("/apps");
+        assert_eq!(
+            changed_dir(apps_dir, Path::new("/apps/a/index.js")),
+            Some(OsString::from("a"))
+        );
+        assert_eq!(
+            changed_dir(apps_dir, Path::new("/apps/a")),
+            Some(OsString::from("a"))
+        );
+        assert_eq!(changed_dir(apps_dir, Path::new("/apps/a/data/x")), None);
+        assert_eq!(changed_dir(apps_dir, Path::new("/apps/.staging/x")), None);
+        assert_eq!(changed_dir(apps_dir, Path::new("/other/a")), None);
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let dir = std::env::temp_dir().join(format!("neotool-watch-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let apps_dir = tokio::fs::canonicalize(&dir).await.unwrap();
+        let host_version = Version::new(0, 2, 0);
+        let policy = SignaturePolicy::default();
+
+        write_app(&apps_dir.join("a"), "test.app", "a");
+        write_app(&apps_dir.join("b"), "test.app", "b");
+        write_app(&apps_dir.join("c"), "test.other", "c");
+        let mut dirs = HashMap::new();
+        for name in all_dirs(&apps_dir).await.unwrap() {
+            let config = load_dir(&apps_dir, &name, &host_version, &policy)
+                .await
+                .unwrap()
+                .unwrap();
+            dirs.insert(name, config);
+        }
+        let changed = |name: &str| HashSet::from([OsString::from(name)]);
+
+        // 没有变化的应用也会因为目录里的文件变化而发送事件
+        let (events, errors) =
+            reload(&apps_dir, &host_version, &policy, &mut dirs, &changed("c")).await;
+        assert!(errors.is_empty());
+        assert!(matches!(
+            events.as_slice(),
+            [AppEvent::AppChanged { config }] if config.id == "test.other"
+        ));
+
+        // id重复的应用被删除后加载另一个
+        std::fs::remove_dir_all(apps_dir.join("a")).unwrap();
+        let (events, _) = reload(&apps_dir, &host_version, &policy, &mut dirs, &changed("a")).await;
+        assert!(matches!(
+            events.as_slice(),
+            [AppEvent::AppChanged { config }] if config.name == "b"
+        ));
+
+        // 加载失败的应用被当作删除，并且报告错误
+        std::fs::write(apps_dir.join("c").join(CONFIG_FILE), "{").unwrap();
+        let (events, errors) =
+            reload(&apps_dir, &host_version, &policy, &mut dirs, &changed("c")).await;
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            events.as_slice(),
+            [AppEvent::AppRemoved { id, .. }] if id == "test.other"
+        ));
+
+        write_app(&apps_dir.join("d"), "test.new", "d");
+        let (events, errors) =
+            reload(&apps_dir, &host_version, &policy, &mut dirs, &changed("d")).await;
+        assert!(errors.is_empty());
+        assert!(matches!(
+            events.as_slice(),
+            [AppEvent::AppAdded { config }] if config.id == "test.new"
+        ));
+        // 没有变化的目录不会重新加载
+        assert_eq!(dirs.len(), 2);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
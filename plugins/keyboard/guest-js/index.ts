@@ -93,11 +93,14 @@ export class KeyboardListener {
     this.#id = id;
   }
 
+  // 需要keyboard.listen权限，token是neotool加载应用时传入的token
   static async start_listen(
+    token: string,
     keyDown: (key: Key) => void,
     keyUp: (key: Key) => void
   ): Promise<KeyboardListener> {
     const id: number = await invoke('plugin:acfunlive-neotool-keyboard|start_listen', {
+      token,
       keyDownCallback: transformCallback(keyDown),
       keyUpCallback: transformCallback(keyUp)
     });
@@ -112,6 +115,7 @@ export class KeyboardListener {
 
 export type Input = { KeyDown: Key } | { KeyUp: Key } | { Text: string };
 
-export async function simulate_input(input: Input) {
-  await invoke('plugin:acfunlive-neotool-keyboard|simulate_input', { input });
+// 需要keyboard.simulate权限
+export async function simulate_input(token: string, input: Input) {
+  await invoke('plugin:acfunlive-neotool-keyboard|simulate_input', { token, input });
 }
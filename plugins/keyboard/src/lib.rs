@@ -2,6 +2,7 @@ mod key;
 
 use std::{any::Any, collections::HashMap};
 
+use acfunlive_neotool_permission::{Permission, PermissionManager};
 use device_query::{DeviceEvents, DeviceState};
 use enigo::{Enigo, KeyboardControllable};
 use once_cell::sync::Lazy;
@@ -15,7 +16,7 @@ use tokio::sync::Mutex;
 
 use key::{Input, Key};
 
-type Result<T> = std::result::Result<T, ()>;
+type Result<T> = std::result::Result<T, acfunlive_neotool_permission::Error>;
 
 type Id = u32;
 
@@ -41,9 +42,14 @@ struct ListenManager(Mutex<HashMap<Id, ListenData>>);
 #[command]
 async fn start_listen<R: Runtime>(
     window: Window<R>,
+    token: String,
     key_down_callback: CallbackFn,
     key_up_callback: CallbackFn,
-) -> Id {
+) -> Result<Id> {
+    PermissionManager::global()
+        .authorize(&token, &Permission::KeyboardListen)
+        .await?;
+
     let (key_down_guard, key_up_guard) = {
         let device_state = DeviceState::new();
         let key_down_guard = {
@@ -77,7 +83,7 @@ async fn start_listen<R: Runtime>(
         },
     );
 
-    id
+    Ok(id)
 }
 
 #[command]
@@ -93,7 +99,14 @@ struct InputManager(Mutex<Enigo>);
 
 #[command]
 #[inline]
-async fn simulate_input(manager: State<'_, InputManager>, input: Input) -> Result<()> {
+async fn simulate_input(
+    manager: State<'_, InputManager>,
+    token: String,
+    input: Input,
+) -> Result<()> {
+    PermissionManager::global()
+        .authorize(&token, &Permission::KeyboardSimulate)
+        .await?;
     let mut enigo = manager.0.lock().await;
     match input {
         Input::KeyDown(key) => enigo.key_down(key.into()),
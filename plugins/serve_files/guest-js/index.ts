@@ -22,10 +22,17 @@ export class Server {
     this.#id = id;
   }
 
-  static async startServe(dir: string, hostname: string, port: number): Promise<Server> {
+  // 需要serve_files权限，token是neotool加载应用时传入的token
+  static async startServe(
+    token: string,
+    dir: string,
+    hostname: string,
+    port: number
+  ): Promise<Server> {
     checkPort(port);
 
     const id: number = await invoke('plugin:acfunlive-neotool-serve-files|start_serve', {
+      token,
       dir,
       hostname,
       port
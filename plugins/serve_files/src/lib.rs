@@ -1,5 +1,6 @@
 use std::{collections::HashMap, net::TcpListener, sync::Arc, thread::spawn};
 
+use acfunlive_neotool_permission::{Permission, PermissionManager};
 use file_serve::{Server, ServerBuilder};
 use once_cell::sync::Lazy;
 use serde::{ser::Serializer, Serialize};
@@ -16,10 +17,12 @@ type Result<T> = std::result::Result<T, Error>;
 
 static ID: Lazy<Mutex<Id>> = Lazy::new(|| Mutex::new(0));
 
-#[derive(Clone, Debug, thiserror::Error)]
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("server address {0}:{1} is not available")]
     AddressNotAvailable(String, u16),
+    #[error(transparent)]
+    PermissionError(#[from] acfunlive_neotool_permission::Error),
 }
 
 impl Serialize for Error {
@@ -44,10 +47,14 @@ fn is_address_available(hostname: &str, port: u16) -> bool {
 #[command]
 async fn start_serve(
     manager: State<'_, ServerManager>,
+    token: String,
     dir: String,
     hostname: String,
     port: u16,
 ) -> Result<Id> {
+    PermissionManager::global()
+        .authorize(&token, &Permission::ServeFiles)
+        .await?;
     if !is_address_available(&hostname, port) {
         return Err(Error::AddressNotAvailable(hostname, port));
     }
@@ -31,16 +31,19 @@ export type SparkResponse = {
   tokens: TokenStatistics;
 };
 
+// 需要spark权限，token是neotool加载应用时传入的token
 export async function sparkChat(
+  token: string,
   request: SparkRequest,
   callback: (content: string) => void
 ): Promise<TokenStatistics> {
   return await invoke('plugin:acfunlive-neotool-spark|spark_chat', {
+    token,
     request,
     cb: transformCallback(callback)
   });
 }
 
-export async function sparkChatFull(request: SparkRequest): Promise<SparkResponse> {
-  return await invoke('plugin:acfunlive-neotool-spark|spark_chat_full', { request });
+export async function sparkChatFull(token: string, request: SparkRequest): Promise<SparkResponse> {
+  return await invoke('plugin:acfunlive-neotool-spark|spark_chat_full', { token, request });
 }
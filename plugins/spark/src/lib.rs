@@ -2,6 +2,7 @@ mod data;
 
 pub use data::*;
 
+use acfunlive_neotool_permission::{Permission, PermissionManager};
 use acfunlive_neotool_xunfei::authorization;
 use futures_util::{SinkExt, StreamExt};
 use serde::{Serialize, Serializer};
@@ -30,6 +31,8 @@ pub enum Error {
     SparkRequestError(String),
     #[error("spark API error: {0}")]
     SparkApiError(String),
+    #[error(transparent)]
+    PermissionError(#[from] acfunlive_neotool_permission::Error),
 }
 
 impl Serialize for Error {
@@ -115,9 +118,13 @@ pub async fn spark_request_full(request: SparkRequest) -> Result<SparkResponse>
 #[inline]
 async fn spark_chat<R: Runtime>(
     window: Window<R>,
+    token: String,
     request: SparkRequest,
     cb: CallbackFn,
 ) -> Result<TokenStatistics> {
+    PermissionManager::global()
+        .authorize(&token, &Permission::Spark)
+        .await?;
     spark_request(request, |content| {
         let js = format_callback(cb, &content).expect("unable to serialize spark response content");
         let _ = window.eval(&js);
@@ -127,7 +134,10 @@ async fn spark_chat<R: Runtime>(
 
 #[command]
 #[inline]
-async fn spark_chat_full(request: SparkRequest) -> Result<SparkResponse> {
+async fn spark_chat_full(token: String, request: SparkRequest) -> Result<SparkResponse> {
+    PermissionManager::global()
+        .authorize(&token, &Permission::Spark)
+        .await?;
     spark_request_full(request).await
 }
 
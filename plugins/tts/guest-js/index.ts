@@ -29,19 +29,34 @@ export type TtsRequest = {
   getAllOnce: boolean;
 };
 
+// 需要tts权限，token是neotool加载应用时传入的token
 export async function tts(
+  token: string,
   request: TtsRequest,
   callback: (audioSourceId: AudioSourceId) => void
 ): Promise<void> {
   await invoke('plugin:acfunlive-neotool-tts|tts', {
+    token,
     request,
     cb: transformCallback(callback)
   });
 }
 
-export async function ttsFull(request: TtsRequest): Promise<AudioSourceId[]> {
+export async function ttsFull(token: string, request: TtsRequest): Promise<AudioSourceId[]> {
   const idList: AudioSourceId[] = [];
-  await tts(request, (id) => idList.push(id));
+  await tts(token, request, (id) => idList.push(id));
 
   return idList;
 }
+
+export async function ttsStream(
+  token: string,
+  request: TtsRequest,
+  callback: (audioSourceId: AudioSourceId) => void
+): Promise<void> {
+  await invoke('plugin:acfunlive-neotool-tts|tts_stream', {
+    token,
+    request,
+    cb: transformCallback(callback)
+  });
+}
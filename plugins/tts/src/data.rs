@@ -1,3 +1,4 @@
+use acfunlive_neotool_audio::{AudioFormat, AudioOrigin, AudioSource, AudioStreamWriter};
 use base64::engine::{general_purpose::STANDARD, Engine};
 use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
@@ -18,6 +19,41 @@ pub enum Aue {
     Lame,
 }
 
+// TTS返回的音频都是单声道
+impl Aue {
+    #[inline]
+    fn audio_format(self) -> AudioFormat {
+        match self {
+            Aue::Raw => AudioFormat::Pcm,
+            Aue::Lame => AudioFormat::Mp3,
+        }
+    }
+
+    /// 用TTS返回的音频数据生成音频源
+    pub fn audio_source(self, auf: Option<Auf>, data: Vec<u8>) -> AudioSource {
+        let sample_rate = auf.unwrap_or(Auf::Audio16kRate).sample_rate();
+        match self {
+            Aue::Raw => AudioSource::pcm(data, sample_rate, 1, AudioOrigin::Tts),
+            Aue::Lame => {
+                let mut source = AudioSource::new(data, self.audio_format(), AudioOrigin::Tts);
+                source.sample_rate = Some(sample_rate);
+                source.channels = Some(1);
+
+                source
+            }
+        }
+    }
+
+    /// 新建用来写入TTS音频数据的音频流
+    pub fn audio_stream(self, auf: Option<Auf>) -> (AudioSource, AudioStreamWriter) {
+        let (mut source, writer) = AudioSource::stream(self.audio_format(), AudioOrigin::Tts);
+        source.sample_rate = Some(auf.unwrap_or(Auf::Audio16kRate).sample_rate());
+        source.channels = Some(1);
+
+        (source, writer)
+    }
+}
+
 #[derive(Clone, Copy, Debug, Serialize_repr)]
 #[repr(u8)]
 pub(crate) enum Sfl {
@@ -32,6 +68,16 @@ pub enum Auf {
     Audio16kRate,
 }
 
+impl Auf {
+    #[inline]
+    pub fn sample_rate(self) -> u32 {
+        match self {
+            Auf::Audio8kRate => 8000,
+            Auf::Audio16kRate => 16000,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize_repr)]
 #[repr(u8)]
 pub enum Bgs {
@@ -2,9 +2,10 @@ mod data;
 
 pub use data::*;
 
-use std::future::Future;
+use std::future::{ready, Future};
 
 use acfunlive_neotool_audio::AudioSourceManager;
+use acfunlive_neotool_permission::{Permission, PermissionManager};
 use acfunlive_neotool_xunfei::authorization;
 use base64::engine::{general_purpose::STANDARD, Engine};
 use futures_util::{SinkExt, StreamExt};
@@ -36,6 +37,8 @@ pub enum Error {
     TtsRequestError(String),
     #[error("TTS API error: {0}")]
     TtsApiError(String),
+    #[error(transparent)]
+    PermissionError(#[from] acfunlive_neotool_permission::Error),
 }
 
 impl Serialize for Error {
@@ -110,24 +113,59 @@ pub async fn tts_request<F: FnMut(Vec<u8>) -> Fut, Fut: Future<Output = ()>>(
 
 #[command]
 #[inline]
-async fn tts<R: Runtime>(window: Window<R>, request: TtsRequest, cb: CallbackFn) -> Result<()> {
+async fn tts<R: Runtime>(
+    window: Window<R>,
+    token: String,
+    request: TtsRequest,
+    cb: CallbackFn,
+) -> Result<()> {
+    PermissionManager::global()
+        .authorize(&token, &Permission::Tts)
+        .await?;
     let manager = window.state::<AudioSourceManager>();
+    let (aue, auf) = (request.aue, request.auf);
 
-    tts_request(request, |source| async {
-        let id = manager.add(source).await;
+    tts_request(request, |data| async {
+        let id = manager.add(aue.audio_source(auf, data)).await;
         let js = format_callback(cb, &id).expect("unable to serialize audio source ID");
         let _ = window.eval(&js);
     })
     .await
 }
 
+/// 把TTS返回的音频数据写入同一个音频流，回调只会收到一个音频源ID，可以在TTS结束前开始播放
+#[command]
+async fn tts_stream<R: Runtime>(
+    window: Window<R>,
+    token: String,
+    mut request: TtsRequest,
+    cb: CallbackFn,
+) -> Result<()> {
+    PermissionManager::global()
+        .authorize(&token, &Permission::Tts)
+        .await?;
+    let manager = window.state::<AudioSourceManager>();
+    let (source, writer) = request.aue.audio_stream(request.auf);
+    let id = manager.add(source).await;
+    let js = format_callback(cb, &id).expect("unable to serialize audio source ID");
+    let _ = window.eval(&js);
+
+    request.get_all_once = false;
+    // 返回时writer被drop，音频流随之关闭
+    tts_request(request, |data| {
+        writer.append(&data);
+        ready(())
+    })
+    .await
+}
+
 /// Initializes the plugin.
 #[inline]
 pub fn init<R: Runtime>() -> TauriPlugin<R> {
     Builder::new("acfunlive-neotool-tts")
-        .invoke_handler(tauri::generate_handler![tts])
+        .invoke_handler(tauri::generate_handler![tts, tts_stream])
         .setup(|app| {
-            app.manage(AudioSourceManager::default());
+            app.manage(AudioSourceManager::global());
 
             Ok(())
         })